@@ -0,0 +1,5 @@
+DROP TABLE IF EXISTS "pseudonym";
+
+ALTER TABLE bottle DROP COLUMN IF EXISTS pseudonym CASCADE;
+ALTER TABLE bottle DROP COLUMN IF EXISTS anonymous CASCADE;
+ALTER TABLE guild DROP COLUMN IF EXISTS allow_anonymous CASCADE;
@@ -0,0 +1,13 @@
+ALTER TABLE guild ADD COLUMN allow_anonymous bool NOT NULL DEFAULT false;
+
+ALTER TABLE bottle ADD COLUMN anonymous bool NOT NULL DEFAULT false;
+ALTER TABLE bottle ADD COLUMN pseudonym int;
+UPDATE bottle SET anonymous = true WHERE guild IS NULL;
+
+CREATE TABLE "pseudonym" (
+	"thread" bigint NOT NULL REFERENCES bottle("id") ON DELETE CASCADE,
+	"user" bigint NOT NULL REFERENCES "user"("id") ON DELETE CASCADE,
+	"number" int NOT NULL,
+	CONSTRAINT pseudonym_pk PRIMARY KEY ("thread", "user"),
+	UNIQUE ("thread", "number")
+);
@@ -0,0 +1,8 @@
+CREATE TABLE "privacy" (
+	"user" bigint NOT NULL REFERENCES "user"("id") ON DELETE CASCADE,
+	"hide_profile" bool NOT NULL DEFAULT false,
+	"hide_bottles" bool NOT NULL DEFAULT false,
+	"hide_contributions" bool NOT NULL DEFAULT false,
+	"hide_leaderboard" bool NOT NULL DEFAULT false,
+	CONSTRAINT privacy_pk PRIMARY KEY ("user")
+);
@@ -0,0 +1,12 @@
+CREATE TABLE "xp_event" (
+	"id" bigserial NOT NULL,
+	"user" bigint NOT NULL REFERENCES "user"("id") ON DELETE CASCADE,
+	"guild" bigint,
+	"bottle" bigint REFERENCES bottle("id") ON DELETE SET NULL,
+	"xp" int NOT NULL,
+	"reason" TEXT NOT NULL,
+	"time" TIMESTAMP NOT NULL DEFAULT 'NOW()',
+	CONSTRAINT xp_event_pk PRIMARY KEY ("id")
+);
+
+CREATE INDEX xp_event_user ON xp_event ("user");
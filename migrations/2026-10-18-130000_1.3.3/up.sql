@@ -0,0 +1,5 @@
+CREATE TABLE "managed_guild" (
+	"user" bigint NOT NULL REFERENCES "user"("id") ON DELETE CASCADE,
+	"guild" bigint NOT NULL,
+	CONSTRAINT managed_guild_pk PRIMARY KEY ("user", "guild")
+);
@@ -0,0 +1,5 @@
+ALTER TABLE guild DROP COLUMN IF EXISTS receive_replies CASCADE;
+ALTER TABLE guild DROP COLUMN IF EXISTS receive_bottles CASCADE;
+ALTER TABLE guild DROP COLUMN IF EXISTS blocked_words CASCADE;
+ALTER TABLE guild DROP COLUMN IF EXISTS allow_links CASCADE;
+ALTER TABLE guild DROP COLUMN IF EXISTS allow_images CASCADE;
@@ -0,0 +1,5 @@
+ALTER TABLE guild ADD COLUMN allow_images bool NOT NULL DEFAULT true;
+ALTER TABLE guild ADD COLUMN allow_links bool NOT NULL DEFAULT true;
+ALTER TABLE guild ADD COLUMN blocked_words TEXT;
+ALTER TABLE guild ADD COLUMN receive_bottles bool NOT NULL DEFAULT true;
+ALTER TABLE guild ADD COLUMN receive_replies bool NOT NULL DEFAULT true;
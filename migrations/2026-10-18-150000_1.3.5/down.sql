@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS "session";
+
+ALTER TABLE "user" ADD COLUMN "session" UUID UNIQUE;
@@ -0,0 +1,15 @@
+ALTER TABLE "user" DROP COLUMN IF EXISTS "session" CASCADE;
+
+CREATE TABLE "session" (
+	"id" TEXT NOT NULL,
+	"user" bigint REFERENCES "user"("id") ON DELETE CASCADE,
+	"csrf" TEXT,
+	"redirect" TEXT,
+	"form_token" TEXT,
+	"created" TIMESTAMP NOT NULL DEFAULT 'NOW()',
+	"expires" TIMESTAMP NOT NULL,
+	"revoked" bool NOT NULL DEFAULT false,
+	CONSTRAINT session_pk PRIMARY KEY ("id")
+);
+
+CREATE INDEX session_user ON "session" ("user");
@@ -0,0 +1,9 @@
+CREATE TABLE "api_key" (
+	"id" TEXT NOT NULL,
+	"user" bigint NOT NULL REFERENCES "user"("id") ON DELETE CASCADE,
+	"name" TEXT NOT NULL,
+	"rate_limit" int NOT NULL DEFAULT 60,
+	"created" TIMESTAMP NOT NULL DEFAULT 'NOW()',
+	"revoked" bool NOT NULL DEFAULT false,
+	CONSTRAINT api_key_pk PRIMARY KEY ("id")
+);
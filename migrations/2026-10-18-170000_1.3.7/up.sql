@@ -0,0 +1,24 @@
+CREATE TABLE "webhook" (
+	"id" bigserial NOT NULL,
+	"guild" bigint REFERENCES guild("id") ON DELETE CASCADE,
+	"url" TEXT NOT NULL,
+	"secret" TEXT NOT NULL,
+	"events" int NOT NULL,
+	"created" TIMESTAMP NOT NULL DEFAULT 'NOW()',
+	CONSTRAINT webhook_pk PRIMARY KEY ("id")
+);
+
+CREATE TABLE "webhook_delivery" (
+	"id" bigserial NOT NULL,
+	"webhook" bigint NOT NULL REFERENCES webhook("id") ON DELETE CASCADE,
+	"event" TEXT NOT NULL,
+	"payload" TEXT NOT NULL,
+	"attempts" int NOT NULL DEFAULT 0,
+	"next_attempt" TIMESTAMP,
+	"status" int,
+	"error" TEXT,
+	"created" TIMESTAMP NOT NULL DEFAULT 'NOW()',
+	CONSTRAINT webhook_delivery_pk PRIMARY KEY ("id")
+);
+
+CREATE INDEX webhook_delivery_due ON webhook_delivery ("next_attempt") WHERE "next_attempt" IS NOT NULL;
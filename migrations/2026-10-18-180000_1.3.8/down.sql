@@ -0,0 +1,5 @@
+ALTER TABLE "received_bottle" DROP COLUMN IF EXISTS "platform";
+ALTER TABLE "bottle" DROP COLUMN IF EXISTS "platform";
+
+DROP TABLE IF EXISTS "platform_id";
+DROP SEQUENCE IF EXISTS platform_id_seq;
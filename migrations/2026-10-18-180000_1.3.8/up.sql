@@ -0,0 +1,13 @@
+CREATE SEQUENCE platform_id_seq INCREMENT BY -1 MAXVALUE -1 START WITH -1;
+
+CREATE TABLE "platform_id" (
+	"id" bigint NOT NULL DEFAULT nextval('platform_id_seq'),
+	"platform" smallint NOT NULL,
+	"external" TEXT NOT NULL,
+	"name" TEXT NOT NULL,
+	CONSTRAINT platform_id_pk PRIMARY KEY ("id"),
+	CONSTRAINT platform_id_external UNIQUE ("platform", "external")
+);
+
+ALTER TABLE "bottle" ADD COLUMN "platform" smallint NOT NULL DEFAULT 0;
+ALTER TABLE "received_bottle" ADD COLUMN "platform" smallint NOT NULL DEFAULT 0;
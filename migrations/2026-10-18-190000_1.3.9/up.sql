@@ -0,0 +1 @@
+ALTER TABLE guild ALTER COLUMN prefix TYPE TEXT;
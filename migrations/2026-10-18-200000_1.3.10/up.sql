@@ -0,0 +1,5 @@
+CREATE TABLE "pending_delivery" (
+	"bottle" bigint NOT NULL REFERENCES bottle("id") ON DELETE CASCADE,
+	"queued" TIMESTAMP NOT NULL DEFAULT 'NOW()',
+	CONSTRAINT pending_delivery_pk PRIMARY KEY ("bottle")
+);
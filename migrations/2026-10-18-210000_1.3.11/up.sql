@@ -0,0 +1,11 @@
+CREATE TABLE "discord_profile" (
+	"id" bigint NOT NULL,
+	"guild" BOOLEAN NOT NULL,
+	"name" TEXT NOT NULL,
+	"avatar" TEXT,
+	"bot" BOOLEAN NOT NULL DEFAULT FALSE,
+	"refreshed" TIMESTAMP NOT NULL DEFAULT 'NOW()',
+	CONSTRAINT discord_profile_pk PRIMARY KEY ("id")
+);
+
+CREATE INDEX discord_profile_refreshed ON discord_profile ("refreshed");
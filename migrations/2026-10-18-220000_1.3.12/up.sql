@@ -0,0 +1,24 @@
+DROP VIEW IF EXISTS "user_rank";
+DROP VIEW IF EXISTS "guild_rank";
+
+CREATE TABLE "user_leaderboard" (
+	"id" bigint NOT NULL REFERENCES "user"("id") ON DELETE CASCADE,
+	"rank" bigint NOT NULL,
+	"xp" bigint NOT NULL,
+	CONSTRAINT user_leaderboard_pk PRIMARY KEY ("id")
+);
+
+CREATE TABLE "guild_leaderboard" (
+	"id" bigint NOT NULL REFERENCES guild("id") ON DELETE CASCADE,
+	"rank" bigint NOT NULL,
+	"xp" bigint NOT NULL,
+	CONSTRAINT guild_leaderboard_pk PRIMARY KEY ("id")
+);
+
+CREATE INDEX user_leaderboard_rank ON user_leaderboard ("rank");
+CREATE INDEX guild_leaderboard_rank ON guild_leaderboard ("rank");
+
+INSERT INTO "user_leaderboard" ("id", "rank", "xp")
+	SELECT "id", ROW_NUMBER() OVER (ORDER BY xp DESC, "id"), xp FROM "user";
+INSERT INTO "guild_leaderboard" ("id", "rank", "xp")
+	SELECT "guild", ROW_NUMBER() OVER (ORDER BY SUM(xp) DESC, "guild"), SUM(xp) FROM "guild_contribution" GROUP BY "guild";
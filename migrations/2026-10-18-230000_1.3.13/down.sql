@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS bottle_search;
+DROP INDEX IF EXISTS bottle_reply_to;
+DROP INDEX IF EXISTS bottle_time_pushed;
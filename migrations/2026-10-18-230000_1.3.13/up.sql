@@ -0,0 +1,3 @@
+CREATE INDEX bottle_time_pushed ON bottle ("time_pushed");
+CREATE INDEX bottle_reply_to ON bottle ("reply_to");
+CREATE INDEX bottle_search ON bottle USING GIN (to_tsvector('english', "contents"));
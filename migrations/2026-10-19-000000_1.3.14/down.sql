@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS received_bottle_guild;
+ALTER TABLE received_bottle DROP COLUMN "guild";
@@ -0,0 +1,7 @@
+ALTER TABLE received_bottle ADD COLUMN "guild" bigint;
+
+UPDATE received_bottle SET "guild" = (
+	SELECT guild."id" FROM guild WHERE guild.bottle_channel = received_bottle.channel OR guild.admin_channel = received_bottle.channel LIMIT 1
+);
+
+CREATE INDEX received_bottle_guild ON received_bottle ("guild");
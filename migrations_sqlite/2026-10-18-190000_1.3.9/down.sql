@@ -0,0 +1,19 @@
+DROP TABLE IF EXISTS "platform_id";
+DROP TABLE IF EXISTS "platform_seq";
+DROP TABLE IF EXISTS "webhook_delivery";
+DROP TABLE IF EXISTS "webhook";
+DROP TABLE IF EXISTS "api_key";
+DROP TABLE IF EXISTS "session";
+DROP TABLE IF EXISTS "managed_guild";
+DROP TABLE IF EXISTS "xp_event";
+DROP TABLE IF EXISTS "privacy";
+DROP TABLE IF EXISTS "pseudonym";
+DROP TABLE IF EXISTS "ban";
+DROP TABLE IF EXISTS "report";
+DROP VIEW IF EXISTS "guild_rank";
+DROP VIEW IF EXISTS "user_rank";
+DROP TABLE IF EXISTS "guild_contribution";
+DROP TABLE IF EXISTS "received_bottle";
+DROP TABLE IF EXISTS "bottle";
+DROP TABLE IF EXISTS "guild";
+DROP TABLE IF EXISTS "user";
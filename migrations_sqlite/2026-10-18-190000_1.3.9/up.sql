@@ -0,0 +1,171 @@
+-- SQLite has no migration history to replay, so this is the whole schema as of 1.3.9
+
+CREATE TABLE "user" (
+	"id" bigint NOT NULL,
+	"xp" integer NOT NULL DEFAULT 0,
+	"admin" bool NOT NULL DEFAULT false,
+	"tickets" integer NOT NULL DEFAULT 0,
+	CONSTRAINT user_pk PRIMARY KEY ("id")
+);
+
+CREATE TABLE "guild" (
+	"id" bigint NOT NULL,
+	"invite" TEXT,
+	"bottle_channel" bigint UNIQUE,
+	"admin_channel" bigint UNIQUE,
+	"prefix" TEXT DEFAULT NULL,
+	"allow_anonymous" bool NOT NULL DEFAULT false,
+	"allow_images" bool NOT NULL DEFAULT true,
+	"allow_links" bool NOT NULL DEFAULT true,
+	"blocked_words" TEXT,
+	"receive_bottles" bool NOT NULL DEFAULT true,
+	"receive_replies" bool NOT NULL DEFAULT true,
+	CONSTRAINT guild_pk PRIMARY KEY ("id")
+);
+
+CREATE TABLE "bottle" (
+	"id" INTEGER PRIMARY KEY AUTOINCREMENT,
+	"reply_to" bigint REFERENCES bottle("id") ON DELETE CASCADE,
+	"user" bigint NOT NULL REFERENCES "user"("id"),
+	"message" bigint NOT NULL UNIQUE,
+	"guild" bigint REFERENCES guild("id") ON DELETE SET NULL,
+	"time_pushed" TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	"contents" TEXT NOT NULL,
+	"url" TEXT,
+	"image" TEXT,
+	"channel" bigint NOT NULL,
+	"deleted" bool NOT NULL DEFAULT false,
+	"anonymous" bool NOT NULL DEFAULT false,
+	"pseudonym" integer,
+	"platform" smallint NOT NULL DEFAULT 0
+);
+
+CREATE TABLE "received_bottle" (
+	"id" INTEGER PRIMARY KEY AUTOINCREMENT,
+	"bottle" bigint NOT NULL REFERENCES bottle("id") ON DELETE CASCADE,
+	"message" bigint NOT NULL UNIQUE,
+	"time_recieved" TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	"channel" bigint NOT NULL,
+	"platform" smallint NOT NULL DEFAULT 0
+);
+
+CREATE INDEX received_bottle_channel ON received_bottle ("channel");
+
+CREATE TABLE "guild_contribution" (
+	"guild" bigint NOT NULL REFERENCES guild("id") ON DELETE CASCADE,
+	"user" bigint NOT NULL REFERENCES "user"("id") ON DELETE CASCADE,
+	"xp" integer NOT NULL,
+	CONSTRAINT guild_contribution_pk PRIMARY KEY ("guild", "user")
+);
+
+CREATE VIEW "user_rank" AS SELECT ROW_NUMBER() OVER (ORDER BY xp DESC) AS "rank", "id" FROM "user";
+CREATE VIEW "guild_rank" AS SELECT ROW_NUMBER() OVER (ORDER BY SUM(xp) DESC) AS "rank", "guild" AS "id" FROM "guild_contribution" GROUP BY "guild";
+
+CREATE TABLE "report" (
+	"bottle" bigint NOT NULL REFERENCES bottle("id") ON DELETE CASCADE,
+	"user" bigint NOT NULL REFERENCES "user"("id"),
+	"received_bottle" bigint UNIQUE REFERENCES received_bottle("id"),
+	CONSTRAINT report_pk PRIMARY KEY ("bottle")
+);
+
+CREATE TABLE "ban" (
+	"report" bigint REFERENCES report("bottle") ON DELETE SET NULL,
+	"user" bigint NOT NULL REFERENCES "user"("id"),
+	CONSTRAINT ban_pk PRIMARY KEY ("user")
+);
+
+CREATE TABLE "pseudonym" (
+	"thread" bigint NOT NULL REFERENCES bottle("id") ON DELETE CASCADE,
+	"user" bigint NOT NULL REFERENCES "user"("id") ON DELETE CASCADE,
+	"number" integer NOT NULL,
+	CONSTRAINT pseudonym_pk PRIMARY KEY ("thread", "user"),
+	UNIQUE ("thread", "number")
+);
+
+CREATE TABLE "privacy" (
+	"user" bigint NOT NULL REFERENCES "user"("id") ON DELETE CASCADE,
+	"hide_profile" bool NOT NULL DEFAULT false,
+	"hide_bottles" bool NOT NULL DEFAULT false,
+	"hide_contributions" bool NOT NULL DEFAULT false,
+	"hide_leaderboard" bool NOT NULL DEFAULT false,
+	CONSTRAINT privacy_pk PRIMARY KEY ("user")
+);
+
+CREATE TABLE "xp_event" (
+	"id" INTEGER PRIMARY KEY AUTOINCREMENT,
+	"user" bigint NOT NULL REFERENCES "user"("id") ON DELETE CASCADE,
+	"guild" bigint,
+	"bottle" bigint REFERENCES bottle("id") ON DELETE SET NULL,
+	"xp" integer NOT NULL,
+	"reason" TEXT NOT NULL,
+	"time" TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX xp_event_user ON xp_event ("user");
+
+CREATE TABLE "managed_guild" (
+	"user" bigint NOT NULL REFERENCES "user"("id") ON DELETE CASCADE,
+	"guild" bigint NOT NULL,
+	CONSTRAINT managed_guild_pk PRIMARY KEY ("user", "guild")
+);
+
+CREATE TABLE "session" (
+	"id" TEXT NOT NULL,
+	"user" bigint REFERENCES "user"("id") ON DELETE CASCADE,
+	"csrf" TEXT,
+	"redirect" TEXT,
+	"form_token" TEXT,
+	"created" TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	"expires" TIMESTAMP NOT NULL,
+	"revoked" bool NOT NULL DEFAULT false,
+	CONSTRAINT session_pk PRIMARY KEY ("id")
+);
+
+CREATE INDEX session_user ON "session" ("user");
+
+CREATE TABLE "api_key" (
+	"id" TEXT NOT NULL,
+	"user" bigint NOT NULL REFERENCES "user"("id") ON DELETE CASCADE,
+	"name" TEXT NOT NULL,
+	"rate_limit" integer NOT NULL DEFAULT 60,
+	"created" TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	"revoked" bool NOT NULL DEFAULT false,
+	CONSTRAINT api_key_pk PRIMARY KEY ("id")
+);
+
+CREATE TABLE "webhook" (
+	"id" INTEGER PRIMARY KEY AUTOINCREMENT,
+	"guild" bigint REFERENCES guild("id") ON DELETE CASCADE,
+	"url" TEXT NOT NULL,
+	"secret" TEXT NOT NULL,
+	"events" integer NOT NULL,
+	"created" TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE "webhook_delivery" (
+	"id" INTEGER PRIMARY KEY AUTOINCREMENT,
+	"webhook" bigint NOT NULL REFERENCES webhook("id") ON DELETE CASCADE,
+	"event" TEXT NOT NULL,
+	"payload" TEXT NOT NULL,
+	"attempts" integer NOT NULL DEFAULT 0,
+	"next_attempt" TIMESTAMP,
+	"status" integer,
+	"error" TEXT,
+	"created" TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX webhook_delivery_due ON webhook_delivery ("next_attempt") WHERE "next_attempt" IS NOT NULL;
+
+-- stands in for postgres' platform_id_seq, ids handed out are the negated rowids
+CREATE TABLE "platform_seq" (
+	"id" INTEGER PRIMARY KEY AUTOINCREMENT
+);
+
+CREATE TABLE "platform_id" (
+	"id" bigint NOT NULL,
+	"platform" smallint NOT NULL,
+	"external" TEXT NOT NULL,
+	"name" TEXT NOT NULL,
+	CONSTRAINT platform_id_pk PRIMARY KEY ("id"),
+	CONSTRAINT platform_id_external UNIQUE ("platform", "external")
+);
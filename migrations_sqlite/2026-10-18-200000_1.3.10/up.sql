@@ -0,0 +1,4 @@
+CREATE TABLE "pending_delivery" (
+	"bottle" bigint PRIMARY KEY NOT NULL REFERENCES bottle("id") ON DELETE CASCADE,
+	"queued" TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
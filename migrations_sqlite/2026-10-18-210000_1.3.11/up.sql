@@ -0,0 +1,10 @@
+CREATE TABLE "discord_profile" (
+	"id" bigint PRIMARY KEY NOT NULL,
+	"guild" BOOLEAN NOT NULL,
+	"name" TEXT NOT NULL,
+	"avatar" TEXT,
+	"bot" BOOLEAN NOT NULL DEFAULT FALSE,
+	"refreshed" TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX discord_profile_refreshed ON discord_profile ("refreshed");
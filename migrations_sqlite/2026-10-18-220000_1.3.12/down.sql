@@ -0,0 +1,5 @@
+DROP TABLE IF EXISTS "user_leaderboard";
+DROP TABLE IF EXISTS "guild_leaderboard";
+
+CREATE VIEW "user_rank" AS SELECT ROW_NUMBER() OVER (ORDER BY xp DESC) AS "rank", "id" FROM "user";
+CREATE VIEW "guild_rank" AS SELECT ROW_NUMBER() OVER (ORDER BY SUM(xp) DESC) AS "rank", "guild" AS "id" FROM "guild_contribution" GROUP BY "guild";
@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS bottle_reply_to;
+DROP INDEX IF EXISTS bottle_time_pushed;
@@ -0,0 +1,2 @@
+CREATE INDEX bottle_time_pushed ON bottle ("time_pushed");
+CREATE INDEX bottle_reply_to ON bottle ("reply_to");
@@ -0,0 +1,4 @@
+CREATE TABLE "erased_user" (
+	"id" bigint PRIMARY KEY NOT NULL REFERENCES "user"("id") ON DELETE CASCADE,
+	"erased" timestamp NOT NULL
+);
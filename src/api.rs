@@ -0,0 +1,353 @@
+use std::{collections::HashMap, fmt, sync::Mutex, time::{Duration, Instant}};
+
+use iron::{self, AroundMiddleware};
+use iron::prelude::*;
+use iron::{Handler, AfterMiddleware, status};
+use typemap::Key;
+use router::{Router, NoRoute};
+use params::{Params, Value};
+use serde::Serialize;
+use serde_derive::Serialize;
+use serde_json;
+use log::error;
+
+use model::*;
+use data::*;
+use web::{self, PrerequisiteMiddleware};
+use bottle;
+use gateway::ChatGateway;
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+const PER_PAGE: i64 = 20;
+const MAX_PER_PAGE: i64 = 100;
+
+#[derive(Debug)]
+struct ApiError(String);
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ApiError(desc) = self;
+        write!(f, "{}", desc)
+    }
+}
+
+impl iron::Error for ApiError {}
+
+impl ApiError {
+    fn new(status: status::Status, desc: &str) -> IronError {
+        IronError::new(ApiError(desc.to_owned()), status)
+    }
+
+    fn with<T, F: FnMut() -> Res<T>>(mut f: F) -> IronResult<T> {
+        f().map_err(ApiError::from_err)
+    }
+
+    fn from_err(err: BottleError) -> IronError {
+        if err.internal() {
+            error!("API error: {}", err);
+        }
+
+        ApiError::new(web::error_status(&err), &err.user_message())
+    }
+}
+
+fn db_conn(req: &Request) -> IronResult<Conn> {
+    req.get_conn().map_err(ApiError::from_err)
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String
+}
+
+fn json<T: Serialize>(data: &T) -> IronResult<Response> {
+    let body = serde_json::to_string(data)
+        .map_err(|_| ApiError::new(status::InternalServerError, "Error serializing response"))?;
+
+    Ok(Response::with((status::Ok, "application/json".parse::<iron::mime::Mime>().unwrap(), body)))
+}
+
+struct ErrorMiddleware;
+impl AfterMiddleware for ErrorMiddleware {
+    fn catch(&self, _req: &mut Request, err: IronError) -> IronResult<Response> {
+        let (status, error) = if err.error.is::<NoRoute>() {
+            (status::NotFound, "Not found".to_owned())
+        } else if err.error.is::<ApiError>() {
+            (err.response.status.unwrap_or(status::InternalServerError), err.error.to_string())
+        } else {
+            (status::InternalServerError, "An internal error occured".to_owned())
+        };
+
+        let mut resp = json(&ErrorBody {error})?;
+        resp.status = Some(status);
+        Ok(resp)
+    }
+}
+
+struct DApiKey;
+impl Key for DApiKey {
+    type Value = ApiKey;
+}
+
+#[derive(Default)]
+struct Windows {
+    counts: HashMap<String, (Instant, i32)>,
+    swept: Option<Instant>
+}
+
+/// Fixed one minute windows, counted per key
+struct RateLimiter {
+    windows: Mutex<Windows>
+}
+
+impl RateLimiter {
+    fn new() -> Self {
+        RateLimiter {windows: Mutex::new(Windows::default())}
+    }
+
+    fn hit(&self, key: &ApiKey) -> Option<i32> {
+        let mut windows = self.windows.lock().unwrap();
+        let now = Instant::now();
+
+        //once a window, forget keys that went quiet or were revoked so the map doesn't keep every key ever used
+        if windows.swept.map_or(true, |t| now.duration_since(t) >= RATE_WINDOW) {
+            windows.counts.retain(|_, w| now.duration_since(w.0) < RATE_WINDOW);
+            windows.swept = Some(now);
+        }
+
+        let window = windows.counts.entry(key.id.clone()).or_insert((now, 0));
+        if now.duration_since(window.0) >= RATE_WINDOW {
+            *window = (now, 0);
+        }
+
+        if window.1 >= key.rate_limit {
+            None
+        } else {
+            window.1 += 1;
+            Some(key.rate_limit - window.1)
+        }
+    }
+}
+
+/// Expects ``Authorization: Bearer <key>``
+struct ApiAuth {limiter: RateLimiter}
+
+fn get_key(req: &Request) -> Option<String> {
+    req.headers.get_raw("Authorization")
+        .and_then(|x| x.first()).and_then(|x| String::from_utf8(x.clone()).ok())
+        .and_then(|x| x.strip_prefix("Bearer ").map(|k| k.trim().to_owned()))
+}
+
+impl AroundMiddleware for ApiAuth {
+    fn around(self, handler: Box<dyn Handler>) -> Box<dyn Handler> {
+        Box::new(move |req: &mut Request| -> IronResult<Response> {
+            let key = get_key(req).ok_or_else(|| ApiError::new(status::Unauthorized, "Missing API key"))?;
+            let key = ApiKey::get(&key, &mut db_conn(req)?)
+                .map_err(|_| ApiError::new(status::Unauthorized, "Invalid API key"))?;
+
+            let remaining = self.limiter.hit(&key)
+                .ok_or_else(|| ApiError::new(status::TooManyRequests, "Rate limit exceeded"))?;
+
+            let limit = key.rate_limit;
+            req.extensions.insert::<DApiKey>(key);
+
+            let mut resp = handler.handle(req)?;
+            resp.headers.set_raw("X-RateLimit-Limit", vec![limit.to_string().into_bytes()]);
+            resp.headers.set_raw("X-RateLimit-Remaining", vec![remaining.to_string().into_bytes()]);
+            Ok(resp)
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct Paginated<T> {
+    page: i64,
+    per_page: i64,
+    items: Vec<T>
+}
+
+/// Returns the 1-based page and page size, along with the offset to query from
+fn get_page(req: &mut Request) -> IronResult<(i64, i64, i64)> {
+    let params = req.get_ref::<Params>().map(Clone::clone)
+        .map_err(|_| ApiError::new(status::BadRequest, "Invalid parameters"))?;
+
+    let num = |name: &str, default: i64| match params.find(&[name]) {
+        None => Ok(default),
+        Some(Value::String(x)) => x.parse::<i64>().map_err(|_| ApiError::new(status::BadRequest, "Invalid page parameter")),
+        _ => Err(ApiError::new(status::BadRequest, "Invalid page parameter"))
+    };
+
+    let page = num("page", 1)?;
+    let per_page = num("per_page", PER_PAGE)?;
+
+    if page < 1 || per_page < 1 || per_page > MAX_PER_PAGE {
+        return Err(ApiError::new(status::BadRequest, "Page must be at least 1 and per_page between 1 and 100"));
+    }
+
+    let offset = (page - 1).checked_mul(per_page).ok_or_else(|| ApiError::new(status::BadRequest, "Page is out of range"))?;
+    Ok((page, per_page, offset))
+}
+
+fn get_id(req: &Request, name: &str) -> IronResult<i64> {
+    req.extensions.get::<Router>().unwrap()
+        .find(name).and_then(|x| x.parse().ok())
+        .ok_or_else(|| ApiError::new(status::BadRequest, "Invalid id"))
+}
+
+#[derive(Serialize)]
+struct ApiBottle {
+    id: BottleId,
+    reply_to: Option<BottleId>,
+    author: String,
+    author_id: Option<UserId>,
+    guild: Option<GuildId>,
+    time_pushed: DTime,
+    contents: String,
+    url: Option<String>,
+    image: Option<String>,
+    deleted: bool,
+    hidden: bool
+}
+
+impl ApiBottle {
+    /// Bottles from users hiding them only keep their place in the thread
+    fn new(bottle: Bottle, gateway: &dyn ChatGateway, conn: &mut Conn) -> Self {
+        let hidden = Privacy::get(bottle.user, conn).bottles_hidden();
+
+        if bottle.deleted || hidden {
+            return ApiBottle {
+                id: bottle.id, reply_to: bottle.reply_to, author: if hidden { "Hidden".to_owned() } else { bottle::author_name(&bottle, gateway) },
+                author_id: None, guild: bottle.guild, time_pushed: bottle.time_pushed,
+                contents: String::new(), url: None, image: None, deleted: bottle.deleted, hidden
+            };
+        }
+
+        ApiBottle {
+            id: bottle.id, reply_to: bottle.reply_to, author: bottle::author_name(&bottle, gateway),
+            author_id: if bottle.anonymous { None } else { Some(bottle.user) },
+            guild: bottle.guild, time_pushed: bottle.time_pushed,
+            contents: bottle.contents, url: bottle.url, image: bottle.image, deleted: false, hidden: false
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ApiThread {
+    bottle: ApiBottle,
+    ancestors: Vec<ApiBottle>,
+    truncated: bool,
+    replies: Vec<ApiBottle>
+}
+
+#[derive(Serialize)]
+struct Stats {
+    bottle_count: i64,
+    user_count: i64,
+    guild_count: i64
+}
+
+fn stats(req: &mut Request) -> IronResult<Response> {
+    let conn = &mut db_conn(req)?;
+
+    json(&ApiError::with(|| Ok(Stats {
+        bottle_count: get_bottle_count(conn)?,
+        user_count: get_user_count(conn)?,
+        guild_count: get_guild_count(conn)?
+    }))?)
+}
+
+fn user(req: &mut Request) -> IronResult<Response> {
+    let uid = get_id(req, "user")?;
+
+    let data = web::get_user_data(uid, req.get_service().gateway.as_ref(), &mut db_conn(req)?, &req.get_cfg())
+        .map_err(|_| ApiError::new(status::NotFound, "User not found"))?;
+
+    json(&data)
+}
+
+fn guild(req: &mut Request) -> IronResult<Response> {
+    let gid = get_id(req, "guild")?;
+
+    let data = web::get_guild_data(gid, req.get_service().gateway.as_ref(), &mut db_conn(req)?, &req.get_cfg())
+        .map_err(|_| ApiError::new(status::NotFound, "Guild not found"))?;
+
+    json(&data)
+}
+
+fn user_leaderboard(req: &mut Request) -> IronResult<Response> {
+    let (page, per_page, offset) = get_page(req)?;
+    let conn = &mut db_conn(req)?;
+    let gateway = req.get_service().gateway;
+
+    let items = ApiError::with(|| Ok(User::get_top_page(offset, per_page, conn)?.into_iter()
+        .map(|x| web::UserContribution {uid: x.id, user: gateway.user_name(x.id), xp: x.xp}).collect()))?;
+
+    json(&Paginated {page, per_page, items})
+}
+
+fn guild_leaderboard(req: &mut Request) -> IronResult<Response> {
+    let (page, per_page, offset) = get_page(req)?;
+    let conn = &mut db_conn(req)?;
+    let gateway = req.get_service().gateway;
+
+    let items = ApiError::with(|| Ok(Guild::get_top_page(offset, per_page, conn)?.into_iter()
+        .map(|x| web::GuildContribution {gid: x.id, guild: gateway.guild_name(x.id), xp: x.xp}).collect()))?;
+
+    json(&Paginated {page, per_page, items})
+}
+
+fn bottles(req: &mut Request) -> IronResult<Response> {
+    let (page, per_page, offset) = get_page(req)?;
+    let search = req.get_ref::<Params>().map_err(|_| ApiError::new(status::BadRequest, "Invalid parameters"))
+        .and_then(|params| web::bottle_search(params).map_err(|err| ApiError::new(status::BadRequest, &err)))?;
+
+    let conn = &mut db_conn(req)?;
+    let gateway = req.get_service().gateway;
+
+    let items = ApiError::with(|| Ok(Bottle::search(&search, offset, per_page, conn)?.into_iter()
+        .map(|b| ApiBottle::new(b, gateway.as_ref(), conn)).collect()))?;
+
+    json(&Paginated {page, per_page, items})
+}
+
+fn thread(req: &mut Request) -> IronResult<Response> {
+    let bid = get_id(req, "bottle")?;
+    let conn = &mut db_conn(req)?;
+    let gateway = req.get_service().gateway;
+    let gateway = gateway.as_ref();
+
+    let bottle = Bottle::get(bid, conn).ok().filter(|b| !Privacy::get(b.user, conn).bottles_hidden())
+        .ok_or_else(|| ApiError::new(status::NotFound, "Bottle not found"))?;
+
+    let data = ApiError::with(|| {
+        let (chain, truncated) = bottle.get_reply_list(conn)?;
+        let replies = bottle.get_replies(conn)?;
+
+        Ok(ApiThread {
+            bottle: ApiBottle::new(bottle.clone(), gateway, conn),
+            ancestors: chain.into_iter().skip(1).map(|b| ApiBottle::new(b, gateway, conn)).collect(),
+            truncated,
+            replies: replies.into_iter().map(|b| ApiBottle::new(b, gateway, conn)).collect()
+        })
+    })?;
+
+    json(&data)
+}
+
+pub fn make_chain(prerequisites: PrerequisiteMiddleware) -> Chain {
+    let mut router = Router::new();
+    router.get("/stats", stats, "stats");
+    router.get("/users/:user", user, "user");
+    router.get("/guilds/:guild", guild, "guild");
+    router.get("/leaderboard/users", user_leaderboard, "user_leaderboard");
+    router.get("/leaderboard/guilds", guild_leaderboard, "guild_leaderboard");
+    router.get("/bottles", bottles, "bottles");
+    router.get("/bottles/:bottle/thread", thread, "thread");
+
+    let mut chain = Chain::new(router);
+    chain.link_around(ApiAuth {limiter: RateLimiter::new()});
+    chain.link_before(prerequisites);
+    chain.link_after(ErrorMiddleware);
+
+    chain
+}
@@ -1,385 +1,684 @@
 use std::thread;
 use std::borrow::Cow;
-use chrono::{DateTime, Utc};
-use serenity::model::id::{ChannelId, UserId, GuildId, MessageId};
-use serenity::model::channel::{Message, ReactionType, Reaction, Embed, Attachment};
+use std::sync::Arc;
 use time::Duration;
-use diesel::prelude::*;
-use serenity::utils::Colour;
 
-use model::id::*;
 use model;
 use model::*;
-use diesel::sql_types::BigInt;
+use webhook::{self, Event, bottle_payload};
+use platform::{self, Platform, Incoming, Reacted, RenderedBottle};
+use gateway::ChatGateway;
+use metrics;
+use shutdown;
 use log::*;
+use serde_json::json;
 
-pub fn col_wheel(num: usize) -> Colour {
-    match num%8 {
-        0 => Colour::BLURPLE,
-        1 => Colour::BLUE,
-        2 => Colour::TEAL,
-        3 => Colour::DARK_GREEN,
-        4 => Colour::KERBAL,
-        5 => Colour::GOLD,
-        6 => Colour::DARK_RED,
-        _ => Colour::MAGENTA
+pub fn author_name(bottle: &Bottle, gateway: &dyn ChatGateway) -> String {
+    if bottle.anonymous {
+        bottle.pseudonym.map(pseudonym_name).unwrap_or_else(|| "Anonymous".to_owned())
+    } else {
+        gateway.user_name(bottle.user)
+    }
+}
+
+pub fn give_xp(bottle: &Bottle, xp: i32, reason: &str, conn: &mut Conn) -> Res<()> {
+    let mut u = User::get(bottle.user, conn);
+    u.xp += xp;
+    u.update(conn)?;
+
+    MakeXpEvent {user: u.id, guild: bottle.guild, bottle: Some(bottle.id), xp, reason, time: now()}.make(conn)?;
+
+    if let Some(g) = bottle.guild {
+        let mut contribution = GuildContribution::get((g, u.id), conn);
+        contribution.xp += xp;
+        contribution.update(conn)?;
+    }
+
+    Ok(())
+}
+
+fn test_prefix<T>(content: &mut String, p: &'static str, v: T) -> Option<T> {
+    if content.starts_with(p) {
+        content.drain(..p.len());
+        
+        Some(v)
+    } else {
+        None
     }
 }
 
-pub async fn render_bottle(bottle: &Bottle, edit: Option<MessageId>, mut level: usize, in_reply: bool, channel: ChannelId, cfg:&Config) -> Res<Message> {
-    channel.broadcast_typing().await?;
+/// Where a new bottle's fan-out runs once it's queued
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Delivery {
+    /// On its own thread, so the sender gets an answer right away
+    Background,
+    /// Before ``new_bottle`` returns, for callers that look at the deliveries straight after
+    Inline
+}
+
+/// The bot's rules, independent of which chat service the events come from
+#[derive(Clone)]
+pub struct BottleService {
+    pub gateway: Arc<dyn ChatGateway>,
+    pub pool: ConnPool,
+    pub cfg: Config,
+    pub delivery: Delivery
+}
+
+impl BottleService {
+    pub fn new(gateway: Arc<dyn ChatGateway>, pool: ConnPool, cfg: Config) -> BottleService {
+        BottleService {gateway, pool, cfg, delivery: Delivery::Background}
+    }
+
+    pub fn with_delivery(self, delivery: Delivery) -> BottleService {
+        BottleService {delivery, ..self}
+    }
+
+    /// Runs blocking work with a connection off the async runtime, see ``ConnPool::run``
+    pub async fn run<T, F>(&self, f: F) -> Res<T>
+        where T: Send + 'static, F: FnOnce(&BottleService, &mut Conn) -> Res<T> + Send + 'static {
+        let service = self.clone();
+        self.pool.run(move |conn| f(&service, conn)).await
+    }
 
-    if in_reply {
-        level += 1;
+    pub fn render(&self, bottle: &Bottle, level: usize, in_reply: bool) -> RenderedBottle {
+        RenderedBottle::new(bottle, level, in_reply, self.gateway.as_ref(), &self.cfg)
     }
 
-    let embd: Res<serenity::builder::CreateEmbed> = (|| {
-        let e = serenity::builder::CreateEmbed::default();
+    /// Sends (or edits) a bottle on whichever platform the channel belongs to, returning the platform and message id
+    pub fn send_bottle(&self, bottle: &Bottle, edit: Option<i64>, level: usize, in_reply: bool, channel: i64, conn: &mut Conn) -> Res<(Platform, i64)> {
+        let _timer = metrics::RENDER_SECONDS.start_timer();
+        let rendered = self.render(bottle, level, in_reply);
+
+        match Platform::of_channel(channel, conn)? {
+            (Platform::Discord, _) => match edit {
+                Some(message) => {
+                    self.gateway.edit_bottle(channel, message, &rendered)?;
+                    Ok((Platform::Discord, message))
+                },
+                None => Ok((Platform::Discord, self.gateway.send_bottle(channel, &rendered)?))
+            },
+
+            (platform, Some(mapped)) => match edit {
+                Some(message) => Ok((platform, message)),
+                None => {
+                    platform::get_bridge(platform)?.send(&mapped, &rendered)?;
+                    Ok((platform, PlatformId::next(conn)?))
+                }
+            },
 
-        if bottle.deleted {
-            e.title(format!("BOTTLE FROM {} IS DELETED", get_user_name(bottle.user))).description("This bottle has been deleted.");
-            return Ok(e);
+            (platform, None) => Err(format!("Channel {} isn't mapped to {}", channel, platform.name()).into())
         }
+    }
 
-        let title = if level > 0 { "You have found a message glued to the bottle!" } else { "You have recovered a bottle!" }; //TODO: better reply system, takes last bottle as an argument
+    #[tracing::instrument(skip_all, fields(channel = channel, guild = ?guild))]
+    pub fn distribute_to_channel(&self, (bottles, in_reply): (&Vec<(usize, Bottle)>, &bool), channel: i64, guild: Option<model::GuildId>, conn: &mut Conn) -> Res<()> {
+        let last_bottle = ReceivedBottle::get_last(channel, conn).ok().map(|x| x.id);
+        let unrepeated: Vec<&(usize, Bottle)> = bottles.into_iter().take_while(|(_, x)| Some(x.id) != last_bottle).collect();
 
-        let mut extra_info = String::new();
-        if let Some(x) = &bottle.url {
-            if bottle.contents.is_empty() {
-                extra_info.push_str(&format!(" [Link]({})", x));
-            }
-        };
+        //reply chains go back to where each bottle was sent, which belongs to that bottle's guild
+        let owner = guild.or_else(|| bottles.iter().find(|(_, b)| b.channel == channel).and_then(|(_, b)| b.guild));
 
-        if let Some(x) = bottle.guild {
-            extra_info.push_str(&format!(" [Guild]({})", guild_url(x, cfg)))
+        for (i, bottle) in unrepeated.into_iter().rev() {
+            let (platform, message) = self.send_bottle(&bottle, None, *i, *in_reply, channel, conn)?;
+            MakeReceivedBottle {bottle: bottle.id, channel, message, time_recieved: now(), platform: platform.id(), guild: owner}.make(conn)?;
         }
 
-        e.title(title)
-            .description(format!("{}{} [Report]({})", bottle.contents, extra_info, report_url(bottle.id, cfg)))
-            .timestamp(&DateTime::<Utc>::from_utc(bottle.time_pushed, Utc))
-            .color(col_wheel(level))
-            .footer(|footer|
-                if let Some(ref guild) = bottle.guild.and_then(|guild| GuildId(guild as u64).to_partial_guild().ok()) {
-                    let mut f = footer.text(&guild.name);
-                    if let Some(ref icon) = guild.icon_url() {
-                        f = f.icon_url(&icon);
-                    }
+        if let (Some(guild), Some((_, bottle))) = (guild, bottles.first()) {
+            webhook::fire(Event::BottleReceived, Some(guild), json!({"bottle": bottle_payload(bottle), "channel": channel}), conn);
+        }
 
-                    f
-                } else {
-                    footer.text("No guild found")
+        trace!("Delivered bottle to channel {}", &channel);
+        Ok (())
+    }
+
+    /// The ``deliver_to`` guilds that went longest without a bottle and accept this one. Filtered guilds
+    /// don't count towards the limit, so batches are fetched until enough are found or none are left
+    fn delivery_guilds(&self, bottle: &Bottle, conn: &mut Conn) -> Res<Vec<(model::GuildId, i64)>> {
+        let limit = self.cfg.economy.deliver_to;
+        let mut guilds = Vec::new();
+        let mut offset = 0;
+
+        loop {
+            let batch = Guild::get_delivery_channels(bottle.channel, bottle.reply_to.is_some(), offset, limit, conn)?;
+            let exhausted = (batch.len() as i64) < limit;
+            offset += batch.len() as i64;
+
+            for (guild, channel) in batch {
+                if !Guild::get(guild, conn).accepts(bottle) {
+                    trace!("Guild {} filtered out bottle {}", guild, bottle.id);
+                } else if (guilds.len() as i64) < limit {
+                    guilds.push((guild, channel));
                 }
-            )
-            .author(|author| {
-                if bottle.guild.is_some() {
-                    let user: Result<serenity::model::user::User, serenity::Error> = UserId(bottle.user as u64).to_user();
-                    let username = user.as_ref().map(|u| u.tag())
-                        .unwrap_or_else(|_| "Error fetching username".to_owned());
+            }
 
-                    let avatar = user.as_ref().ok().and_then(|u| u.avatar_url()).unwrap_or_else(|| anonymous_url(cfg));
+            if exhausted || guilds.len() as i64 >= limit {
+                return Ok(guilds);
+            }
+        }
+    }
 
-                    author.url(&user_url(bottle.user, cfg))
-                        .name(&username).icon_url(&avatar)
-                } else {
-                    author.name("Anonymous").icon_url(&anonymous_url(cfg))
+    #[tracing::instrument(skip_all, fields(bottle = bottle.id, guild = ?bottle.guild, user = bottle.user))]
+    pub fn distribute_bottle (&self, bottle: &Bottle, conn: &mut Conn) -> Res<()> {
+        let (bottles, in_reply) = bottle.get_reply_list(conn)?;
+        let bottles: Vec<(usize, Bottle)> = bottles.into_iter().rev().enumerate().rev().collect();
+
+        let guilds = self.delivery_guilds(bottle, conn)?;
+
+        let mut channels: Vec<(Option<i64>, i64)> =
+            guilds.into_iter().map(|(id, bottle_channel)| (Some(id), bottle_channel)).collect(); //tuple of guild and channel
+        channels.extend(bottles.iter().map(|(_, b)| (None, b.channel)));
+        channels.dedup();
+
+        //only non-empty when resuming a fan-out that was cut short
+        let received: Vec<i64> = ReceivedBottle::get_from_bottle(bottle.id, conn)?.into_iter().map(|r| r.channel).collect();
+
+        let mut fanout = 0;
+        for (guild, channel) in channels {
+            if channel != bottle.channel && !received.contains(&channel) {
+                fanout += 1;
+                metrics::DELIVERIES.inc();
+
+                if let Err(err) = self.distribute_to_channel((&bottles, &in_reply), channel, guild, conn) {
+                    metrics::DELIVERY_FAILURES.with_label_values(&[err.class()]).inc();
+
+                    if let Some(guild) = guild.filter(|g| *g >= 0) { //bridged channels come back when they rejoin
+                        debug!("Deleting guild {}, error sending: {}", guild, err);
+                        Guild::del(guild, conn)?;
+                    }
                 }
-            });
+            }
+        }
+
+        metrics::FANOUT.observe(fanout as f64);
+        Ok(())
+    }
+
+    /// Distributes a bottle queued in ``pending_delivery``, only dequeuing it once the whole fan-out went through
+    fn deliver_pending(&self, bottle: &Bottle, conn: &mut Conn) -> Res<()> {
+        self.distribute_bottle(bottle, conn)?;
+        PendingDelivery::del(bottle.id, conn)?;
+        Ok(())
+    }
+
+    fn spawn_delivery(&self, bottle: Bottle) {
+        let service = self.clone();
+        let span = tracing::Span::current();
+        let work = shutdown::track();
+
+        thread::spawn(move || {
+            let _span = span.enter();
+            if let Err(err) = service.pool.get_conn().and_then(|mut conn| service.deliver_pending(&bottle, &mut conn)) {
+                error!("Error distributing bottle {}: {}", bottle.id, err);
+            }
+
+            drop(work);
+        });
+    }
 
-        if let Some(img) = &bottle.image {
-            e.image(img).url(img);
+    /// Picks up fan-outs cut short by the last shutdown, channels that already got the bottle are skipped
+    pub fn resume_deliveries(&self) -> Res<()> {
+        let pending = PendingDelivery::get_all(&mut self.pool.get_conn()?)?;
+        if !pending.is_empty() {
+            info!("Resuming {} pending deliveries", pending.len());
         }
 
-        if let Some(url) = &bottle.url {
-            e.url(url);
+        for bottle in pending {
+            self.spawn_delivery(bottle);
         }
 
-        Ok(e)
-    })();
+        Ok(())
+    }
 
-    let embd = embd?;
+    /// Runs every queued fan-out on this thread, returning how many there were
+    pub fn reprocess_deliveries(&self, conn: &mut Conn) -> Res<usize> {
+        let pending = PendingDelivery::get_all(conn)?;
+        let count = pending.len();
 
-    let msg = {
-        if let Some(x) = edit {
-            channel.edit_message(x, |x| x.embed(|_| embd))
-        } else {
-            channel.send_message(|x| x.embed(|_| embd))
+        for bottle in pending {
+            self.deliver_pending(&bottle, conn)?;
         }
-    }?;
 
-    Ok(msg)
-}
+        Ok(count)
+    }
 
-const DELIVERNUM: i64 = 4;
+    pub fn report_bottle(&self, bottle: &Bottle, user: model::UserId, conn: &mut Conn) -> Res<ReceivedBottleId> {
+        let cfg = &self.cfg;
+        let channel = cfg.discord.admin_channel;
+        let user = self.gateway.get_user(user).ok_or(BottleError::NotFound("user"))?;
+        let author = self.gateway.get_user(bottle.user).map(|u| u.tag).unwrap_or_else(|| "User not found".to_owned());
+        let msg = self.gateway.say(channel, &format!("REPORT FROM {}. USER ID {}, BOTTLE ID {}. AUTHOR {} (USER ID {}).", user.tag, user.id, bottle.id, author, bottle.user))?;
 
-pub fn distribute_to_channel((bottles, in_reply): (&Vec<(usize, Bottle)>, &bool), channel: i64, conn: &Conn, cfg:&Config) -> Res<()> {
-    let bottlechannelid = ChannelId(channel as u64);
+        let bottlemsg = self.gateway.send_bottle(channel, &self.render(bottle, 0, true))?;
 
-    let last_bottle = ReceivedBottle::get_last(channel, conn).ok().map(|x| x.id);
-    let unrepeated: Vec<&(usize, Bottle)> = bottles.into_iter().take_while(|(_, x)| Some(x.id) != last_bottle).collect();
+        self.gateway.react(channel, msg, &cfg.moderation.ban_emoji)?;
+        self.gateway.react(channel, bottlemsg, &cfg.moderation.ban_emoji)?;
+        self.gateway.react(channel, bottlemsg, &cfg.moderation.delete_emoji)?;
 
-    for (i, bottle) in unrepeated.into_iter().rev() {
-        let msg = render_bottle(&bottle, None, *i, *in_reply, bottlechannelid, cfg)?;
-        MakeReceivedBottle {bottle: bottle.id, channel: bottlechannelid.as_i64(), message: msg.id.as_i64(), time_recieved: now()}.make(conn)?;
+        let recv = MakeReceivedBottle {bottle: bottle.id, channel, message: bottlemsg, time_recieved: now(), platform: Platform::Discord.id(), guild: None}.make(conn)?;
+        //the reporter is named, so only the bot's own webhooks hear about reports, not the reported guild's
+        webhook::fire(Event::ReportFiled, None, json!({"bottle": bottle_payload(bottle), "reporter": user.id}), conn);
+        metrics::REPORTS.inc();
+
+        Ok(recv.id)
     }
 
-    trace!("Delivered bottle to channel {}", &channel);
-    Ok (())
-}
+    pub fn del_bottle(&self, mut b: Bottle, conn: &mut Conn) -> Res<()> {
+        trace!("Bottle deleted");
 
-#[derive(QueryableByName)]
-struct GuildsResult {
-    #[sql_type="BigInt"] #[column_name="id"]
-    id: i64,
-    #[sql_type="BigInt"] #[column_name="bottle_channel"]
-    bottle_channel: i64
-}
+        Bottle::del(b.id, conn)?;
+        b.deleted = true;
+        webhook::fire(Event::BottleDeleted, b.guild, bottle_payload(&b), conn);
 
-pub fn distribute_bottle (bottle: &Bottle, conn:&Conn, cfg:&Config) -> Res<()> {
-    let (bottles, in_reply) = bottle.get_reply_list(conn)?;
-    let bottles: Vec<(usize, Bottle)> = bottles.into_iter().rev().enumerate().rev().collect();
-
-    let guilds: Vec<GuildsResult> = diesel::sql_query(
-        "SELECT \"id\", bottle_channel FROM (SELECT DISTINCT ON (guild.id) guild.id, bottle_channel, time_recieved FROM guild LEFT JOIN received_bottle ON (bottle_channel = received_bottle.channel) ORDER BY guild.id, received_bottle.time_recieved DESC) channels
-        WHERE bottle_channel IS NOT NULL ORDER BY time_recieved ASC NULLS FIRST LIMIT $2")
-        .bind::<BigInt, _>(bottle.channel).bind::<BigInt, _>(DELIVERNUM).load(conn)?;
-
-    let mut channels: Vec<(Option<i64>, i64)> =
-        guilds.into_iter().map(|GuildsResult {id, bottle_channel}| (Some(id), bottle_channel)).collect(); //tuple of guild and channel
-    channels.extend(bottles.iter().map(|(_, b)| (None, b.channel)));
-    channels.dedup();
-
-    for (guild, channel) in channels {
-        if channel != bottle.channel {
-            if let Err(err) = distribute_to_channel((&bottles, &in_reply), channel, conn, cfg) {
-                if let Some(guild) = guild {
-                    debug!("Deleting guild {}, error sending: {}", guild, err);
-                    Guild::del(guild, conn)?;
-                }
+        for rb in ReceivedBottle::get_from_bottle(b.id, conn)? {
+            let _ = self.send_bottle(&b, Some(rb.message), 0, false, rb.channel, conn);
+        }
+
+        Ok(())
+    }
+
+    pub fn erase_user(&self, uid: model::UserId, conn: &mut Conn) -> Res<()> {
+        info!("Erasing user {}", uid);
+
+        let bottles = User::get(uid, conn).get_all_bottles(conn)?;
+        User::erase(uid, conn)?;
+        self.gateway.forget_user(uid);
+
+        for b in bottles {
+            let b = Bottle::get(b.id, conn)?;
+
+            for rb in ReceivedBottle::get_from_bottle(b.id, conn)? {
+                let _ = self.send_bottle(&b, Some(rb.message), 0, false, rb.channel, conn);
             }
         }
+
+        Ok(())
     }
 
-    Ok(())
-}
+    /// Takes down everything the user sent, ``report`` is the bottle that got them banned if there was one
+    pub fn ban_user(&self, user: model::UserId, report: Option<BottleId>, conn: &mut Conn) -> Res<()> {
+        for x in User::get(user, conn).get_all_bottles(conn)? {
+            self.del_bottle(x, conn)?;
+        }
 
-pub fn report_bottle(bottle: &Bottle, user: model::UserId, conn: &Conn, cfg: &Config) -> Res<ReceivedBottleId> {
-    let channel = ChannelId(cfg.admin_channel as u64);
-    let user = UserId(user as u64).to_user()?;
-    let msg = channel.say(&format!("REPORT FROM {}. USER ID {}, BOTTLE ID {}.", user.tag(), user.id, bottle.id))?;
+        Ban {user, report}.make(conn)?;
+        metrics::BANS.inc();
+        webhook::fire(Event::BanIssued, None, json!({"user": user, "report": report}), conn);
+        Ok(())
+    }
 
-    let bottlemsg: Message = render_bottle(&bottle, None, 0, true, channel, cfg)?;
+    pub fn unban_user(&self, user: model::UserId, conn: &mut Conn) -> Res<usize> {
+        Ok(Ban {user, report: None}.del(conn)?)
+    }
 
-    msg.react(cfg.ban_emoji.as_str())?;
-    bottlemsg.react(cfg.ban_emoji.as_str())?;
-    bottlemsg.react(cfg.delete_emoji.as_str())?;
+    pub fn react(&self, r: &Reacted, add: bool, conn: &mut Conn) -> Res<()> {
+        trace!("Reaction added: {}", r.emoji);
 
-    let recv = MakeReceivedBottle {bottle: bottle.id, channel: channel.as_i64(), message: bottlemsg.id.as_i64(), time_recieved: now()}.make(conn)?;
+        let cfg = &self.cfg;
+        let user = User::get(r.user, conn);
+        let mid = r.message;
+        let emoji_name = &r.emoji;
 
-    Ok(recv.id)
-}
+        let ban =
+            |report: Report, user: model::UserId, conn: &mut Conn| -> Res<()> { //either received or original
+                if add {
+                    self.ban_user(user, Some(report.bottle), conn)
+                } else {
+                    self.unban_user(user, conn).map(|_| ())
+                }
+            };
 
-pub fn del_bottle(mut b: Bottle, conn: &Conn, cfg: &Config) -> Res<()> {
-    trace!("Bottle deleted");
+        if user.admin {
+            let user = user.id;
 
-    Bottle::del(b.id, conn)?;
-    b.deleted = true;
+            if *emoji_name == cfg.moderation.ban_emoji {
+                if let Ok(recv) = ReceivedBottle::get_from_message(mid, conn) {
+                    let buser = Bottle::get(recv.bottle, conn)?.user;
 
-    for rb in ReceivedBottle::get_from_bottle(b.id, conn)? {
-        let _ = render_bottle(&b, Some(MessageId(rb.message as u64)), 0, false, ChannelId(rb.channel as u64), cfg);
+                    if let Ok(report) = Report::get_from_recv_user(recv.id, user, conn) { ban(report, buser, conn)?; } else {
+                        let rep = Report { bottle: recv.bottle, user, received_bottle: Some(recv.id) }.make(conn)?;
+                        ban(rep, buser, conn)?;
+                    }
+                } else if let Ok(bottle) = Bottle::get_from_message(mid, conn) {
+                    let rep = Report { bottle: bottle.id, user, received_bottle: None }.make(conn)?;
+                    ban( rep, bottle.user, conn)?;
+                }
+            } else if let Ok(bottle) = Bottle::get_recv_or_bottle_from_message(mid, conn) {
+                if *emoji_name == cfg.moderation.delete_emoji && add {
+                    self.del_bottle(bottle, conn)?;
+                }
+            }
+        }
+
+        Ok(())
     }
 
-    Ok(())
-}
+    /// Spans the bottle's whole journey, deliveries on other threads are recorded under it
+    #[tracing::instrument(skip_all, fields(user = new_msg.user, channel = new_msg.channel, guild = ?guild, bottle = tracing::field::Empty))]
+    pub fn new_bottle<'a, 'b>(&self, new_msg: &'a Incoming, guild: Option<model::GuildId>, conn: &mut Conn) -> Res<Option<Cow<'b, str>>> {
+        trace!("New bottle found");
+
+        let userid = new_msg.user;
+        let msgid = new_msg.message;
+        let channelid = new_msg.channel;
+        let mut contents = new_msg.content.trim().to_owned();
+
+        let anonymous = test_prefix(&mut contents, ANONYMOUS_PREFIX, true).unwrap_or(false);
+        let prefix = test_prefix(&mut contents, SEND_PREFIX, Prefix::SendPrefix)
+            .or_else(|| test_prefix(&mut contents, BRANCH_REPLY_PREFIX, Prefix::BranchReplyPrefix)) //in this order cuz one prefix includes the other
+            .or_else(|| test_prefix(&mut contents, REPLY_PREFIX, Prefix::ReplyPrefix));
 
-pub fn react(conn: &Conn, r: Reaction, add: bool, cfg: &Config) -> Res<()> {
-    trace!("Reaction added: {}", r.emoji.to_string());
+        let prefix = match prefix {
+            None => return Ok(None),
+            Some(x) => x
+        };
 
-    let user = {
-        let x = r.user()?;
-        if x.bot {
-            return Ok(());
+        if shutdown::stopping() {
+            return Err(BottleError::ShuttingDown);
         }
 
-        User::get(x.id.as_i64(), conn)
-    };
+        let economy = &self.cfg.economy;
+        let mut user = User::get(userid, conn);
+
+        let lastbottle = user.get_bottle(conn).ok();
+        let ticket_res = |mut user: User, err: BottleError| -> Res<Option<Cow<'b, str>>>  {
+            metrics::REJECTIONS.with_label_values(&[err.class()]).inc();
+            user.tickets += 1;
+            user.update(conn)?;
 
-    let mid = r.message_id.as_i64();
+            if user.tickets > economy.max_tickets {
+                Ok(None)
+            } else {
+                Ok(Some(err.user_message().into()))
+            }
+        };
 
-    let emoji_name = match r.emoji {
-        ReactionType::Unicode(x) => x,
-        _ => return Ok (())
-    };
+        if !user.admin {
+            if user.get_banned(conn)? {
+                return ticket_res(user, BottleError::Banned);
+            }
 
-    let ban =
-        |report: Report, user: model::UserId, conn: &Conn| -> Res<()> { //either received or original
-            let b = Ban { user, report: Some(report.bottle) };
+            if let Some(ref bottle) = lastbottle {
+                let since_push = now().signed_duration_since(bottle.time_pushed);
+                let cooldown = Duration::minutes(economy.cooldown);
 
-            if add {
-                let u = User::get(user, conn);
-                for x in u.get_all_bottles(conn)? {
-                    del_bottle(x, conn, cfg)?;
+                if since_push < cooldown {
+                    let towait = cooldown - since_push;
+                    return ticket_res(user, BottleError::Cooldown {remaining: towait.num_seconds()});
                 }
+            }
+        }
 
-                b.make(conn)?;
-            } else {
-                b.del(conn)?;
+        if let Some(gid) = guild {
+            if anonymous && !Guild::get(gid, conn).allow_anonymous {
+                return ticket_res(user, BottleError::Permission("This guild doesn't allow anonymous bottles!".to_owned()));
             }
+        }
+
+        let url = new_msg.url.clone();
+        let image = new_msg.image.clone();
 
-            Ok(())
+        if url.is_none() && image.is_none() && contents.len() == 0 && !user.admin {
+            return ticket_res(user, BottleError::Empty);
+        }
+
+        let reply_to = match prefix {
+            Prefix::ReplyPrefix => {
+                //TODO: better things in life, you know. i hate when things are done so lousy, prohibiting the rights of errors. errors need better lives. and in this tempest, they live short. they have the potential to be pronounced, but instead they are discarded to the binary choices of "existent" or "nonexistent". the ultimatum is that when they are declared as either, they die. lost to the void. overwritten with new bits and bytes of blinking lights...
+                let bottle = Bottle::get_last(channelid, conn);
+                Some(bottle)
+            },
+            Prefix::BranchReplyPrefix => {
+                let rbottle = ReceivedBottle::get_last(channelid, conn);
+                Some(rbottle)
+            },
+            Prefix::SendPrefix => None
+        };
+
+        let reply_to = match reply_to {
+            Some(Ok(x)) => Some(x),
+            Some(Err(_)) => return ticket_res(user, BottleError::NotFound("bottle to reply to")),
+            None => None
         };
 
-    if user.admin {
-        let user = user.id;
+        user.tickets = 0;
+        user.update(conn)?;
+        metrics::BOTTLES_SENT.with_label_values(&[prefix.name()]).inc();
 
-        if emoji_name == cfg.ban_emoji {
-            if let Ok(recv) = ReceivedBottle::get_from_message(mid, conn) {
-                let buser = Bottle::get(recv.bottle, conn)?.user;
+        let mut bottle = MakeBottle {
+                message: msgid, reply_to: reply_to.as_ref().map(|r| r.id),
+                channel: channelid, guild, user: user.id,
+                time_pushed: now(), contents, url, image,
+                anonymous: anonymous || guild.is_none(), platform: new_msg.platform.id()
+            }.make(conn)?;
 
-                if let Ok(report) = Report::get_from_recv_user(recv.id, user, conn) { ban(report, buser, conn)?; } else {
-                    let rep = Report { bottle: recv.bottle, user, received_bottle: Some(recv.id) }.make(conn)?;
-                    ban(rep, buser, conn)?;
-                }
-            } else if let Ok(bottle) = Bottle::get_from_message(mid, conn) {
-                let rep = Report { bottle: bottle.id, user, received_bottle: None }.make(conn)?;
-                ban( rep, bottle.user, conn)?;
+        tracing::Span::current().record("bottle", bottle.id);
+
+        if bottle.anonymous {
+            let thread = bottle.get_thread_root(conn)?;
+            let number = Pseudonym::get_or_make(thread, user.id, conn)?.number;
+
+            Bottle::set_pseudonym(bottle.id, number, conn)?;
+            bottle.pseudonym = Some(number);
+        }
+
+        webhook::fire(Event::BottleSent, bottle.guild, bottle_payload(&bottle), conn);
+        if let Some(r) = &reply_to {
+            webhook::fire(Event::ReplyReceived, r.guild, json!({"bottle": bottle_payload(&bottle), "reply_to": bottle_payload(r)}), conn);
+        }
+
+        let mut xp = 0;
+
+        xp += economy.push_xp;
+
+        if let Some(r) = &reply_to {
+            if r.user != new_msg.user {
+                give_xp(r, economy.reply_xp, REPLY_REASON, conn)?;
             }
-        } else if let Ok(bottle) = Bottle::get_recv_or_bottle_from_message(mid, conn) {
-            if emoji_name == cfg.delete_emoji && add {
-                del_bottle(bottle, conn, cfg)?;
+        }
+
+        if bottle.url.is_some() { xp += economy.url_xp; }
+        if bottle.image.is_some() { xp += economy.image_xp; }
+
+        give_xp(&bottle, xp, BOTTLE_REASON, conn)?;
+
+        debug!("Sending bottle: {:?}", &bottle);
+        PendingDelivery {bottle: bottle.id, queued: now()}.make(conn)?;
+
+        match self.delivery {
+            Delivery::Background => self.spawn_delivery(bottle),
+            Delivery::Inline => if let Err(err) = self.deliver_pending(&bottle, conn) {
+                error!("Error distributing bottle {}: {}", bottle.id, err); //still pending, so it's retried like a cut short fan-out
             }
         }
-    }
 
-    Ok(())
+        Ok(Some("Your message has been cast away!".into()))
+    }
 }
 
-pub fn give_xp(bottle: &Bottle, xp: i32, conn:&Conn) -> Res<()> {
-    let mut u = User::get(bottle.user, conn);
-    u.xp += xp;
-    u.update(conn)?;
+#[cfg(test)]
+mod tests {
+    use model::*;
+    use testing::*;
+    use webhook::Event;
+
+    const A: UserId = 101;
+    const B: UserId = 102;
+    const ADMIN: UserId = 103;
+
+    const G1: GuildId = 1;
+    const G2: GuildId = 2;
+    const C1: i64 = 11;
+    const C2: i64 = 21;
+
+    fn ocean() -> Harness {
+        let h = Harness::new();
+
+        h.user(A, "a#0001");
+        h.user(B, "b#0002");
+        h.admin(ADMIN, "admin#0003");
+        h.guild(G1, "One", C1);
+        h.guild(G2, "Two", C2);
+        h
+    }
 
-    if let Some(g) = bottle.guild {
-        let mut contribution = GuildContribution::get((g, u.id), conn);
-        contribution.xp += xp;
-        contribution.update(conn)?;
+    #[test]
+    fn bottle_reaches_other_guilds() {
+        let h = ocean();
+
+        let (msg, res) = h.send(A, C1, Some(G1), "> hi");
+        assert_eq!(res.unwrap().as_deref(), Some("Your message has been cast away!"));
+
+        let conn = &mut h.conn();
+        let bottle = Bottle::get_from_message(msg, conn).unwrap();
+        assert_eq!(bottle.contents.trim(), "hi");
+        assert_eq!(bottle.guild, Some(G1));
+
+        let received: Vec<i64> = ReceivedBottle::get_from_bottle(bottle.id, conn).unwrap().into_iter().map(|r| r.channel).collect();
+        assert_eq!(received, vec![C2]);
+        assert!(h.gateway.messages_in(C1).is_empty());
+
+        let delivered = h.gateway.messages_in(C2);
+        assert_eq!(delivered.len(), 1);
+
+        let embed = delivered[0].embed.as_ref().unwrap();
+        assert_eq!(embed.title, "You have recovered a bottle!");
+        assert_eq!(embed.author, "a#0001");
+        assert_eq!(embed.origin.as_deref(), Some("One"));
+        assert_eq!(User::get(A, conn).xp, PUSHXP);
     }
 
-    Ok(())
-}
+    #[test]
+    fn reply_travels_back() {
+        let h = ocean();
 
-fn test_prefix<T>(content: &mut String, p: &'static str, v: T) -> Option<T> {
-    if content.starts_with(p) {
-        content.drain(..p.len());
-        
-        Some(v)
-    } else {
-        None
+        let (first, _) = h.send(A, C1, Some(G1), "> hi");
+        let (second, res) = h.send(B, C2, Some(G2), "-> hey");
+        assert_eq!(res.unwrap().as_deref(), Some("Your message has been cast away!"));
+
+        let conn = &mut h.conn();
+        let bottle = Bottle::get_from_message(first, conn).unwrap();
+        let reply = Bottle::get_from_message(second, conn).unwrap();
+        assert_eq!(reply.reply_to, Some(bottle.id));
+
+        assert!(ReceivedBottle::get_from_bottle(reply.id, conn).unwrap().iter().any(|r| r.channel == C1));
+        assert!(h.gateway.messages_in(C1).iter()
+            .any(|m| m.embed.as_ref().map(|e| e.contents.trim() == "hey" && e.level > 0).unwrap_or(false)));
+
+        assert_eq!(User::get(A, conn).xp, PUSHXP + REPLYXP);
+        assert_eq!(User::get(B, conn).xp, PUSHXP);
     }
-}
 
-pub fn new_bottle<'a, 'b>(new_msg: &'a Message, guild: Option<model::GuildId>, connpool:ConnPool, cfg:Config) -> Res<Option<Cow<'b, str>>> {
-    trace!("New bottle found");
+    #[test]
+    fn ban_reaction_deletes_bottles() {
+        let h = ocean();
 
-    let userid = new_msg.author.id.as_i64();
-    let msgid = new_msg.id.as_i64();
-    let channelid = new_msg.channel_id.as_i64();
-    let mut contents = new_msg.content.trim().to_owned();
+        let (msg, _) = h.send(A, C1, Some(G1), "> hi");
+        let copy = h.gateway.messages_in(C2)[0].id;
 
-    let prefix = test_prefix(&mut contents, SEND_PREFIX, Prefix::SendPrefix)
-        .or_else(|| test_prefix(&mut contents, BRANCH_REPLY_PREFIX, Prefix::BranchReplyPrefix)) //in this order cuz one prefix includes the other
-        .or_else(|| test_prefix(&mut contents, REPLY_PREFIX, Prefix::ReplyPrefix));
+        h.react(ADMIN, copy, BAN_EMOJI, true).unwrap();
 
-    let prefix = match prefix {
-        None => return Ok(None),
-        Some(x) => x
-    };
+        let conn = &mut h.conn();
+        assert!(User::get(A, conn).get_banned(conn).unwrap());
+        assert!(Bottle::get_from_message(msg, conn).unwrap().deleted);
 
-    let conn = &connpool.get_conn();
-    let mut user = User::get(userid, conn);
+        let edited = h.gateway.message(copy).unwrap();
+        let embed = edited.embed.unwrap();
+        assert_eq!(edited.edits, 1);
+        assert!(embed.deleted);
+        assert_eq!(embed.title, "BOTTLE FROM a#0001 IS DELETED");
 
-    let lastbottle = user.get_bottle(conn).ok();
-    let ticket_res = |mut user: User, err| -> Res<Option<Cow<'b, str>>>  {
-        user.tickets += 1;
-        user.update(conn)?;
+        let (_, res) = h.send(A, C1, Some(G1), "> again");
+        assert!(res.unwrap().unwrap().starts_with("You are banned"));
+    }
 
-        if user.tickets > MAX_TICKETS {
-            Ok(None)
-        } else {
-            Ok(Some(err))
-        }
-    };
+    #[test]
+    fn reactions_from_users_are_ignored() {
+        let h = ocean();
 
-    if !user.admin {
-        if user.get_banned(conn)? {
-            return ticket_res(user, "You are banned from using Bottle! Appeal by dming the global admins!".into());
-        }
+        let (msg, _) = h.send(A, C1, Some(G1), "> hi");
+        let copy = h.gateway.messages_in(C2)[0].id;
 
-        if let Some(ref bottle) = lastbottle {
-            let since_push = now().signed_duration_since(bottle.time_pushed);
-            let cooldown = Duration::minutes(COOLDOWN);
+        h.react(B, copy, BAN_EMOJI, true).unwrap();
+        h.react(B, copy, DELETE_EMOJI, true).unwrap();
 
-            if since_push < cooldown {
-                let towait = cooldown - since_push;
-                return ticket_res(user, format!("You must wait {} seconds before sending another bottle!", towait.num_seconds()).into());
-            }
-        }
+        let conn = &mut h.conn();
+        assert!(!User::get(A, conn).get_banned(conn).unwrap());
+        assert!(!Bottle::get_from_message(msg, conn).unwrap().deleted);
     }
 
-    let url = new_msg.embeds.get(0).and_then(|emb: &Embed| emb.url.clone());
-    let image = new_msg.attachments.get(0).map(|a: &Attachment| a.url.clone());
+    #[test]
+    fn guild_filters_block_delivery() {
+        let h = ocean();
+
+        {
+            let conn = &mut h.conn();
+            let mut g = Guild::get(G2, conn);
+            g.blocked_words = Some("spoon".to_owned());
+            g.update(conn).unwrap();
+        }
+
+        let (msg, _) = h.send(A, C1, Some(G1), "> a SPOON");
 
-    if url.is_none() && image.is_none() && contents.len() == 0 && !user.admin {
-        return ticket_res(user, "Your bottle cannot be empty!".into());
+        let conn = &mut h.conn();
+        let bottle = Bottle::get_from_message(msg, conn).unwrap();
+        assert!(ReceivedBottle::get_from_bottle(bottle.id, conn).unwrap().is_empty());
+        assert!(h.gateway.messages_in(C2).is_empty());
     }
 
-    let reply_to = match prefix {
-        Prefix::ReplyPrefix => {
-            //TODO: better things in life, you know. i hate when things are done so lousy, prohibiting the rights of errors. errors need better lives. and in this tempest, they live short. they have the potential to be pronounced, but instead they are discarded to the binary choices of "existent" or "nonexistent". the ultimatum is that when they are declared as either, they die. lost to the void. overwritten with new bits and bytes of blinking lights...
-            let bottle = Bottle::get_last(channelid, conn);
-            Some(bottle)
-        },
-        Prefix::BranchReplyPrefix => {
-            let rbottle = ReceivedBottle::get_last(channelid, conn);
-            Some(rbottle)
-        },
-        Prefix::SendPrefix => None
-    };
-
-    let reply_to = match reply_to {
-        Some(Ok(x)) => Some(x),
-        Some(Err(_)) => return ticket_res(user, "No bottle to reply to was found!".into()),
-        None => None
-    };
-
-    user.tickets = 0;
-    user.update(conn)?;
-
-    let bottle = MakeBottle {
-            message: msgid, reply_to: reply_to.as_ref().map(|r| r.id),
-            channel: channelid, guild, user: user.id,
-            time_pushed: now(), contents, url, image
-        }.make(conn)?;
-
-    let mut xp = 0;
-
-    xp += PUSHXP;
-
-    if let Some(r) = &reply_to {
-        if r.user != new_msg.author.id.as_i64() {
-            give_xp(r, REPLYXP, conn)?;
+    #[test]
+    fn filtered_guilds_leave_room_for_others() {
+        let mut h = ocean();
+        h.service.cfg.economy.deliver_to = 1;
+        h.guild(3, "Three", 31);
+
+        {
+            let conn = &mut h.conn();
+            let mut g = Guild::get(G2, conn);
+            g.blocked_words = Some("spoon".to_owned());
+            g.update(conn).unwrap();
         }
+
+        h.send(A, C1, Some(G1), "> a spoon");
+
+        assert!(h.gateway.messages_in(C2).is_empty());
+        assert_eq!(h.gateway.messages_in(31).len(), 1);
     }
 
-    if bottle.url.is_some() { xp += URLXP; }
-    if bottle.image.is_some() { xp += IMAGEXP; }
+    #[test]
+    fn report_reaches_admins() {
+        let h = ocean();
+
+        let (msg, _) = h.send(A, C1, Some(G1), "> hi");
 
-    give_xp(&bottle, xp, conn)?;
+        let conn = &mut h.conn();
+        let bottle = Bottle::get_from_message(msg, conn).unwrap();
+        h.service.report_bottle(&bottle, B, conn).unwrap();
 
-    debug!("Sending bottle: {:?}", &bottle);
+        let admin = h.gateway.messages_in(ADMIN_CHANNEL);
+        assert_eq!(admin.len(), 2);
+        assert!(admin[0].text.as_ref().unwrap().starts_with("REPORT FROM b#0002"));
+        assert_eq!(admin[0].reactions, vec![BAN_EMOJI.to_owned()]);
+        assert_eq!(admin[1].reactions, vec![BAN_EMOJI.to_owned(), DELETE_EMOJI.to_owned()]);
+    }
 
-    thread::spawn(move || {
-        let _ = distribute_bottle(&bottle, &connpool.get_conn(), &cfg);
-    });
+    #[test]
+    fn reports_only_reach_global_webhooks() {
+        let h = ocean();
+        let (msg, _) = h.send(A, C1, Some(G1), "> hi");
 
-    Ok(Some("Your message has been cast away!".into()))
-}
\ No newline at end of file
+        let conn = &mut h.conn();
+        for guild in [Some(G1), None] {
+            MakeWebhook {guild, url: "https://example.com/hook".to_owned(), secret: "secret".to_owned(), events: Event::ReportFiled.bit(), created: now()}
+                .make(conn).unwrap();
+        }
+
+        let bottle = Bottle::get_from_message(msg, conn).unwrap();
+        h.service.report_bottle(&bottle, B, conn).unwrap();
+
+        let due = WebhookDelivery::get_due(10, conn).unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].1.guild, None);
+        assert!(due[0].0.payload.contains("\"reporter\""));
+    }
+}
@@ -0,0 +1,169 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use clap::{Parser, Subcommand};
+use serde_json;
+
+use model::*;
+use data::*;
+use settings;
+use platform;
+use bottle::BottleService;
+use gateway::HttpGateway;
+use profiles::CachedGateway;
+
+#[derive(Parser)]
+#[command(name = "bottle", version, about = "Messages in bottles across Discord guilds and bridged chats")]
+pub struct Cli {
+    /// TOML config, see bottle.example.toml
+    #[arg(long, global = true, default_value = settings::DEFAULT_PATH)]
+    pub config: String,
+
+    /// Validate the configuration and exit without connecting to anything
+    #[arg(long)]
+    pub check_config: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the bot and the web server (the default)
+    Run,
+    /// Run only the web server
+    WebOnly,
+    /// Run only the bot, with its background workers
+    BotOnly,
+    /// Manage database migrations
+    Migrate {
+        #[command(subcommand)]
+        action: Migrate
+    },
+    /// Ban a user, taking down everything they've sent
+    Ban {
+        user: UserId,
+        /// Bottle the ban is for
+        #[arg(long)]
+        report: Option<BottleId>
+    },
+    Unban {
+        user: UserId
+    },
+    /// Make a user a global admin
+    Promote {
+        user: UserId,
+        /// Take admin away instead
+        #[arg(long)]
+        demote: bool
+    },
+    /// Write everything stored about a user as JSON
+    Export {
+        user: UserId,
+        /// Defaults to stdout
+        #[arg(long)]
+        out: Option<PathBuf>
+    },
+    /// Print database counts and queue sizes
+    Stats,
+    /// Finish bottle fan-outs left in pending_delivery
+    ReprocessDeliveries
+}
+
+#[derive(Subcommand)]
+pub enum Migrate {
+    /// Apply pending migrations
+    Up,
+    /// Revert the last applied migration
+    Down,
+    /// List applied and pending migrations
+    Status
+}
+
+/// Anything that edits delivered bottles needs Discord's REST API, but not a gateway connection
+fn offline_service(cfg: &Config, pool: &ConnPool) -> Res<BottleService> {
+    platform::load_names(&mut pool.get_conn()?)?;
+
+    let gateway = CachedGateway::new(Arc::new(HttpGateway::new(&cfg.discord.token)), pool.clone(), &cfg.cache);
+    Ok(BottleService::new(Arc::new(gateway), pool.clone(), cfg.clone()))
+}
+
+/// Runs the operator commands, ``run``, ``web-only`` and ``bot-only`` are handled by ``main``
+pub fn maintain(command: Command, cfg: &Config) -> Res<()> {
+    let pool = ConnPool::connect(&cfg.database.url)?;
+
+    match command {
+        Command::Migrate {action: Migrate::Up} => {
+            pool.run_migrations().map_err(|err| err.to_string())?;
+            println!("Migrations are up to date.");
+        },
+
+        Command::Migrate {action: Migrate::Down} => {
+            let version = pool.revert_migration().map_err(|err| err.to_string())?;
+            println!("Reverted {}.", version);
+        },
+
+        Command::Migrate {action: Migrate::Status} => {
+            let (applied, pending) = pool.migration_status().map_err(|err| err.to_string())?;
+            for version in applied {
+                println!("applied  {}", version);
+            }
+
+            for version in pending {
+                println!("pending  {}", version);
+            }
+        },
+
+        Command::Ban {user, report} => {
+            let service = offline_service(cfg, &pool)?;
+            service.ban_user(user, report, &mut pool.get_conn()?)?;
+            println!("Banned {} and took down their bottles.", user);
+        },
+
+        Command::Unban {user} => {
+            match offline_service(cfg, &pool)?.unban_user(user, &mut pool.get_conn()?)? {
+                0 => println!("{} wasn't banned.", user),
+                _ => println!("Unbanned {}.", user)
+            }
+        },
+
+        Command::Promote {user, demote} => {
+            let conn = &mut pool.get_conn()?;
+            let mut u = User::get(user, conn);
+            u.admin = !demote;
+            u.update(conn)?;
+
+            println!("{} {}.", if demote { "Demoted" } else { "Promoted" }, user);
+        },
+
+        Command::Export {user, out} => {
+            let conn = &mut pool.get_conn()?;
+            let json = serde_json::to_string_pretty(&User::get(user, conn).export(conn)?)?;
+
+            match out {
+                Some(path) => fs::write(path, json)?,
+                None => println!("{}", json)
+            }
+        },
+
+        Command::Stats => {
+            let conn = &mut pool.get_conn()?;
+            println!("bottles              ~{}", get_bottle_count(conn)?);
+            println!("users                ~{}", get_user_count(conn)?);
+            println!("guilds               ~{}", get_guild_count(conn)?);
+            println!("pending deliveries   {}", PendingDelivery::get_all(conn)?.len());
+            println!("due webhooks         {}", WebhookDelivery::count_due(conn)?);
+        },
+
+        Command::ReprocessDeliveries => {
+            let service = offline_service(cfg, &pool)?;
+            let count = service.reprocess_deliveries(&mut pool.get_conn()?)?;
+            println!("Delivered {} pending bottles.", count);
+        },
+
+        Command::Run | Command::WebOnly | Command::BotOnly => unreachable!("handled by main")
+    }
+
+    Ok(())
+}
@@ -1,14 +1,19 @@
 use model::*;
 
 use diesel::sql_function;
-use diesel::sql_types::{Text, Int8};
+use diesel::sql_types::{Text, Int8, BigInt, Bool};
 use diesel::prelude::*;
+use diesel::connection::SimpleConnection;
+use diesel::r2d2::{self as diesel_r2d2, ConnectionManager, CustomizeConnection};
+use r2d2::{Pool, PooledConnection};
 use schema::*;
 use diesel::*;
-use uuid::Uuid;
 
 type Res<A> = Result<A, result::Error>;
 
+/// Concurrent anonymous replies to a thread can pick the same number, the loser picks again
+const PSEUDONYM_ATTEMPTS: usize = 5;
+
 sql_function! {
     fn random() -> Text;
 }
@@ -18,127 +23,1009 @@ sql_function! {
     fn estimate_rows(tablename: Text) -> Int8;
 }
 
-table! {
-    user_rank (id) {
-        id -> Int8,
-        rank -> Int8,
+#[derive(QueryableByName)]
+struct GuildsResult {
+    #[sql_type="BigInt"] #[column_name="id"]
+    id: i64,
+    #[sql_type="BigInt"] #[column_name="bottle_channel"]
+    bottle_channel: i64
+}
+
+pub trait UserRepo {
+    fn get_user(&mut self, uid: UserId) -> User;
+    fn get_top_users(&mut self, offset: i64, limit: i64) -> Res<Vec<LeaderboardEntry>>;
+    fn update_user(&mut self, user: &User) -> Res<usize>;
+    fn get_last_bottles(&mut self, user: &User, limit: i64) -> Res<Vec<Bottle>>;
+    fn get_all_bottles(&mut self, user: &User) -> Res<Vec<Bottle>>;
+    fn get_newest_bottle(&mut self, user: &User) -> Res<Bottle>;
+    fn get_num_bottles(&mut self, user: &User) -> Res<i64>;
+    fn get_user_ranking(&mut self, uid: UserId) -> Res<i64>;
+    fn get_replies_to(&mut self, user: &User, limit: i64) -> Res<Vec<Bottle>>;
+    fn get_managed_guilds(&mut self, uid: UserId) -> Res<Vec<Guild>>;
+    fn can_manage(&mut self, uid: UserId, gid: GuildId) -> Res<bool>;
+    fn set_managed_guilds(&mut self, uid: UserId, guilds: &[GuildId]) -> Res<()>;
+    fn get_user_contributions(&mut self, uid: UserId, limit: i64) -> Res<Vec<GuildContribution>>;
+    fn erase_user(&mut self, uid: UserId) -> Res<()>;
+    fn is_erased(&mut self, uid: UserId) -> Res<bool>;
+    fn export_user(&mut self, user: &User) -> Res<UserExport>;
+
+    fn get_privacy(&mut self, uid: UserId) -> Privacy;
+    fn update_privacy(&mut self, privacy: &Privacy) -> Res<usize>;
+    fn make_xp_event(&mut self, event: &MakeXpEvent) -> Res<usize>;
+    fn get_xp_events(&mut self, uid: UserId) -> Res<Vec<XpEvent>>;
+    fn get_latest_xp_event(&mut self) -> Res<Option<i64>>;
+    fn refresh_leaderboards(&mut self) -> Res<()>;
+}
+
+/// Web sessions and api keys
+pub trait AccountRepo {
+    fn get_session(&mut self, id: &str) -> Res<Session>;
+    fn update_session(&mut self, session: &Session) -> Res<usize>;
+    fn revoke_session(&mut self, id: &str) -> Res<usize>;
+    fn clean_sessions(&mut self) -> Res<usize>;
+
+    fn make_api_key(&mut self, key: &ApiKey) -> Res<usize>;
+    fn get_api_key(&mut self, hash: &str) -> Res<ApiKey>;
+    fn get_api_keys(&mut self, uid: UserId) -> Res<Vec<ApiKey>>;
+    fn revoke_api_key(&mut self, id: &str, uid: UserId) -> Res<usize>;
+}
+
+pub trait BottleRepo {
+    fn make_bottle(&mut self, bottle: &MakeBottle) -> Res<Bottle>;
+    fn get_bottle(&mut self, id: BottleId) -> Res<Bottle>;
+    fn get_bottle_from_message(&mut self, mid: i64) -> Res<Bottle>;
+    fn get_last_bottle(&mut self, channel: i64) -> Res<Bottle>;
+    fn edit_bottle(&mut self, id: BottleId, change: MakeBottle) -> Res<usize>;
+    fn get_bottle_replies(&mut self, bottle: &Bottle) -> Res<Vec<Bottle>>;
+    fn count_replies(&mut self, id: BottleId) -> Res<i64>;
+    fn get_reply_to(&mut self, id: BottleId) -> Res<Option<BottleId>>;
+    fn del_bottle(&mut self, id: BottleId) -> Res<usize>;
+    fn scrub_user_bottles(&mut self, uid: UserId) -> Res<usize>;
+    fn set_pseudonym(&mut self, id: BottleId, number: i32) -> Res<usize>;
+    fn get_or_make_pseudonym(&mut self, thread: BottleId, uid: UserId) -> Res<Pseudonym>;
+
+    fn make_received(&mut self, received: &MakeReceivedBottle) -> Res<ReceivedBottle>;
+    fn get_received(&mut self, id: ReceivedBottleId) -> Res<ReceivedBottle>;
+    fn get_received_from_bottle(&mut self, bid: BottleId) -> Res<Vec<ReceivedBottle>>;
+    fn get_received_from_message(&mut self, mid: i64) -> Res<ReceivedBottle>;
+    fn get_received_from_user(&mut self, uid: UserId) -> Res<Vec<ReceivedBottle>>;
+    fn get_trail(&mut self, bid: BottleId) -> Res<Vec<(ReceivedBottle, Option<GuildId>)>>;
+    fn get_last_received(&mut self, channel: i64) -> Res<Bottle>;
+    fn del_received(&mut self, id: ReceivedBottleId) -> Res<usize>;
+
+    fn make_pending_delivery(&mut self, pending: &PendingDelivery) -> Res<usize>;
+    fn get_pending_deliveries(&mut self) -> Res<Vec<Bottle>>;
+    fn del_pending_delivery(&mut self, bid: BottleId) -> Res<usize>;
+}
+
+pub trait GuildRepo {
+    fn get_guild(&mut self, gid: GuildId) -> Guild;
+    fn get_top_guilds(&mut self, offset: i64, limit: i64) -> Res<Vec<LeaderboardEntry>>;
+    fn update_guild(&mut self, guild: &Guild) -> Res<usize>;
+    fn get_guild_contributions(&mut self, gid: GuildId, limit: i64) -> Res<Vec<GuildContribution>>;
+    fn get_guild_xp(&mut self, gid: GuildId) -> Res<i64>;
+    fn get_guild_ranking(&mut self, gid: GuildId) -> Res<i64>;
+    fn get_channel_received(&mut self, channel: i64) -> Res<i64>;
+    fn del_guild(&mut self, gid: GuildId) -> Res<usize>;
+    fn erase_guild(&mut self, gid: GuildId) -> Res<()>;
+
+    fn get_contribution(&mut self, id: GuildContributionId) -> GuildContribution;
+    fn update_contribution(&mut self, contribution: &GuildContribution) -> Res<GuildContribution>;
+}
+
+/// Reports and bans
+pub trait ModerationRepo {
+    fn make_report(&mut self, report: &Report) -> Res<Report>;
+    fn report_exists(&mut self, bid: BottleId) -> Res<bool>;
+    fn get_report(&mut self, rid: ReceivedBottleId, uid: UserId) -> Res<Report>;
+    fn del_report(&mut self, bid: BottleId) -> Res<usize>;
+
+    fn get_banned(&mut self, uid: UserId) -> Res<bool>;
+    fn make_ban(&mut self, ban: &Ban) -> Res<Ban>;
+    fn del_ban(&mut self, uid: UserId) -> Res<usize>;
+}
+
+/// Webhooks and the ids of bridged platforms
+pub trait IntegrationRepo {
+    fn make_webhook(&mut self, webhook: &MakeWebhook) -> Res<Webhook>;
+    fn get_webhooks_for(&mut self, guild: Option<GuildId>) -> Res<Vec<Webhook>>;
+    fn get_webhooks_from(&mut self, guild: Option<GuildId>) -> Res<Vec<Webhook>>;
+    fn del_webhook(&mut self, id: WebhookId, guild: Option<GuildId>) -> Res<usize>;
+    fn make_delivery(&mut self, delivery: &MakeWebhookDelivery) -> Res<usize>;
+    fn get_due_deliveries(&mut self, limit: i64) -> Res<Vec<(WebhookDelivery, Webhook)>>;
+    fn record_delivery(&mut self, delivery: &WebhookDelivery, next_attempt: Option<DTime>, status: Option<i32>, error: Option<String>) -> Res<usize>;
+    fn count_due_deliveries(&mut self) -> Res<i64>;
+
+    fn get_or_make_platform_id(&mut self, mapped: &MakePlatformId) -> Res<PlatformId>;
+    fn get_platform_id(&mut self, id: i64) -> Res<PlatformId>;
+    fn get_platform_ids(&mut self) -> Res<Vec<PlatformId>>;
+
+    fn update_profile(&mut self, profile: &DiscordProfile) -> Res<usize>;
+    fn get_profile(&mut self, id: i64) -> Res<DiscordProfile>;
+    fn get_stale_profiles(&mut self, before: DTime, limit: i64) -> Res<Vec<DiscordProfile>>;
+}
+
+/// The few queries each database needs written its own way
+pub trait Dialect {
+    fn estimate_rows(&mut self, table: &'static str) -> Res<i64>;
+
+    /// Bridged platforms without ids of their own take negative ones from here
+    fn next_platform_id(&mut self) -> Res<i64>;
+
+    /// Guilds with a bottle channel other than ``from``, least recently delivered to first
+    fn get_delivery_channels(&mut self, from: i64, reply: bool, offset: i64, limit: i64) -> Res<Vec<(GuildId, i64)>>;
+
+    /// Public bottles matching ``search``, newest first
+    fn search_bottles(&mut self, search: &BottleSearch, offset: i64, limit: i64) -> Res<Vec<Bottle>>;
+}
+
+/// The archive filters both databases share, leaving the text search to each ``Dialect``
+macro_rules! archive_query {
+    ($search:expr) => {{
+        let search: &BottleSearch = $search;
+        let hidden = privacy::table.filter(privacy::user.eq(bottle::user))
+            .filter(privacy::hide_bottles.or(privacy::hide_profile));
+
+        let mut query = bottle::table.filter(bottle::anonymous.eq(false)).filter(bottle::deleted.eq(false)).filter(bottle::guild.is_not_null())
+            .filter(dsl::not(dsl::exists(hidden))).into_boxed();
+
+        if let Some(gid) = search.guild {
+            query = query.filter(bottle::guild.eq(gid));
+        }
+
+        if let Some(uid) = search.user {
+            query = query.filter(bottle::user.eq(uid));
+        }
+
+        if let Some(after) = search.after {
+            query = query.filter(bottle::time_pushed.ge(after));
+        }
+
+        if let Some(before) = search.before {
+            query = query.filter(bottle::time_pushed.lt(before));
+        }
+
+        if search.has_image {
+            query = query.filter(bottle::image.is_not_null());
+        }
+
+        if search.has_replies {
+            query = query.filter(dsl::sql::<Bool>("EXISTS (SELECT 1 FROM bottle AS replies WHERE replies.reply_to = bottle.id AND NOT replies.deleted)"));
+        }
+
+        query
+    }};
+}
+
+pub trait Repo: UserRepo + AccountRepo + BottleRepo + GuildRepo + ModerationRepo + IntegrationRepo + Dialect {}
+impl<T: UserRepo + AccountRepo + BottleRepo + GuildRepo + ModerationRepo + IntegrationRepo + Dialect> Repo for T {}
+
+type PgConn = PooledConnection<ConnectionManager<PgConnection>>;
+type SqliteConn = PooledConnection<ConnectionManager<SqliteConnection>>;
+
+/// Everything both databases can run as is, diesel takes care of the differences
+macro_rules! impl_repo {
+    ($conn:ty) => {
+        impl UserRepo for $conn {
+            fn get_user(&mut self, uid: UserId) -> User {
+                user::table.find(uid).first(self).unwrap_or_else(|_| User::new(uid))
+            }
+
+            /// Hidden users aren't ranked at all, this only drops those who hid since the last refresh
+            fn get_top_users(&mut self, offset: i64, limit: i64) -> Res<Vec<LeaderboardEntry>> {
+                let hidden = privacy::table.filter(privacy::user.eq(user_leaderboard::id)).filter(privacy::hide_leaderboard);
+
+                user_leaderboard::table.filter(dsl::not(dsl::exists(hidden))).order_by(user_leaderboard::rank)
+                    .offset(offset).limit(limit).load(self)
+            }
+
+            fn update_user(&mut self, user: &User) -> Res<usize> {
+                insert_into(user::table).values(user).on_conflict(user::id).do_update().set(user).execute(self)
+            }
+
+            fn get_last_bottles(&mut self, user: &User, limit: i64) -> Res<Vec<Bottle>> {
+                Bottle::belonging_to(user).filter(bottle::guild.is_not_null()).filter(bottle::anonymous.eq(false)).filter(bottle::reply_to.is_null()).filter(bottle::deleted.eq(false)).order(bottle::time_pushed.desc()).limit(limit).load(self)
+            }
+
+            fn get_all_bottles(&mut self, user: &User) -> Res<Vec<Bottle>> {
+                Bottle::belonging_to(user).load(self)
+            }
+
+            fn get_newest_bottle(&mut self, user: &User) -> Res<Bottle> {
+                Bottle::belonging_to(user).order(bottle::time_pushed.desc()).limit(1).first(self)
+            }
+
+            fn get_num_bottles(&mut self, user: &User) -> Res<i64> {
+                Bottle::belonging_to(user).select(dsl::count_star()).first(self)
+            }
+
+            fn get_user_ranking(&mut self, uid: UserId) -> Res<i64> {
+                user_leaderboard::table.find(uid).select(user_leaderboard::rank).first(self)
+            }
+
+            fn get_replies_to(&mut self, user: &User, limit: i64) -> Res<Vec<Bottle>> {
+                let own: Vec<Option<BottleId>> = Bottle::belonging_to(user).select(bottle::id.nullable()).load(self)?;
+
+                bottle::table.filter(bottle::reply_to.eq_any(own)).filter(bottle::user.ne(user.id)).filter(bottle::deleted.eq(false))
+                    .order(bottle::time_pushed.desc()).limit(limit).load(self)
+            }
+
+            fn get_managed_guilds(&mut self, uid: UserId) -> Res<Vec<Guild>> {
+                managed_guild::table.inner_join(guild::table).filter(managed_guild::user.eq(uid))
+                    .select(guild::all_columns).load(self)
+            }
+
+            fn can_manage(&mut self, uid: UserId, gid: GuildId) -> Res<bool> {
+                select(dsl::exists(managed_guild::table.find((uid, gid)))).get_result(self)
+            }
+
+            fn set_managed_guilds(&mut self, uid: UserId, guilds: &[GuildId]) -> Res<()> {
+                self.transaction(|conn| {
+                    delete(managed_guild::table.filter(managed_guild::user.eq(uid))).execute(conn)?;
+
+                    let rows: Vec<ManagedGuild> = guilds.iter().map(|&guild| ManagedGuild {user: uid, guild}).collect();
+                    insert_into(managed_guild::table).values(&rows).execute(conn)?;
+                    Ok(())
+                })
+            }
+
+            fn get_user_contributions(&mut self, uid: UserId, limit: i64) -> Res<Vec<GuildContribution>> {
+                guild_contribution::table.filter(guild_contribution::user.eq(uid)).order(guild_contribution::xp.desc()).limit(limit).load(self)
+            }
+
+            fn erase_user(&mut self, uid: UserId) -> Res<()> {
+                self.transaction(|conn| {
+                    conn.scrub_user_bottles(uid)?;
+
+                    delete(guild_contribution::table.filter(guild_contribution::user.eq(uid))).execute(conn)?;
+                    delete(pseudonym::table.filter(pseudonym::user.eq(uid))).execute(conn)?;
+                    delete(privacy::table.find(uid)).execute(conn)?;
+                    delete(managed_guild::table.filter(managed_guild::user.eq(uid))).execute(conn)?;
+                    delete(session::table.filter(session::user.eq(uid))).execute(conn)?;
+                    delete(api_key::table.filter(api_key::user.eq(uid))).execute(conn)?;
+                    delete(xp_event::table.filter(xp_event::user.eq(uid))).execute(conn)?;
+                    delete(report::table.filter(report::user.eq(uid))).execute(conn)?;
+                    delete(discord_profile::table.find(uid)).execute(conn)?;
+
+                    conn.update_user(&User::new(uid))?;
+                    insert_into(erased_user::table).values((erased_user::id.eq(uid), erased_user::erased.eq(now())))
+                        .on_conflict(erased_user::id).do_update().set(erased_user::erased.eq(now())).execute(conn)?;
+                    Ok(())
+                })
+            }
+
+            fn is_erased(&mut self, uid: UserId) -> Res<bool> {
+                select(dsl::exists(erased_user::table.find(uid))).get_result(self)
+            }
+
+            fn export_user(&mut self, user: &User) -> Res<UserExport> {
+                Ok(UserExport {
+                    user: user.clone(),
+                    privacy: self.get_privacy(user.id),
+                    bottles: self.get_all_bottles(user)?,
+                    received_copies: self.get_received_from_user(user.id)?,
+                    pseudonyms: pseudonym::table.filter(pseudonym::user.eq(user.id)).load(self)?,
+                    reports: report::table.filter(report::user.eq(user.id)).load(self)?,
+                    ban: ban::table.find(user.id).first(self).optional()?,
+                    xp_history: self.get_xp_events(user.id)?,
+                    contributions: guild_contribution::table.filter(guild_contribution::user.eq(user.id)).load(self)?,
+                    exported: now()
+                })
+            }
+
+            fn get_privacy(&mut self, uid: UserId) -> Privacy {
+                privacy::table.find(uid).first(self).unwrap_or_else(|_| Privacy::new(uid))
+            }
+
+            fn update_privacy(&mut self, privacy: &Privacy) -> Res<usize> {
+                insert_into(privacy::table).values(privacy).on_conflict(privacy::user).do_update().set(privacy).execute(self)
+            }
+
+            fn make_xp_event(&mut self, event: &MakeXpEvent) -> Res<usize> {
+                insert_into(xp_event::table).values(event).execute(self)
+            }
+
+            fn get_xp_events(&mut self, uid: UserId) -> Res<Vec<XpEvent>> {
+                xp_event::table.filter(xp_event::user.eq(uid)).order(xp_event::time.asc()).load(self)
+            }
+
+            fn get_latest_xp_event(&mut self) -> Res<Option<i64>> {
+                xp_event::table.select(dsl::max(xp_event::id)).first(self)
+            }
+
+            /// Window functions are portable enough, so both databases rebuild the tables the same way
+            fn refresh_leaderboards(&mut self) -> Res<()> {
+                self.transaction(|conn| {
+                    delete(user_leaderboard::table).execute(conn)?;
+                    sql_query("INSERT INTO user_leaderboard (\"id\", \"rank\", xp)
+                        SELECT \"id\", ROW_NUMBER() OVER (ORDER BY xp DESC, \"id\"), xp FROM \"user\"
+                        WHERE NOT EXISTS (SELECT 1 FROM privacy WHERE privacy.\"user\" = \"user\".\"id\" AND privacy.hide_leaderboard)").execute(conn)?;
+
+                    delete(guild_leaderboard::table).execute(conn)?;
+                    sql_query("INSERT INTO guild_leaderboard (\"id\", \"rank\", xp)
+                        SELECT guild, ROW_NUMBER() OVER (ORDER BY SUM(xp) DESC, guild), SUM(xp) FROM guild_contribution GROUP BY guild").execute(conn)?;
+
+                    Ok(())
+                })
+            }
+        }
+
+        impl AccountRepo for $conn {
+            fn get_session(&mut self, id: &str) -> Res<Session> {
+                session::table.find(id).filter(session::revoked.eq(false)).filter(session::expires.gt(now())).first(self)
+            }
+
+            fn update_session(&mut self, session: &Session) -> Res<usize> {
+                insert_into(session::table).values(session).on_conflict(session::id).do_update().set(session).execute(self)
+            }
+
+            fn revoke_session(&mut self, id: &str) -> Res<usize> {
+                update(session::table.find(id)).set(session::revoked.eq(true)).execute(self)
+            }
+
+            fn clean_sessions(&mut self) -> Res<usize> {
+                delete(session::table.filter(session::revoked.eq(true).or(session::expires.le(now())))).execute(self)
+            }
+
+            fn make_api_key(&mut self, key: &ApiKey) -> Res<usize> {
+                insert_into(api_key::table).values(key).execute(self)
+            }
+
+            fn get_api_key(&mut self, hash: &str) -> Res<ApiKey> {
+                api_key::table.find(hash).filter(api_key::revoked.eq(false)).first(self)
+            }
+
+            fn get_api_keys(&mut self, uid: UserId) -> Res<Vec<ApiKey>> {
+                api_key::table.filter(api_key::user.eq(uid)).filter(api_key::revoked.eq(false)).order(api_key::created.asc()).load(self)
+            }
+
+            fn revoke_api_key(&mut self, id: &str, uid: UserId) -> Res<usize> {
+                update(api_key::table.find(id)).filter(api_key::user.eq(uid)).set(api_key::revoked.eq(true)).execute(self)
+            }
+        }
+
+        impl BottleRepo for $conn {
+            fn make_bottle(&mut self, bottle: &MakeBottle) -> Res<Bottle> {
+                insert_into(bottle::table).values(bottle).get_result(self)
+            }
+
+            fn get_bottle(&mut self, id: BottleId) -> Res<Bottle> {
+                bottle::table.find(id).get_result(self)
+            }
+
+            fn get_bottle_from_message(&mut self, mid: i64) -> Res<Bottle> {
+                bottle::table.filter(bottle::message.eq(mid)).first(self)
+            }
+
+            fn get_last_bottle(&mut self, channel: i64) -> Res<Bottle> {
+                bottle::table.left_join(received_bottle::table)
+                    .filter(bottle::channel.eq(channel).or(received_bottle::channel.eq(channel)))
+                    .order((bottle::time_pushed.desc(), received_bottle::time_recieved.desc()))
+                    .select(bottle::all_columns).first(self)
+            }
+
+            fn edit_bottle(&mut self, id: BottleId, change: MakeBottle) -> Res<usize> {
+                update(bottle::table.filter(bottle::id.eq(id))).set(change).execute(self)
+            }
+
+            fn get_bottle_replies(&mut self, bottle: &Bottle) -> Res<Vec<Bottle>> {
+                Bottle::belonging_to(bottle).order(bottle::time_pushed.asc()).load(self)
+            }
+
+            fn count_replies(&mut self, id: BottleId) -> Res<i64> {
+                bottle::table.filter(bottle::reply_to.eq(id)).select(dsl::count_star()).first(self)
+            }
+
+            fn get_reply_to(&mut self, id: BottleId) -> Res<Option<BottleId>> {
+                bottle::table.find(id).select(bottle::reply_to).first(self)
+            }
+
+            fn del_bottle(&mut self, id: BottleId) -> Res<usize> {
+                update(bottle::table).filter(bottle::id.eq(id)).set(bottle::deleted.eq(true)).execute(self)
+            }
+
+            fn scrub_user_bottles(&mut self, uid: UserId) -> Res<usize> {
+                update(bottle::table).filter(bottle::user.eq(uid))
+                    .set((bottle::contents.eq(""), bottle::url.eq(None::<String>), bottle::image.eq(None::<String>),
+                          bottle::deleted.eq(true), bottle::anonymous.eq(true), bottle::pseudonym.eq(None::<i32>)))
+                    .execute(self)
+            }
+
+            fn set_pseudonym(&mut self, id: BottleId, number: i32) -> Res<usize> {
+                update(bottle::table).filter(bottle::id.eq(id)).set(bottle::pseudonym.eq(number)).execute(self)
+            }
+
+            fn get_or_make_pseudonym(&mut self, thread: BottleId, uid: UserId) -> Res<Pseudonym> {
+                let mut attempts = 1;
+                loop {
+                    let res = self.transaction(|conn| {
+                        if let Some(x) = pseudonym::table.find((thread, uid)).first(conn).optional()? {
+                            return Ok(x);
+                        }
+
+                        let last: Option<i32> = pseudonym::table.filter(pseudonym::thread.eq(thread))
+                            .select(dsl::max(pseudonym::number)).first(conn)?;
+
+                        //only a race on the same user is settled here, a taken number is a UNIQUE violation
+                        let pseudonym = Pseudonym {thread, user: uid, number: last.unwrap_or(0) + 1};
+                        insert_into(pseudonym::table).values(&pseudonym)
+                            .on_conflict((pseudonym::thread, pseudonym::user)).do_nothing().execute(conn)?;
+
+                        pseudonym::table.find((thread, uid)).first(conn)
+                    });
+
+                    match res {
+                        Err(result::Error::DatabaseError(result::DatabaseErrorKind::UniqueViolation, _)) if attempts < PSEUDONYM_ATTEMPTS => attempts += 1,
+                        res => return res
+                    }
+                }
+            }
+
+            fn make_received(&mut self, received: &MakeReceivedBottle) -> Res<ReceivedBottle> {
+                insert_into(received_bottle::table).values(received).get_result(self)
+            }
+
+            fn get_received(&mut self, id: ReceivedBottleId) -> Res<ReceivedBottle> {
+                received_bottle::table.find(id).get_result(self)
+            }
+
+            fn get_received_from_bottle(&mut self, bid: BottleId) -> Res<Vec<ReceivedBottle>> {
+                received_bottle::table.filter(received_bottle::bottle.eq(bid)).load(self)
+            }
+
+            fn get_received_from_message(&mut self, mid: i64) -> Res<ReceivedBottle> {
+                received_bottle::table.filter(received_bottle::message.eq(mid)).get_result(self)
+            }
+
+            fn get_received_from_user(&mut self, uid: UserId) -> Res<Vec<ReceivedBottle>> {
+                received_bottle::table.inner_join(bottle::table).filter(bottle::user.eq(uid))
+                    .select(received_bottle::all_columns).load(self)
+            }
+
+            fn get_trail(&mut self, bid: BottleId) -> Res<Vec<(ReceivedBottle, Option<GuildId>)>> {
+                received_bottle::table.left_join(guild::table.on(guild::bottle_channel.eq(received_bottle::channel.nullable())))
+                    .filter(received_bottle::bottle.eq(bid)).order(received_bottle::time_recieved.asc())
+                    .select((received_bottle::all_columns, guild::id.nullable())).load(self)
+            }
+
+            fn get_last_received(&mut self, channel: i64) -> Res<Bottle> {
+                received_bottle::table.inner_join(bottle::table)
+                    .filter(received_bottle::channel.eq(channel))
+                    .order(received_bottle::time_recieved.desc())
+                    .select(bottle::all_columns).first(self)
+            }
+
+            fn del_received(&mut self, id: ReceivedBottleId) -> Res<usize> {
+                delete(received_bottle::table.find(id)).execute(self)
+            }
+
+            fn make_pending_delivery(&mut self, pending: &PendingDelivery) -> Res<usize> {
+                insert_into(pending_delivery::table).values(pending).on_conflict_do_nothing().execute(self)
+            }
+
+            fn get_pending_deliveries(&mut self) -> Res<Vec<Bottle>> {
+                pending_delivery::table.inner_join(bottle::table)
+                    .order(pending_delivery::queued.asc())
+                    .select(bottle::all_columns).load(self)
+            }
+
+            fn del_pending_delivery(&mut self, bid: BottleId) -> Res<usize> {
+                delete(pending_delivery::table.find(bid)).execute(self)
+            }
+        }
+
+        impl GuildRepo for $conn {
+            fn get_guild(&mut self, gid: GuildId) -> Guild {
+                guild::table.find(gid).first(self).unwrap_or_else(|_| Guild::new(gid))
+            }
+
+            fn get_top_guilds(&mut self, offset: i64, limit: i64) -> Res<Vec<LeaderboardEntry>> {
+                guild_leaderboard::table.order_by(guild_leaderboard::rank).offset(offset).limit(limit).load(self)
+            }
+
+            fn update_guild(&mut self, guild: &Guild) -> Res<usize> {
+                insert_into(guild::table).values(guild).on_conflict(guild::id).do_update().set(guild).execute(self)
+            }
+
+            fn get_guild_contributions(&mut self, gid: GuildId, limit: i64) -> Res<Vec<GuildContribution>> {
+                let hidden = privacy::table.filter(privacy::user.eq(guild_contribution::user))
+                    .filter(privacy::hide_leaderboard.or(privacy::hide_contributions));
+
+                guild_contribution::table.filter(guild_contribution::guild.eq(gid)).filter(dsl::not(dsl::exists(hidden)))
+                    .order(guild_contribution::xp.desc()).limit(limit).load(self)
+            }
+
+            fn get_guild_xp(&mut self, gid: GuildId) -> Res<i64> {
+                let x: Option<i64> =
+                    guild_contribution::table.filter(guild_contribution::guild.eq(gid)).select(dsl::sum(guild_contribution::xp)).first(self)?;
+
+                Ok(x.unwrap_or(0))
+            }
+
+            fn get_guild_ranking(&mut self, gid: GuildId) -> Res<i64> {
+                guild_leaderboard::table.find(gid).select(guild_leaderboard::rank).first(self)
+            }
+
+            fn get_channel_received(&mut self, channel: i64) -> Res<i64> {
+                received_bottle::table.filter(received_bottle::channel.eq(channel)).select(dsl::count_star()).first(self)
+            }
+
+            fn del_guild(&mut self, gid: GuildId) -> Res<usize> {
+                delete(guild::table).filter(guild::id.eq(gid)).execute(self)
+            }
+
+            fn erase_guild(&mut self, gid: GuildId) -> Res<()> {
+                self.transaction(|conn| {
+                    let guild = conn.get_guild(gid);
+                    //copies from before received_bottle.guild existed are only known by the current channels
+                    let channels: Vec<i64> = guild.bottle_channel.into_iter().chain(guild.admin_channel).collect();
+                    let received: Vec<ReceivedBottleId> = received_bottle::table
+                        .filter(received_bottle::guild.eq(gid).or(received_bottle::channel.eq_any(&channels)))
+                        .select(received_bottle::id).load(conn)?;
+
+                    update(report::table.filter(report::received_bottle.eq_any(&received))).set(report::received_bottle.eq(None::<i64>)).execute(conn)?;
+                    delete(received_bottle::table.filter(received_bottle::id.eq_any(&received))).execute(conn)?;
+
+                    update(bottle::table).filter(bottle::guild.eq(gid))
+                        .set((bottle::contents.eq(""), bottle::url.eq(None::<String>), bottle::image.eq(None::<String>),
+                              bottle::deleted.eq(true), bottle::guild.eq(None::<i64>)))
+                        .execute(conn)?;
+                    update(xp_event::table.filter(xp_event::guild.eq(gid))).set(xp_event::guild.eq(None::<i64>)).execute(conn)?;
+
+                    conn.del_guild(gid)?;
+                    Ok(())
+                })
+            }
+
+            fn get_contribution(&mut self, id: GuildContributionId) -> GuildContribution {
+                guild_contribution::table.find(id).first(self).unwrap_or_else(|_| GuildContribution {guild: id.0, user: id.1, xp: 0})
+            }
+
+            fn update_contribution(&mut self, contribution: &GuildContribution) -> Res<GuildContribution> {
+                insert_into(guild_contribution::table).values(contribution)
+                    .on_conflict((guild_contribution::guild, guild_contribution::user)).do_update().set(contribution).get_result(self)
+            }
+        }
+
+        impl ModerationRepo for $conn {
+            fn make_report(&mut self, report: &Report) -> Res<Report> {
+                insert_into(report::table).values(report).on_conflict(report::bottle).do_update().set(report).get_result(self)
+            }
+
+            fn report_exists(&mut self, bid: BottleId) -> Res<bool> {
+                select(dsl::exists(report::table.find(bid))).first(self)
+            }
+
+            fn get_report(&mut self, rid: ReceivedBottleId, uid: UserId) -> Res<Report> {
+                report::table.filter(report::received_bottle.eq(rid)).filter(report::user.eq(uid)).first(self)
+            }
+
+            fn del_report(&mut self, bid: BottleId) -> Res<usize> {
+                delete(report::table.find(bid)).execute(self)
+            }
+
+            fn get_banned(&mut self, uid: UserId) -> Res<bool> {
+                select(dsl::exists(ban::table.find(uid))).get_result(self)
+            }
+
+            fn make_ban(&mut self, ban: &Ban) -> Res<Ban> {
+                insert_into(ban::table).values(ban).get_result(self)
+            }
+
+            fn del_ban(&mut self, uid: UserId) -> Res<usize> {
+                delete(ban::table.find(uid)).execute(self)
+            }
+        }
+
+        impl IntegrationRepo for $conn {
+            fn make_webhook(&mut self, webhook: &MakeWebhook) -> Res<Webhook> {
+                insert_into(webhook::table).values(webhook).get_result(self)
+            }
+
+            fn get_webhooks_for(&mut self, guild: Option<GuildId>) -> Res<Vec<Webhook>> {
+                match guild {
+                    Some(gid) => webhook::table.filter(webhook::guild.eq(gid).or(webhook::guild.is_null())).load(self),
+                    None => webhook::table.filter(webhook::guild.is_null()).load(self)
+                }
+            }
+
+            fn get_webhooks_from(&mut self, guild: Option<GuildId>) -> Res<Vec<Webhook>> {
+                match guild {
+                    Some(gid) => webhook::table.filter(webhook::guild.eq(gid)).order(webhook::id).load(self),
+                    None => webhook::table.filter(webhook::guild.is_null()).order(webhook::id).load(self)
+                }
+            }
+
+            fn del_webhook(&mut self, id: WebhookId, guild: Option<GuildId>) -> Res<usize> {
+                match guild {
+                    Some(gid) => delete(webhook::table.find(id).filter(webhook::guild.eq(gid))).execute(self),
+                    None => delete(webhook::table.find(id).filter(webhook::guild.is_null())).execute(self)
+                }
+            }
+
+            fn make_delivery(&mut self, delivery: &MakeWebhookDelivery) -> Res<usize> {
+                insert_into(webhook_delivery::table).values(delivery).execute(self)
+            }
+
+            fn get_due_deliveries(&mut self, limit: i64) -> Res<Vec<(WebhookDelivery, Webhook)>> {
+                webhook_delivery::table.inner_join(webhook::table)
+                    .filter(webhook_delivery::next_attempt.le(now()))
+                    .order(webhook_delivery::next_attempt.asc()).limit(limit).load(self)
+            }
+
+            fn record_delivery(&mut self, delivery: &WebhookDelivery, next_attempt: Option<DTime>, status: Option<i32>, error: Option<String>) -> Res<usize> {
+                update(webhook_delivery::table.find(delivery.id))
+                    .set((webhook_delivery::attempts.eq(delivery.attempts + 1), webhook_delivery::next_attempt.eq(next_attempt),
+                          webhook_delivery::status.eq(status), webhook_delivery::error.eq(error)))
+                    .execute(self)
+            }
+
+            fn count_due_deliveries(&mut self) -> Res<i64> {
+                webhook_delivery::table.filter(webhook_delivery::next_attempt.le(now())).count().get_result(self)
+            }
+
+            /// Ids are taken from ``next_platform_id`` rather than a column default, which SQLite can't do
+            fn get_or_make_platform_id(&mut self, mapped: &MakePlatformId) -> Res<PlatformId> {
+                self.transaction(|conn| {
+                    let existing: Option<PlatformId> = platform_id::table
+                        .filter(platform_id::platform.eq(mapped.platform)).filter(platform_id::external.eq(mapped.external))
+                        .first(conn).optional()?;
+
+                    match existing {
+                        Some(x) if x.name == mapped.name => Ok(x),
+                        Some(x) => update(platform_id::table.find(x.id)).set(platform_id::name.eq(mapped.name)).get_result(conn),
+                        None => {
+                            let id = conn.next_platform_id()?;
+                            insert_into(platform_id::table).values((platform_id::id.eq(id), mapped)).get_result(conn)
+                        }
+                    }
+                })
+            }
+
+            fn get_platform_id(&mut self, id: i64) -> Res<PlatformId> {
+                platform_id::table.find(id).get_result(self)
+            }
+
+            fn get_platform_ids(&mut self) -> Res<Vec<PlatformId>> {
+                platform_id::table.load(self)
+            }
+
+            fn update_profile(&mut self, profile: &DiscordProfile) -> Res<usize> {
+                insert_into(discord_profile::table).values(profile)
+                    .on_conflict(discord_profile::id).do_update().set(profile).execute(self)
+            }
+
+            fn get_profile(&mut self, id: i64) -> Res<DiscordProfile> {
+                discord_profile::table.find(id).get_result(self)
+            }
+
+            fn get_stale_profiles(&mut self, before: DTime, limit: i64) -> Res<Vec<DiscordProfile>> {
+                discord_profile::table.filter(discord_profile::refreshed.lt(before))
+                    .order(discord_profile::refreshed.asc()).limit(limit).load(self)
+            }
+        }
+    };
+}
+
+impl_repo!(PgConn);
+impl_repo!(SqliteConn);
+
+impl Dialect for PgConn {
+    fn estimate_rows(&mut self, table: &'static str) -> Res<i64> {
+        select(estimate_rows(table.to_owned())).get_result(self)
+    }
+
+    fn next_platform_id(&mut self) -> Res<i64> {
+        select(dsl::sql::<Int8>("nextval('platform_id_seq')")).get_result(self)
+    }
+
+    fn get_delivery_channels(&mut self, from: i64, reply: bool, offset: i64, limit: i64) -> Res<Vec<(GuildId, i64)>> {
+        let guilds: Vec<GuildsResult> = sql_query(
+            "SELECT \"id\", bottle_channel FROM (SELECT DISTINCT ON (guild.id) guild.id, bottle_channel, receive_bottles, receive_replies, time_recieved FROM guild LEFT JOIN received_bottle ON (bottle_channel = received_bottle.channel) ORDER BY guild.id, received_bottle.time_recieved DESC) channels
+            WHERE bottle_channel IS NOT NULL AND bottle_channel != $1 AND (CASE WHEN $3 THEN receive_replies ELSE receive_bottles END)
+            ORDER BY time_recieved ASC NULLS FIRST, \"id\" LIMIT $2 OFFSET $4")
+            .bind::<BigInt, _>(from).bind::<BigInt, _>(limit).bind::<Bool, _>(reply).bind::<BigInt, _>(offset).load(self)?;
+
+        Ok(guilds.into_iter().map(|GuildsResult {id, bottle_channel}| (id, bottle_channel)).collect())
+    }
+
+    fn search_bottles(&mut self, search: &BottleSearch, offset: i64, limit: i64) -> Res<Vec<Bottle>> {
+        let mut query = archive_query!(search);
+        if let Some(text) = &search.text {
+            query = query.filter(dsl::sql::<Bool>("to_tsvector('english', contents) @@ websearch_to_tsquery('english', ")
+                .bind::<Text, _>(text.clone()).sql(")"));
+        }
+
+        query.order(bottle::time_pushed.desc()).offset(offset).limit(limit).load(self)
+    }
+}
+
+impl Dialect for SqliteConn {
+    /// SQLite keeps no statistics to estimate from, so this just counts
+    fn estimate_rows(&mut self, table: &'static str) -> Res<i64> {
+        select(dsl::sql::<Int8>(&format!("(SELECT COUNT(*) FROM \"{}\")", table))).get_result(self)
+    }
+
+    fn next_platform_id(&mut self) -> Res<i64> {
+        self.transaction(|conn| {
+            sql_query("INSERT INTO platform_seq DEFAULT VALUES").execute(conn)?;
+            select(dsl::sql::<Int8>("-last_insert_rowid()")).get_result(conn)
+        })
+    }
+
+    fn get_delivery_channels(&mut self, from: i64, reply: bool, offset: i64, limit: i64) -> Res<Vec<(GuildId, i64)>> {
+        let guilds: Vec<GuildsResult> = sql_query(
+            "SELECT guild.id AS \"id\", bottle_channel FROM guild LEFT JOIN received_bottle ON (bottle_channel = received_bottle.channel)
+            WHERE bottle_channel IS NOT NULL AND bottle_channel != ? AND (CASE WHEN ? THEN receive_replies ELSE receive_bottles END)
+            GROUP BY guild.id ORDER BY MAX(received_bottle.time_recieved) ASC NULLS FIRST, guild.id LIMIT ? OFFSET ?")
+            .bind::<BigInt, _>(from).bind::<Bool, _>(reply).bind::<BigInt, _>(limit).bind::<BigInt, _>(offset).load(self)?;
+
+        Ok(guilds.into_iter().map(|GuildsResult {id, bottle_channel}| (id, bottle_channel)).collect())
+    }
+
+    /// No full text search here, just a case insensitive substring match
+    fn search_bottles(&mut self, search: &BottleSearch, offset: i64, limit: i64) -> Res<Vec<Bottle>> {
+        let mut query = archive_query!(search);
+        if let Some(text) = &search.text {
+            let escaped = text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+            query = query.filter(dsl::sql::<Bool>("contents LIKE '%' || ")
+                .bind::<Text, _>(escaped).sql(" || '%' ESCAPE '\\'"));
+        }
+
+        query.order(bottle::time_pushed.desc()).offset(offset).limit(limit).load(self)
     }
 }
 
-table! {
-    guild_rank (id) {
-        id -> Int8,
-        rank -> Int8,
+#[derive(Debug)]
+struct SqlitePragmas;
+
+/// SQLite leaves foreign keys off per connection, and the bot writes from several threads
+impl CustomizeConnection<SqliteConnection, diesel_r2d2::Error> for SqlitePragmas {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel_r2d2::Error> {
+        conn.batch_execute("PRAGMA foreign_keys = ON; PRAGMA busy_timeout = 5000; PRAGMA journal_mode = WAL;")
+            .map_err(diesel_r2d2::Error::QueryError)
     }
 }
 
-allow_tables_to_appear_in_same_query!(guild, guild_rank);
-joinable!(guild_rank -> guild (id));
+/// ``sqlite:`` urls and paths to a database file use SQLite, anything else is taken to be Postgres
+fn sqlite_path(url: &str) -> Option<&str> {
+    if let Some(path) = url.strip_prefix("sqlite://").or_else(|| url.strip_prefix("sqlite:")) {
+        Some(path)
+    } else if url.ends_with(".db") || url.ends_with(".sqlite") {
+        Some(url)
+    } else {
+        None
+    }
+}
+
+const PG_MIGRATIONS: diesel_migrations::EmbeddedMigrations = diesel_migrations::embed_migrations!("migrations/");
+const SQLITE_MIGRATIONS: diesel_migrations::EmbeddedMigrations = diesel_migrations::embed_migrations!("migrations_sqlite/");
+
+impl ConnPool {
+    pub fn connect(url: &str) -> Result<ConnPool, r2d2::Error> {
+        Ok(match sqlite_path(url) {
+            Some(path) => ConnPool::Sqlite(Pool::builder().connection_timeout(POOL_TIMEOUT).connection_customizer(Box::new(SqlitePragmas))
+                .build(ConnectionManager::<SqliteConnection>::new(path))?),
+            None => ConnPool::Postgres(Pool::builder().connection_timeout(POOL_TIMEOUT).build(ConnectionManager::<PgConnection>::new(url))?)
+        })
+    }
+
+    pub fn get(&self) -> Result<Conn, r2d2::Error> {
+        Ok(match self {
+            ConnPool::Postgres(pool) => Box::new(pool.get()?),
+            ConnPool::Sqlite(pool) => Box::new(pool.get()?)
+        })
+    }
+
+    pub fn state(&self) -> r2d2::State {
+        match self {
+            ConnPool::Postgres(pool) => pool.state(),
+            ConnPool::Sqlite(pool) => pool.state()
+        }
+    }
+
+    pub fn max_size(&self) -> u32 {
+        match self {
+            ConnPool::Postgres(pool) => pool.max_size(),
+            ConnPool::Sqlite(pool) => pool.max_size()
+        }
+    }
+
+    pub fn run_migrations(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        use diesel_migrations::MigrationHarness;
+
+        match self {
+            ConnPool::Postgres(pool) => {
+                let conn: &mut PgConnection = &mut pool.get()?;
+                conn.run_pending_migrations(PG_MIGRATIONS)?;
+            },
+            ConnPool::Sqlite(pool) => {
+                let conn: &mut SqliteConnection = &mut pool.get()?;
+                conn.run_pending_migrations(SQLITE_MIGRATIONS)?;
+            }
+        }
+
+        Ok(())
+    }
 
-allow_tables_to_appear_in_same_query!(user, user_rank);
-joinable!(user_rank -> user (id));
+    /// Returns the version that was reverted
+    pub fn revert_migration(&self) -> Result<String, Box<dyn Error + Send + Sync>> {
+        use diesel_migrations::MigrationHarness;
 
-//TODO: convert conn argument to data access traits (which are then modular and able to be imported easily) or something else cuz this is getting messy
+        Ok(match self {
+            ConnPool::Postgres(pool) => {
+                let conn: &mut PgConnection = &mut pool.get()?;
+                conn.revert_last_migration(PG_MIGRATIONS)?.to_string()
+            },
+            ConnPool::Sqlite(pool) => {
+                let conn: &mut SqliteConnection = &mut pool.get()?;
+                conn.revert_last_migration(SQLITE_MIGRATIONS)?.to_string()
+            }
+        })
+    }
+
+    /// Applied and pending migration versions
+    pub fn migration_status(&self) -> Result<(Vec<String>, Vec<String>), Box<dyn Error + Send + Sync>> {
+        use diesel_migrations::{MigrationHarness, Migration};
+
+        fn names<DB: diesel::backend::Backend>(migrations: Vec<Box<dyn Migration<DB>>>) -> Vec<String> {
+            migrations.iter().map(|m| m.name().version().to_string()).collect()
+        }
+
+        Ok(match self {
+            ConnPool::Postgres(pool) => {
+                let conn: &mut PgConnection = &mut pool.get()?;
+                let applied = conn.applied_migrations()?.iter().map(ToString::to_string).collect();
+                (applied, names(conn.pending_migrations(PG_MIGRATIONS)?))
+            },
+            ConnPool::Sqlite(pool) => {
+                let conn: &mut SqliteConnection = &mut pool.get()?;
+                let applied = conn.applied_migrations()?.iter().map(ToString::to_string).collect();
+                (applied, names(conn.pending_migrations(SQLITE_MIGRATIONS)?))
+            }
+        })
+    }
+}
 
 impl User {
     pub fn get(uid: UserId, conn: &mut Conn) -> Self {
-        user::table.find(uid).first(conn).unwrap_or_else(|_| User::new(uid))
+        conn.get_user(uid)
+    }
+
+    /// Users hiding from the leaderboard are left out, and ranked as if they weren't there
+    pub fn get_top(limit: i64, conn: &mut Conn) -> Res<Vec<LeaderboardEntry>> {
+        User::get_top_page(0, limit, conn)
     }
 
-    pub fn get_top(limit: i64, conn: &mut Conn) -> Res<Vec<Self>> {
-        user_rank::table.inner_join(user::table).order_by(user_rank::rank)
-            .select(user::all_columns).limit(limit).load(conn)
+    pub fn get_top_page(offset: i64, limit: i64, conn: &mut Conn) -> Res<Vec<LeaderboardEntry>> {
+        conn.get_top_users(offset, limit)
     }
 
     pub fn update(&self, conn:&mut Conn) -> Res<usize> {
-        insert_into(user::table).values(self).on_conflict(user::id).do_update().set(self).execute(conn)
+        conn.update_user(self)
     }
 
     pub fn get_last_bottles(&self, limit:i64, conn:&mut Conn) -> Res<Vec<Bottle>> {
-        Bottle::belonging_to(self).filter(bottle::guild.is_not_null()).filter(bottle::reply_to.is_null()).filter(bottle::deleted.eq(false)).order(bottle::time_pushed.desc()).limit(limit).load(conn)
+        conn.get_last_bottles(self, limit)
     }
 
     pub fn get_all_bottles(&self, conn:&mut Conn) -> Res<Vec<Bottle>> {
-        Bottle::belonging_to(self).load(conn)
+        conn.get_all_bottles(self)
     }
 
     pub fn get_bottle(&self, conn:&mut Conn) -> Res<Bottle> {
-        Bottle::belonging_to(self).order(bottle::time_pushed.desc()).limit(1).first(conn)
+        conn.get_newest_bottle(self)
     }
 
     pub fn get_num_bottles(&self, conn:&mut Conn) -> Res<i64> {
-        Bottle::belonging_to(self).select(dsl::count_star()).first(conn)
+        conn.get_num_bottles(self)
     }
 
     pub fn get_ranking(&self, conn:&mut Conn) -> Res<i64> {
-        user_rank::table.find(self.id).select(user_rank::rank).first(conn)
+        conn.get_user_ranking(self.id)
     }
 
     pub fn get_banned(&self, conn:&mut Conn) -> Res<bool> {
-        select(dsl::exists(ban::table.find(self.id))).get_result(conn)
+        conn.get_banned(self.id)
+    }
+
+    pub fn get_replies(&self, limit:i64, conn:&mut Conn) -> Res<Vec<Bottle>> {
+        conn.get_replies_to(self, limit)
+    }
+
+    pub fn get_managed_guilds(&self, conn:&mut Conn) -> Res<Vec<Guild>> {
+        conn.get_managed_guilds(self.id)
     }
 
-    pub fn from_session(ses:Uuid, conn:&mut Conn) -> Res<Self> {
-        user::table.filter(user::session.eq(ses)).first(conn)
+    pub fn can_manage(&self, gid: GuildId, conn:&mut Conn) -> Res<bool> {
+        conn.can_manage(self.id, gid)
+    }
+
+    pub fn set_managed_guilds(&self, guilds: &[GuildId], conn:&mut Conn) -> Res<()> {
+        conn.set_managed_guilds(self.id, guilds)
     }
 
     pub fn get_contributions(&self, limit:i64, conn:&mut Conn) -> Res<Vec<GuildContribution>> {
-        guild_contribution::table.filter(guild_contribution::user.eq(self.id)).order(guild_contribution::xp.desc()).limit(limit).load(conn)
+        conn.get_user_contributions(self.id, limit)
+    }
+
+    /// Bottles and bans still reference the user, so the row itself is kept but reset to nothing but its id
+    pub fn erase(uid: UserId, conn:&mut Conn) -> Res<()> {
+        conn.erase_user(uid)
+    }
+
+    /// Erased users keep their id, this tells them apart so their Discord profile isn't stored again
+    pub fn is_erased(uid: UserId, conn:&mut Conn) -> Res<bool> {
+        conn.is_erased(uid)
+    }
+
+    pub fn export(&self, conn:&mut Conn) -> Res<UserExport> {
+        conn.export_user(self)
     }
 }
 
 impl Guild {
     pub fn get(gid: GuildId, conn:&mut Conn) -> Self {
-        guild::table.find(gid).first(conn).unwrap_or_else(|_| Guild::new(gid))
+        conn.get_guild(gid)
     }
 
-    pub fn get_top(limit: i64, conn: &mut Conn) -> Res<Vec<Self>> {
-        guild_rank::table.inner_join(guild::table).order_by(guild_rank::rank)
-            .select(guild::all_columns).limit(limit).load(conn)
+    pub fn get_top(limit: i64, conn: &mut Conn) -> Res<Vec<LeaderboardEntry>> {
+        Guild::get_top_page(0, limit, conn)
+    }
+
+    pub fn get_top_page(offset: i64, limit: i64, conn: &mut Conn) -> Res<Vec<LeaderboardEntry>> {
+        conn.get_top_guilds(offset, limit)
     }
 
     pub fn update(&self, conn:&mut Conn) -> Res<usize> {
-        insert_into(guild::table).values(self).on_conflict(guild::id).do_update().set(self).execute(conn)
+        conn.update_guild(self)
     }
 
     pub fn get_contributions(&self, limit:i64, conn:&mut Conn) -> Res<Vec<GuildContribution>> {
-        guild_contribution::table.filter(guild_contribution::guild.eq(self.id)).order(guild_contribution::xp.desc()).limit(limit).load(conn)
+        conn.get_guild_contributions(self.id, limit)
     }
 
     pub fn get_xp(&self, conn:&mut Conn) -> Res<i64> {
-        let x: Option<i64> =
-            guild_contribution::table.filter(guild_contribution::guild.eq(self.id)).select(dsl::sum(guild_contribution::xp)).first(conn)?;
-
-        Ok(x.unwrap_or(0))
+        conn.get_guild_xp(self.id)
     }
 
     pub fn get_ranking(&self, conn:&mut Conn) -> Res<i64> {
-        guild_rank::table.find(self.id).select(guild_rank::rank).first(conn)
+        conn.get_guild_ranking(self.id)
     }
 
     pub fn get_num_bottles(&self, conn:&mut Conn) -> Res<i64> {
         let b = self.bottle_channel.ok_or(result::Error::NotFound)?;
-        received_bottle::table.filter(received_bottle::channel.eq(b)).select(dsl::count_star()).first(conn)
+        conn.get_channel_received(b)
+    }
+
+    /// Guilds with a bottle channel to deliver to, least recently delivered to first
+    pub fn get_delivery_channels(from: i64, reply: bool, offset: i64, limit: i64, conn:&mut Conn) -> Res<Vec<(GuildId, i64)>> {
+        conn.get_delivery_channels(from, reply, offset, limit)
     }
 
     pub fn del(gid: GuildId, conn:&mut Conn) -> Res<usize> {
-        delete(guild::table).filter(guild::id.eq(gid)).execute(conn)
+        conn.del_guild(gid)
+    }
+
+    /// Drops every copy received in the guild and scrubs the bottles sent from it
+    pub fn erase(gid: GuildId, conn:&mut Conn) -> Res<()> {
+        conn.erase_guild(gid)
     }
 }
 
 impl MakeBottle {
     pub fn make(&self, conn:&mut Conn) -> Res<Bottle> {
-        insert_into(bottle::table).values(self).get_result(conn)
+        conn.make_bottle(self)
     }
 }
 
 impl Bottle {
     pub fn get(id:BottleId, conn:&mut Conn) -> Res<Self> {
-        bottle::table.find(id).get_result(conn)
+        conn.get_bottle(id)
     }
 
     pub fn get_from_message(mid: i64, conn: &mut Conn) -> Res<Bottle> {
-        bottle::table.filter(bottle::message.eq(mid)).first(conn)
+        conn.get_bottle_from_message(mid)
     }
 
     pub fn get_recv_or_bottle_from_message(mid: i64, conn: &mut Conn) -> Res<Bottle> {
@@ -150,22 +1037,48 @@ impl Bottle {
     }
 
     pub fn get_last(channel: i64, conn:&mut Conn) -> Res<Bottle> {
-        bottle::table.left_join(received_bottle::table)
-            .filter(bottle::channel.eq(channel).or(received_bottle::channel.eq(channel)))
-            .order((bottle::time_pushed.desc(), received_bottle::time_recieved.desc()))
-            .select(bottle::all_columns).first(conn)
+        conn.get_last_bottle(channel)
     }
 
     pub fn edit(id: BottleId, change: MakeBottle, conn:&mut Conn) -> Res<usize> {
-        update(bottle::table.filter(bottle::id.eq(id))).set(change).execute(conn)
+        conn.edit_bottle(id, change)
+    }
+
+    /// Leaves out anonymous and deleted bottles, and those of users hiding their bottles or profile
+    pub fn search(search: &BottleSearch, offset: i64, limit: i64, conn:&mut Conn) -> Res<Vec<Self>> {
+        conn.search_bottles(search, offset, limit)
+    }
+
+    pub fn get_replies(&self, conn:&mut Conn) -> Res<Vec<Self>> {
+        conn.get_bottle_replies(self)
     }
 
     pub fn in_reply_to(id: BottleId, conn:&mut Conn) -> Res<i64> {
-         bottle::table.filter(bottle::reply_to.eq(id)).select(dsl::count_star()).first(conn)
+        conn.count_replies(id)
     }
 
     pub fn del(id:BottleId, conn:&mut Conn) -> Res<usize> {
-        update(bottle::table).filter(bottle::id.eq(id)).set(bottle::deleted.eq(true)).execute(conn)
+        conn.del_bottle(id)
+    }
+
+    pub fn scrub_from_user(uid: UserId, conn:&mut Conn) -> Res<usize> {
+        conn.scrub_user_bottles(uid)
+    }
+
+    pub fn set_pseudonym(id: BottleId, number: i32, conn:&mut Conn) -> Res<usize> {
+        conn.set_pseudonym(id, number)
+    }
+
+    pub fn get_thread_root(&self, conn:&mut Conn) -> Res<BottleId> {
+        let mut root = self.id;
+        let mut parent = self.reply_to;
+
+        while let Some(x) = parent {
+            root = x;
+            parent = conn.get_reply_to(x)?;
+        }
+
+        Ok(root)
     }
 
     pub fn get_reply_list(&self, conn:&mut Conn) -> Res<(Vec<Self>, bool)> {
@@ -191,82 +1104,479 @@ impl Bottle {
 
 impl MakeReceivedBottle {
     pub fn make(&self, conn:&mut Conn) -> Res<ReceivedBottle> {
-        insert_into(received_bottle::table).values(self).get_result(conn)
+        conn.make_received(self)
     }
 }
 
 impl ReceivedBottle {
     pub fn get(buid: ReceivedBottleId, conn:&mut Conn) -> Res<Self> {
-        received_bottle::table.find(buid).get_result(conn)
+        conn.get_received(buid)
     }
 
     pub fn get_from_bottle(bid: BottleId, conn:&mut Conn) -> Res<Vec<Self>> {
-        received_bottle::table.filter(received_bottle::bottle.eq(bid)).load(conn)
+        conn.get_received_from_bottle(bid)
     }
 
     pub fn get_from_message(mid:i64, conn:&mut Conn) -> Res<Self> {
-        received_bottle::table.filter(received_bottle::message.eq(mid)).get_result(conn)
+        conn.get_received_from_message(mid)
+    }
+
+    pub fn get_trail(bid: BottleId, conn:&mut Conn) -> Res<Vec<(Self, Option<GuildId>)>> {
+        conn.get_trail(bid)
+    }
+
+    pub fn get_from_user(uid: UserId, conn:&mut Conn) -> Res<Vec<Self>> {
+        conn.get_received_from_user(uid)
     }
 
     pub fn get_last(channel: i64, conn:&mut Conn) -> Res<Bottle> {
-        received_bottle::table.inner_join(bottle::table)
-            .filter(received_bottle::channel.eq(channel))
-            .order(received_bottle::time_recieved.desc())
-            .select(bottle::all_columns).first(conn)
+        conn.get_last_received(channel)
     }
 
     pub fn del(&self, conn:&mut Conn) -> Res<usize> {
-        delete(received_bottle::table.find(self.id)).execute(conn)
+        conn.del_received(self.id)
+    }
+}
+
+impl PendingDelivery {
+    pub fn make(&self, conn:&mut Conn) -> Res<usize> {
+        conn.make_pending_delivery(self)
+    }
+
+    pub fn get_all(conn:&mut Conn) -> Res<Vec<Bottle>> {
+        conn.get_pending_deliveries()
+    }
+
+    pub fn del(bid: BottleId, conn:&mut Conn) -> Res<usize> {
+        conn.del_pending_delivery(bid)
     }
 }
 
 impl GuildContribution {
     pub fn get(id: GuildContributionId, conn:&mut Conn) -> Self {
-        guild_contribution::table.find(id).first(conn).unwrap_or_else(|_| GuildContribution {guild: id.0, user: id.1, xp: 0})
+        conn.get_contribution(id)
     }
 
     pub fn update(&self, conn:&mut Conn) -> Res<Self> {
-        insert_into(guild_contribution::table).values(self)
-            .on_conflict((guild_contribution::guild, guild_contribution::user)).do_update().set(self).get_result(conn)
+        conn.update_contribution(self)
+    }
+}
+
+impl ApiKey {
+    pub fn make(&self, conn:&mut Conn) -> Res<usize> {
+        conn.make_api_key(self)
+    }
+
+    pub fn get(key: &str, conn:&mut Conn) -> Res<Self> {
+        conn.get_api_key(&ApiKey::hash(key))
+    }
+
+    pub fn get_from_user(uid: UserId, conn:&mut Conn) -> Res<Vec<Self>> {
+        conn.get_api_keys(uid)
+    }
+
+    pub fn revoke(id: &str, uid: UserId, conn:&mut Conn) -> Res<usize> {
+        conn.revoke_api_key(id, uid)
+    }
+}
+
+impl<'a> MakePlatformId<'a> {
+    /// Looks up the id of the external user or channel, keeping its display name current
+    pub fn get_or_make(&self, conn:&mut Conn) -> Res<PlatformId> {
+        conn.get_or_make_platform_id(self)
+    }
+}
+
+impl PlatformId {
+    pub fn get(id: i64, conn:&mut Conn) -> Res<Self> {
+        conn.get_platform_id(id)
+    }
+
+    pub fn get_all(conn:&mut Conn) -> Res<Vec<Self>> {
+        conn.get_platform_ids()
+    }
+
+    /// Bridged platforms without message ids of their own take one from the same sequence
+    pub fn next(conn:&mut Conn) -> Res<i64> {
+        conn.next_platform_id()
+    }
+}
+
+impl DiscordProfile {
+    pub fn update(&self, conn:&mut Conn) -> Res<usize> {
+        conn.update_profile(self)
+    }
+
+    pub fn get(id: i64, conn:&mut Conn) -> Res<Self> {
+        conn.get_profile(id)
+    }
+
+    /// Least recently refreshed first
+    pub fn get_stale(before: DTime, limit: i64, conn:&mut Conn) -> Res<Vec<Self>> {
+        conn.get_stale_profiles(before, limit)
+    }
+}
+
+impl MakeWebhook {
+    pub fn make(&self, conn:&mut Conn) -> Res<Webhook> {
+        conn.make_webhook(self)
+    }
+}
+
+impl Webhook {
+    /// Webhooks of the guild along with every global webhook
+    pub fn get_for(guild: Option<GuildId>, conn:&mut Conn) -> Res<Vec<Self>> {
+        conn.get_webhooks_for(guild)
+    }
+
+    pub fn get_from_guild(guild: Option<GuildId>, conn:&mut Conn) -> Res<Vec<Self>> {
+        conn.get_webhooks_from(guild)
+    }
+
+    pub fn del(id: WebhookId, guild: Option<GuildId>, conn:&mut Conn) -> Res<usize> {
+        conn.del_webhook(id, guild)
+    }
+}
+
+impl<'a> MakeWebhookDelivery<'a> {
+    pub fn make(&self, conn:&mut Conn) -> Res<usize> {
+        conn.make_delivery(self)
+    }
+}
+
+impl WebhookDelivery {
+    pub fn get_due(limit: i64, conn:&mut Conn) -> Res<Vec<(Self, Webhook)>> {
+        conn.get_due_deliveries(limit)
+    }
+
+    pub fn count_due(conn: &mut Conn) -> Res<i64> {
+        conn.count_due_deliveries()
+    }
+
+    pub fn record(&self, next_attempt: Option<DTime>, status: Option<i32>, error: Option<String>, conn:&mut Conn) -> Res<usize> {
+        conn.record_delivery(self, next_attempt, status, error)
+    }
+}
+
+impl Session {
+    pub fn get(id: &str, conn:&mut Conn) -> Res<Self> {
+        conn.get_session(id)
+    }
+
+    pub fn update(&self, conn:&mut Conn) -> Res<usize> {
+        conn.update_session(self)
+    }
+
+    pub fn revoke(id: &str, conn:&mut Conn) -> Res<usize> {
+        conn.revoke_session(id)
+    }
+
+    pub fn clean(conn:&mut Conn) -> Res<usize> {
+        conn.clean_sessions()
+    }
+}
+
+impl<'a> MakeXpEvent<'a> {
+    pub fn make(&self, conn:&mut Conn) -> Res<usize> {
+        conn.make_xp_event(self)
+    }
+}
+
+impl XpEvent {
+    pub fn get_from_user(uid: UserId, conn:&mut Conn) -> Res<Vec<Self>> {
+        conn.get_xp_events(uid)
+    }
+
+    pub fn get_latest(conn:&mut Conn) -> Res<Option<i64>> {
+        conn.get_latest_xp_event()
+    }
+}
+
+impl LeaderboardEntry {
+    pub fn refresh(conn:&mut Conn) -> Res<()> {
+        conn.refresh_leaderboards()
+    }
+}
+
+impl Privacy {
+    pub fn get(uid: UserId, conn:&mut Conn) -> Self {
+        conn.get_privacy(uid)
+    }
+
+    pub fn update(&self, conn:&mut Conn) -> Res<usize> {
+        conn.update_privacy(self)
+    }
+}
+
+impl Pseudonym {
+    pub fn get_or_make(thread: BottleId, uid: UserId, conn:&mut Conn) -> Res<Self> {
+        conn.get_or_make_pseudonym(thread, uid)
     }
 }
 
 impl Report {
     pub fn make(&self, conn:&mut Conn) -> Res<Self> {
-        insert_into(report::table).values(self).on_conflict(report::bottle).do_update().set(self).get_result(conn)
+        conn.make_report(self)
     }
 
     pub fn exists(bid: BottleId, conn:&mut Conn) -> Res<bool> {
-        select(dsl::exists(report::table.find(bid))).first(conn)
+        conn.report_exists(bid)
     }
 
     pub fn get_from_recv_user(rid:ReceivedBottleId, uid: UserId, conn:&mut Conn) -> Res<Self> {
-        report::table.filter(report::received_bottle.eq(rid)).filter(report::user.eq(uid)).first(conn)
+        conn.get_report(rid, uid)
     }
 
     pub fn del(&self, conn:&mut Conn) -> Res<usize> {
-        delete(report::table.find(self.bottle)).execute(conn)
+        conn.del_report(self.bottle)
     }
 }
 
 impl Ban {
     pub fn make(&self, conn:&mut Conn) -> Res<Self> {
-        insert_into(ban::table).values(self).get_result(conn)
+        conn.make_ban(self)
     }
 
     pub fn del(&self, conn:&mut Conn) -> Res<usize> {
-        delete(ban::table.find(self.user)).execute(conn)
+        conn.del_ban(self.user)
     }
 }
 
 pub fn get_bottle_count (conn: &mut Conn) -> Res<i64> {
-    select(estimate_rows("bottle".to_owned())).get_result(conn)
+    conn.estimate_rows("bottle")
 }
 
 pub fn get_user_count (conn: &mut Conn) -> Res<i64> {
-    select(estimate_rows("user".to_owned())).get_result(conn)
+    conn.estimate_rows("user")
 }
 
 pub fn get_guild_count (conn: &mut Conn) -> Res<i64> {
-    select(estimate_rows("guild".to_owned())).get_result(conn)
-}
\ No newline at end of file
+    conn.estimate_rows("guild")
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use model::*;
+    use testing::*;
+
+    const A: UserId = 101;
+    const B: UserId = 102;
+    const C: UserId = 103;
+
+    const G1: GuildId = 1;
+    const G2: GuildId = 2;
+    const G3: GuildId = 3;
+    const G4: GuildId = 4;
+    const C1: i64 = 11;
+    const C2: i64 = 21;
+    const C3: i64 = 31;
+    const C4: i64 = 41;
+
+    #[test]
+    fn platform_ids_are_negative_and_unique() {
+        let h = Harness::new();
+        let conn = &mut h.conn();
+
+        let first = PlatformId::next(conn).unwrap();
+        let second = PlatformId::next(conn).unwrap();
+        assert!(first < 0 && second < 0);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn delivery_goes_to_the_longest_waiting_guilds() {
+        let h = Harness::new();
+        h.user(A, "a#0001");
+        for (g, c) in [(G1, C1), (G2, C2), (G3, C3), (G4, C4)] {
+            h.guild(g, "Guild", c);
+        }
+
+        let conn = &mut h.conn();
+        Guild::get(5, conn).update(conn).unwrap(); //no bottle channel, so never delivered to
+
+        let bottle = h.bottle(A, Some(G1), C1, "hi");
+        h.received(&bottle, G2, C2, now() - Duration::hours(1));
+        h.received(&bottle, G2, C2, now() - Duration::hours(3)); //only the latest copy counts
+        h.received(&bottle, G3, C3, now() - Duration::hours(2));
+
+        assert_eq!(Guild::get_delivery_channels(C1, false, 0, 10, conn).unwrap(), vec![(G4, C4), (G3, C3), (G2, C2)]);
+        assert_eq!(Guild::get_delivery_channels(C4, false, 0, 10, conn).unwrap(), vec![(G1, C1), (G3, C3), (G2, C2)]);
+        assert_eq!(Guild::get_delivery_channels(C2, false, 0, 10, conn).unwrap(), vec![(G1, C1), (G4, C4), (G3, C3)]); //ties go by id
+        assert_eq!(Guild::get_delivery_channels(C1, false, 0, 2, conn).unwrap(), vec![(G4, C4), (G3, C3)]);
+        assert_eq!(Guild::get_delivery_channels(C1, false, 2, 2, conn).unwrap(), vec![(G2, C2)]);
+
+        let mut g3 = Guild::get(G3, conn);
+        g3.receive_bottles = false;
+        g3.update(conn).unwrap();
+        let mut g4 = Guild::get(G4, conn);
+        g4.receive_replies = false;
+        g4.update(conn).unwrap();
+
+        assert_eq!(Guild::get_delivery_channels(C1, false, 0, 10, conn).unwrap(), vec![(G4, C4), (G2, C2)]);
+        assert_eq!(Guild::get_delivery_channels(C1, true, 0, 10, conn).unwrap(), vec![(G3, C3), (G2, C2)]);
+    }
+
+    #[test]
+    fn sqlite_counts_rows_exactly() {
+        let h = Harness::new();
+        let conn = &mut h.conn();
+        if let ConnPool::Postgres(_) = h.service.pool {
+            return; //postgres only estimates from its statistics
+        }
+
+        h.user(A, "a#0001");
+        h.guild(G1, "One", C1);
+        h.guild(G2, "Two", C2);
+
+        assert_eq!(get_user_count(conn).unwrap(), 1);
+        assert_eq!(get_guild_count(conn).unwrap(), 2);
+        assert_eq!(get_bottle_count(conn).unwrap(), 0);
+    }
+
+    fn set_xp(h: &Harness, uid: UserId, xp: i32) {
+        let conn = &mut h.conn();
+        let mut u = User::get(uid, conn);
+        u.xp = xp;
+        u.update(conn).unwrap();
+    }
+
+    fn ranks(entries: Vec<LeaderboardEntry>) -> Vec<(i64, i64, i64)> {
+        entries.into_iter().map(|e| (e.id, e.rank, e.xp)).collect()
+    }
+
+    #[test]
+    fn leaderboards_rank_by_xp_then_id() {
+        let h = Harness::new();
+        let conn = &mut h.conn();
+        for (uid, xp) in [(A, 10), (C, 30), (B, 30)] {
+            h.user(uid, "user#0001");
+            set_xp(&h, uid, xp);
+        }
+
+        h.guild(G1, "One", C1);
+        h.guild(G2, "Two", C2);
+        for (guild, user, xp) in [(G1, A, 5), (G2, B, 7), (G2, A, 1)] {
+            GuildContribution {guild, user, xp}.update(conn).unwrap();
+        }
+
+        assert!(User::get_top(10, conn).unwrap().is_empty()); //nothing until the first refresh
+        LeaderboardEntry::refresh(conn).unwrap();
+
+        assert_eq!(ranks(User::get_top(10, conn).unwrap()), vec![(B, 1, 30), (C, 2, 30), (A, 3, 10)]);
+        assert_eq!(ranks(User::get_top_page(1, 1, conn).unwrap()), vec![(C, 2, 30)]);
+        assert_eq!(ranks(Guild::get_top(10, conn).unwrap()), vec![(G2, 1, 8), (G1, 2, 5)]);
+        assert_eq!(User::get(A, conn).get_ranking(conn).unwrap(), 3);
+
+        set_xp(&h, A, 50);
+        LeaderboardEntry::refresh(conn).unwrap();
+        assert_eq!(ranks(User::get_top(10, conn).unwrap()), vec![(A, 1, 50), (B, 2, 30), (C, 3, 30)]);
+    }
+
+    #[test]
+    fn hidden_users_leave_no_gap_in_the_ranks() {
+        let h = Harness::new();
+        let conn = &mut h.conn();
+        for (uid, xp) in [(A, 30), (B, 20), (C, 10)] {
+            h.user(uid, "user#0001");
+            set_xp(&h, uid, xp);
+        }
+
+        let mut privacy = Privacy::get(B, conn);
+        privacy.hide_leaderboard = true;
+        privacy.update(conn).unwrap();
+
+        LeaderboardEntry::refresh(conn).unwrap();
+        assert_eq!(ranks(User::get_top(10, conn).unwrap()), vec![(A, 1, 30), (C, 2, 10)]);
+        assert!(User::get(B, conn).get_ranking(conn).is_err());
+
+        privacy.hide_leaderboard = false;
+        privacy.update(conn).unwrap();
+        LeaderboardEntry::refresh(conn).unwrap();
+        assert_eq!(ranks(User::get_top(10, conn).unwrap()), vec![(A, 1, 30), (B, 2, 20), (C, 3, 10)]);
+    }
+
+    fn search(search: BottleSearch, conn: &mut Conn) -> Vec<String> {
+        let mut found: Vec<String> = Bottle::search(&search, 0, 50, conn).unwrap().into_iter().map(|b| b.contents).collect();
+        found.sort();
+        found
+    }
+
+    fn text(text: &str) -> BottleSearch {
+        BottleSearch {text: Some(text.to_owned()), ..Default::default()}
+    }
+
+    #[test]
+    fn archive_leaves_out_hidden_bottles() {
+        let h = Harness::new();
+        h.user(A, "a#0001");
+        h.user(B, "b#0002");
+        h.user(C, "c#0003");
+        h.guild(G1, "One", C1);
+
+        let conn = &mut h.conn();
+        h.bottle(A, Some(G1), C1, "shown");
+        h.bottle(A, None, C4, "direct message");
+        h.bottle(B, Some(G1), C1, "bottles hidden");
+        h.bottle(C, Some(G1), C1, "profile hidden");
+        MakeBottle {anonymous: true, ..h.draft(A, Some(G1), C1, "anonymous")}.make(conn).unwrap();
+
+        let deleted = h.bottle(A, Some(G1), C1, "deleted");
+        Bottle::del(deleted.id, conn).unwrap();
+
+        let mut privacy = Privacy::get(B, conn);
+        privacy.hide_bottles = true;
+        privacy.update(conn).unwrap();
+
+        let mut privacy = Privacy::get(C, conn);
+        privacy.hide_profile = true;
+        privacy.update(conn).unwrap();
+
+        assert_eq!(search(BottleSearch::default(), conn), vec!["shown"]);
+    }
+
+    #[test]
+    fn archive_filters_combine() {
+        let h = Harness::new();
+        h.user(A, "a#0001");
+        h.user(B, "b#0002");
+        h.guild(G1, "One", C1);
+        h.guild(G2, "Two", C2);
+
+        let conn = &mut h.conn();
+        let first = h.bottle(A, Some(G1), C1, "first");
+        let answered = h.bottle(B, Some(G2), C2, "answered");
+        let ignored = h.bottle(B, Some(G2), C2, "ignored");
+        MakeBottle {reply_to: Some(answered.id), ..h.draft(A, Some(G1), C1, "reply")}.make(conn).unwrap();
+        let gone = MakeBottle {reply_to: Some(ignored.id), ..h.draft(A, Some(G1), C1, "gone")}.make(conn).unwrap();
+        Bottle::del(gone.id, conn).unwrap();
+
+        assert_eq!(search(BottleSearch {guild: Some(G2), ..Default::default()}, conn), vec!["answered", "ignored"]);
+        assert_eq!(search(BottleSearch {user: Some(A), guild: Some(G1), ..Default::default()}, conn), vec!["first", "reply"]);
+        assert_eq!(search(BottleSearch {has_replies: true, ..Default::default()}, conn), vec!["answered"]);
+        assert!(search(BottleSearch {before: Some(first.time_pushed), user: Some(A), guild: Some(G1), ..Default::default()}, conn)
+            .iter().all(|c| c != "first"));
+        assert!(search(BottleSearch {after: Some(first.time_pushed), ..Default::default()}, conn).contains(&"first".to_owned()));
+    }
+
+    #[test]
+    fn archive_search_matches_wildcards_literally() {
+        let h = Harness::new();
+        if let ConnPool::Postgres(_) = h.service.pool {
+            return; //full text search there, which has no wildcards to escape
+        }
+
+        h.user(A, "a#0001");
+        h.guild(G1, "One", C1);
+        for contents in ["100% sure", "1000 sure", "a_b", "axb", "back\\slash"] {
+            h.bottle(A, Some(G1), C1, contents);
+        }
+
+        let conn = &mut h.conn();
+        assert_eq!(search(text("100%"), conn), vec!["100% sure"]);
+        assert_eq!(search(text("a_b"), conn), vec!["a_b"]);
+        assert_eq!(search(text("k\\s"), conn), vec!["back\\slash"]);
+        assert_eq!(search(text("SURE"), conn), vec!["100% sure", "1000 sure"]);
+    }
+}
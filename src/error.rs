@@ -0,0 +1,121 @@
+use std::fmt;
+use std::num::ParseIntError;
+use diesel::result::Error as DieselError;
+
+/// Everything the bot can fail with. ``Display`` is the detail for logs,
+/// ``user_message`` is what gets shown to whoever caused it
+#[derive(Debug)]
+pub enum BottleError {
+    /// Names the thing that wasn't found, e.g. "bottle to reply to"
+    NotFound(&'static str),
+    Banned,
+    Cooldown {remaining: i64},
+    Empty,
+    Permission(String),
+    Invalid(String),
+    ShuttingDown,
+
+    Discord(serenity::Error),
+    Database(DieselError),
+    Unavailable(r2d2::Error),
+    Http(reqwest::Error),
+    Other(String)
+}
+
+impl BottleError {
+    /// Whether this is our fault rather than the user's, and so worth logging
+    pub fn internal(&self) -> bool {
+        match self {
+            BottleError::Discord(_) | BottleError::Database(_) | BottleError::Unavailable(_)
+                | BottleError::Http(_) | BottleError::Other(_) => true,
+            _ => false
+        }
+    }
+
+    /// Label for metrics, one per variant
+    pub fn class(&self) -> &'static str {
+        match self {
+            BottleError::NotFound(_) => "not_found",
+            BottleError::Banned => "banned",
+            BottleError::Cooldown {..} => "cooldown",
+            BottleError::Empty => "empty",
+            BottleError::Permission(_) => "permission",
+            BottleError::Invalid(_) => "invalid",
+            BottleError::ShuttingDown => "shutting_down",
+            BottleError::Discord(_) => "discord",
+            BottleError::Database(_) => "database",
+            BottleError::Unavailable(_) => "unavailable",
+            BottleError::Http(_) => "http",
+            BottleError::Other(_) => "other"
+        }
+    }
+
+    pub fn user_message(&self) -> String {
+        match self {
+            BottleError::NotFound(what) => format!("No {} was found!", what),
+            BottleError::Banned => "You are banned from using Bottle! Appeal by dming the global admins!".to_owned(),
+            BottleError::Cooldown {remaining} => format!("You must wait {} seconds before sending another bottle!", remaining),
+            BottleError::Empty => "Your bottle cannot be empty!".to_owned(),
+            BottleError::Permission(x) | BottleError::Invalid(x) => x.clone(),
+            BottleError::ShuttingDown => "Bottle is restarting, please try again in a minute!".to_owned(),
+            BottleError::Unavailable(_) => "Bottle is busy right now, please try again in a moment!".to_owned(),
+            BottleError::Discord(_) => "Discord couldn't be reached, please try again later!".to_owned(),
+            _ => "Something went wrong on our end, sorry!".to_owned()
+        }
+    }
+}
+
+impl fmt::Display for BottleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BottleError::Discord(err) => write!(f, "Discord error: {}", err),
+            BottleError::Database(err) => write!(f, "Database error: {}", err),
+            BottleError::Unavailable(err) => write!(f, "Connection pool error: {}", err),
+            BottleError::Http(err) => write!(f, "HTTP error: {}", err),
+            BottleError::Other(err) => write!(f, "{}", err),
+            x => write!(f, "{}", x.user_message())
+        }
+    }
+}
+
+impl std::error::Error for BottleError {}
+
+impl From<DieselError> for BottleError {
+    fn from(err: DieselError) -> Self {
+        match err {
+            DieselError::NotFound => BottleError::NotFound("record"),
+            err => BottleError::Database(err)
+        }
+    }
+}
+
+impl From<serenity::Error> for BottleError {
+    fn from(err: serenity::Error) -> Self { BottleError::Discord(err) }
+}
+
+impl From<r2d2::Error> for BottleError {
+    fn from(err: r2d2::Error) -> Self { BottleError::Unavailable(err) }
+}
+
+impl From<reqwest::Error> for BottleError {
+    fn from(err: reqwest::Error) -> Self { BottleError::Http(err) }
+}
+
+impl From<String> for BottleError {
+    fn from(err: String) -> Self { BottleError::Other(err) }
+}
+
+impl<'a> From<&'a str> for BottleError {
+    fn from(err: &'a str) -> Self { BottleError::Other(err.to_owned()) }
+}
+
+/// Failures that only ever need their message
+macro_rules! other_from {
+    ($($err:ty),*) => {
+        $(impl From<$err> for BottleError {
+            fn from(err: $err) -> Self { BottleError::Other(err.to_string()) }
+        })*
+    };
+}
+
+other_from!(std::io::Error, serde_json::Error, ParseIntError, tokio::task::JoinError);
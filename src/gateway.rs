@@ -0,0 +1,259 @@
+use chrono::{DateTime, Utc};
+use futures_lite::future::block_on;
+use serenity::http::Http;
+use serenity::model::id::{ChannelId, GuildId, MessageId, UserId};
+use serenity::model::channel::{Message, ChannelType};
+use serenity::utils::Colour;
+
+use model;
+use model::*;
+use model::id::*;
+use platform::{RenderedBottle, bridged_name};
+
+#[derive(Clone, Debug)]
+pub struct ChatUser {
+    pub id: model::UserId,
+    pub tag: String,
+    pub avatar: Option<String>,
+    pub bot: bool
+}
+
+#[derive(Clone, Debug)]
+pub struct ChatGuild {
+    pub id: model::GuildId,
+    pub name: String,
+    pub icon: Option<String>
+}
+
+/// Everything the bot core asks of the chat service, channel and message ids are the raw snowflakes
+pub trait ChatGateway: Send + Sync {
+    fn send_bottle(&self, channel: i64, bottle: &RenderedBottle) -> Res<i64>;
+    fn edit_bottle(&self, channel: i64, message: i64, bottle: &RenderedBottle) -> Res<()>;
+    fn say(&self, channel: i64, text: &str) -> Res<i64>;
+    fn react(&self, channel: i64, message: i64, emoji: &str) -> Res<()>;
+
+    fn get_user(&self, user: model::UserId) -> Option<ChatUser>;
+    fn get_guild(&self, guild: model::GuildId) -> Option<ChatGuild>;
+    /// Text channels by id and name, for the settings page
+    fn text_channels(&self, guild: model::GuildId) -> Res<Vec<(i64, String)>>;
+    /// A permanent invite url, for guilds listed publicly
+    fn create_invite(&self, channel: i64) -> Res<String>;
+    /// Drops anything remembered about the user, once they've been erased
+    fn forget_user(&self, _user: model::UserId) {}
+    /// Whether the user currently has Manage Server, since what they had at login can be taken away
+    fn can_manage(&self, guild: model::GuildId, user: model::UserId) -> Res<bool>;
+
+    /// Bridged names first, since those ids don't exist on Discord
+    fn user_name(&self, user: model::UserId) -> String {
+        bridged_name(user).or_else(|| self.get_user(user).map(|u| u.tag))
+            .unwrap_or_else(|| "User not found".to_owned())
+    }
+
+    fn guild_name(&self, guild: model::GuildId) -> String {
+        bridged_name(guild).or_else(|| self.get_guild(guild).map(|g| g.name))
+            .unwrap_or_else(|| "Guild not found".to_owned())
+    }
+}
+
+pub fn col_wheel(num: usize) -> Colour {
+    match num%8 {
+        0 => Colour::BLURPLE,
+        1 => Colour::BLUE,
+        2 => Colour::TEAL,
+        3 => Colour::DARK_GREEN,
+        4 => Colour::KERBAL,
+        5 => Colour::GOLD,
+        6 => Colour::DARK_RED,
+        _ => Colour::MAGENTA
+    }
+}
+
+pub fn bottle_embed(bottle: &RenderedBottle) -> serenity::builder::CreateEmbed {
+    let e = serenity::builder::CreateEmbed::default();
+
+    if bottle.deleted {
+        e.title(&bottle.title).description(&bottle.contents);
+        return e;
+    }
+
+    let mut extra_info = String::new();
+    if let Some(x) = &bottle.url {
+        if bottle.contents.is_empty() {
+            extra_info.push_str(&format!(" [Link]({})", x));
+        }
+    };
+
+    if let Some(url) = &bottle.guild_url {
+        extra_info.push_str(&format!(" [Guild]({})", url))
+    }
+
+    e.title(&bottle.title)
+        .description(format!("{}{} [Report]({})", bottle.contents, extra_info, bottle.report_url))
+        .timestamp(&DateTime::<Utc>::from_utc(bottle.time, Utc))
+        .color(col_wheel(bottle.level))
+        .footer(|footer|
+            if let Some(ref origin) = bottle.origin {
+                let mut f = footer.text(origin);
+                if let Some(ref icon) = bottle.origin_icon {
+                    f = f.icon_url(icon);
+                }
+
+                f
+            } else {
+                footer.text("No guild found")
+            }
+        )
+        .author(|author| {
+            let author = author.name(&bottle.author).icon_url(&bottle.avatar);
+            match bottle.author_url {
+                Some(ref url) => author.url(url),
+                None => author
+            }
+        });
+
+    if let Some(img) = &bottle.image {
+        e.image(img).url(img);
+    }
+
+    if let Some(url) = &bottle.url {
+        e.url(url);
+    }
+
+    e
+}
+
+#[tracing::instrument(skip_all, fields(channel = channel.0, edit = ?edit))]
+pub async fn render_bottle(bottle: &RenderedBottle, edit: Option<MessageId>, channel: ChannelId) -> Res<Message> {
+    channel.broadcast_typing().await?;
+
+    let embd = bottle_embed(bottle);
+
+    let msg = {
+        if let Some(x) = edit {
+            channel.edit_message(x, |x| x.embed(|_| embd))
+        } else {
+            channel.send_message(|x| x.embed(|_| embd))
+        }
+    }?;
+
+    Ok(msg)
+}
+
+pub struct DiscordGateway;
+
+impl ChatGateway for DiscordGateway {
+    fn send_bottle(&self, channel: i64, bottle: &RenderedBottle) -> Res<i64> {
+        let msg = block_on(render_bottle(bottle, None, ChannelId(channel as u64)))?;
+        Ok(msg.id.as_i64())
+    }
+
+    fn edit_bottle(&self, channel: i64, message: i64, bottle: &RenderedBottle) -> Res<()> {
+        block_on(render_bottle(bottle, Some(MessageId(message as u64)), ChannelId(channel as u64)))?;
+        Ok(())
+    }
+
+    fn say(&self, channel: i64, text: &str) -> Res<i64> {
+        Ok(ChannelId(channel as u64).say(text)?.id.as_i64())
+    }
+
+    fn react(&self, channel: i64, message: i64, emoji: &str) -> Res<()> {
+        ChannelId(channel as u64).create_reaction(MessageId(message as u64), emoji)?;
+        Ok(())
+    }
+
+    fn get_user(&self, user: model::UserId) -> Option<ChatUser> {
+        UserId(user as u64).to_user().ok()
+            .map(|u| ChatUser {id: user, tag: u.tag(), avatar: u.avatar_url(), bot: u.bot})
+    }
+
+    /// Guilds we're in are already in the gateway cache
+    fn get_guild(&self, guild: model::GuildId) -> Option<ChatGuild> {
+        if let Some(g) = GuildId(guild as u64).to_guild_cached() {
+            let g = g.read();
+            return Some(ChatGuild {id: guild, name: g.name.clone(), icon: g.icon_url()});
+        }
+
+        GuildId(guild as u64).to_partial_guild().ok()
+            .map(|g| ChatGuild {id: guild, name: g.name.clone(), icon: g.icon_url()})
+    }
+
+    fn text_channels(&self, guild: model::GuildId) -> Res<Vec<(i64, String)>> {
+        Ok(GuildId(guild as u64).channels()?.into_iter()
+            .filter(|(_, c)| c.kind == ChannelType::Text)
+            .map(|(cid, c)| (cid.0 as i64, c.name)).collect())
+    }
+
+    fn create_invite(&self, channel: i64) -> Res<String> {
+        Ok(ChannelId(channel as u64).create_invite(|x| x.max_age(0).temporary(true))?.url())
+    }
+
+    fn can_manage(&self, guild: model::GuildId, user: model::UserId) -> Res<bool> {
+        Ok(GuildId(guild as u64).member(UserId(user as u64))?.permissions()?.manage_guild())
+    }
+}
+
+/// REST only, for processes without a gateway connection like ``web-only`` and the operator commands
+pub struct HttpGateway {
+    http: Http,
+    /// reqwest needs a tokio runtime underneath, which these processes don't otherwise start
+    rt: tokio::runtime::Runtime
+}
+
+impl HttpGateway {
+    pub fn new(token: &str) -> HttpGateway {
+        let rt = tokio::runtime::Builder::new_multi_thread().enable_all().build()
+            .expect("Error starting the Discord HTTP runtime.");
+
+        HttpGateway {http: Http::new(&format!("Bot {}", token)), rt}
+    }
+}
+
+impl ChatGateway for HttpGateway {
+    fn send_bottle(&self, channel: i64, bottle: &RenderedBottle) -> Res<i64> {
+        let embd = bottle_embed(bottle);
+        let msg = self.rt.block_on(ChannelId(channel as u64).send_message(&self.http, |x| x.set_embed(embd)))?;
+        Ok(msg.id.as_i64())
+    }
+
+    fn edit_bottle(&self, channel: i64, message: i64, bottle: &RenderedBottle) -> Res<()> {
+        let embd = bottle_embed(bottle);
+        self.rt.block_on(ChannelId(channel as u64).edit_message(&self.http, MessageId(message as u64), |x| x.set_embed(embd)))?;
+        Ok(())
+    }
+
+    fn say(&self, channel: i64, text: &str) -> Res<i64> {
+        Ok(self.rt.block_on(ChannelId(channel as u64).say(&self.http, text))?.id.as_i64())
+    }
+
+    fn react(&self, channel: i64, message: i64, emoji: &str) -> Res<()> {
+        self.rt.block_on(ChannelId(channel as u64).create_reaction(&self.http, MessageId(message as u64), emoji))?;
+        Ok(())
+    }
+
+    fn get_user(&self, user: model::UserId) -> Option<ChatUser> {
+        self.rt.block_on(self.http.get_user(user as u64)).ok()
+            .map(|u| ChatUser {id: user, tag: u.tag(), avatar: u.avatar_url(), bot: u.bot})
+    }
+
+    fn get_guild(&self, guild: model::GuildId) -> Option<ChatGuild> {
+        self.rt.block_on(self.http.get_guild(guild as u64)).ok()
+            .map(|g| ChatGuild {id: guild, name: g.name.clone(), icon: g.icon_url()})
+    }
+
+    fn text_channels(&self, guild: model::GuildId) -> Res<Vec<(i64, String)>> {
+        Ok(self.rt.block_on(self.http.get_channels(guild as u64))?.into_iter()
+            .filter(|c| c.kind == ChannelType::Text)
+            .map(|c| (c.id.0 as i64, c.name)).collect())
+    }
+
+    fn create_invite(&self, channel: i64) -> Res<String> {
+        let inv = self.rt.block_on(ChannelId(channel as u64).create_invite(&self.http, |x| x.max_age(0).temporary(true)))?;
+        Ok(inv.url())
+    }
+
+    fn can_manage(&self, guild: model::GuildId, user: model::UserId) -> Res<bool> {
+        let partial = self.rt.block_on(self.http.get_guild(guild as u64))?;
+        let member = self.rt.block_on(self.http.get_member(guild as u64, user as u64))?;
+        Ok(partial.member_permissions(&member).manage_guild())
+    }
+}
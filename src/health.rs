@@ -0,0 +1,122 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use iron::prelude::*;
+use iron::status;
+use serde_derive::Serialize;
+use serde_json;
+use serenity::client::bridge::gateway::ShardManager;
+use serenity::gateway::ConnectionStage;
+use serenity::prelude::Mutex;
+
+use log::*;
+
+use model::*;
+use metrics;
+
+const SHARD_POLL: Duration = Duration::from_secs(30);
+/// Due webhook deliveries past this mean the worker has fallen behind, which is reported but doesn't fail readiness
+const BACKLOG_LIMIT: i64 = 1000;
+
+#[derive(Clone, Serialize)]
+pub struct ShardStatus {
+    pub stage: String,
+    pub connected: bool,
+    pub latency_ms: Option<u64>
+}
+
+/// Written by the serenity side and read by the web server, keyed by shard id
+pub type Shards = Arc<RwLock<BTreeMap<u64, ShardStatus>>>;
+
+#[derive(Serialize)]
+struct Check {
+    ok: bool,
+    detail: String
+}
+
+impl Check {
+    fn new(ok: bool, detail: String) -> Self {
+        Check {ok, detail}
+    }
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    database: Check,
+    migrations: Check,
+    /// Informational, slow webhook receivers shouldn't take the bot out of rotation
+    delivery_backlog: Check,
+    /// Left out by ``web-only`` processes, which have no gateway connection
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shards: Option<BTreeMap<u64, ShardStatus>>
+}
+
+/// Shard runners only keep their last stage and heartbeat latency, so they're polled
+pub fn watch_shards(manager: Arc<Mutex<ShardManager>>, shards: Shards) {
+    thread::spawn(move || loop {
+        let statuses: BTreeMap<u64, ShardStatus> = manager.lock().runners.lock().iter().map(|(id, runner)| {
+            if let Some(latency) = runner.latency {
+                metrics::SHARD_LATENCY.with_label_values(&[&id.0.to_string()]).set(latency.as_secs_f64());
+            }
+
+            (id.0, ShardStatus {
+                stage: format!("{:?}", runner.stage), connected: runner.stage == ConnectionStage::Connected,
+                latency_ms: runner.latency.map(|l| l.as_millis() as u64)
+            })
+        }).collect();
+
+        *shards.write().unwrap() = statuses;
+        thread::sleep(SHARD_POLL);
+    });
+}
+
+fn json_response(status: status::Status, body: String) -> IronResult<Response> {
+    Ok(Response::with((status, "application/json".parse::<iron::mime::Mime>().unwrap(), body)))
+}
+
+/// Liveness only, answering at all means the web thread is up
+pub fn healthz() -> IronResult<Response> {
+    json_response(status::Ok, "{\"ok\":true}".to_owned())
+}
+
+fn migrations(pool: &ConnPool) -> Check {
+    match pool.migration_status() {
+        Ok((_, pending)) if pending.is_empty() => Check::new(true, "Up to date".to_owned()),
+        Ok((_, pending)) => Check::new(false, format!("Pending: {}", pending.join(", "))),
+        Err(err) => {
+            error!("Readiness check couldn't read migrations: {}", err);
+            Check::new(false, "Unavailable".to_owned())
+        }
+    }
+}
+
+pub fn readyz(pool: &ConnPool, shards: Option<&Shards>) -> IronResult<Response> {
+    let (database, delivery_backlog) = match pool.get_conn() {
+        Ok(mut conn) => (Check::new(true, "Connected".to_owned()), match WebhookDelivery::count_due(&mut conn) {
+            Ok(due) if due < BACKLOG_LIMIT => Check::new(true, format!("{} due", due)),
+            Ok(due) => Check::new(false, format!("{} due, the worker is behind", due)),
+            Err(err) => {
+                error!("Readiness check couldn't count webhook deliveries: {}", err);
+                Check::new(false, "Unavailable".to_owned())
+            }
+        }),
+        Err(err) => {
+            error!("Readiness check couldn't connect to the database: {}", err);
+            (Check::new(false, "Unavailable".to_owned()), Check::new(false, "Database unavailable".to_owned()))
+        }
+    };
+
+    let migrations = migrations(pool);
+    let shards = shards.map(|s| s.read().unwrap().clone());
+
+    let ready = database.ok && migrations.ok
+        && shards.as_ref().map_or(true, |s| !s.is_empty() && s.values().all(|s| s.connected));
+
+    let body = serde_json::to_string(&Readiness {ready, database, migrations, delivery_backlog, shards})
+        .map_err(|err| IronError::new(BottleError::from(err), status::InternalServerError))?;
+
+    json_response(if ready { status::Ok } else { status::ServiceUnavailable }, body)
+}
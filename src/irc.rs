@@ -0,0 +1,264 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use log::*;
+
+use model::*;
+use platform::{self, Bridge, Platform, Incoming, RenderedBottle};
+use bottle::BottleService;
+
+const DEFAULT_PORT: u16 = 6667;
+const RECONNECT: Duration = Duration::from_secs(30);
+const MAX_LINE: usize = 400; //leaves room for the prefix within irc's 512 bytes
+
+pub struct IrcBridge {
+    server: String,
+    writer: Mutex<TcpStream>
+}
+
+impl IrcBridge {
+    fn send_raw(&self, line: &str) -> Res<()> {
+        let line = line.replace(|c| c == '\r' || c == '\n', " ");
+        trace!("IRC >> {}", line);
+
+        let mut writer = self.writer.lock().unwrap();
+        writer.write_all(line.as_bytes())?;
+        writer.write_all(b"\r\n")?;
+        Ok(())
+    }
+
+    fn privmsg(&self, target: &str, text: &str) -> Res<()> {
+        self.send_raw(&format!("PRIVMSG {} :{}", target, text))
+    }
+
+    fn notice(&self, target: &str, text: &str) -> Res<()> {
+        self.send_raw(&format!("NOTICE {} :{}", target, text))
+    }
+
+    fn external(&self, target: &str) -> String {
+        format!("{}/{}", self.server, target)
+    }
+}
+
+fn truncate(text: &str) -> String {
+    if text.len() <= MAX_LINE {
+        return text.to_owned();
+    }
+
+    let mut end = MAX_LINE;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    format!("{}...", &text[..end])
+}
+
+/// IRC has no embeds, so bottles become a few lines with the reply depth as quote markers
+pub fn render(bottle: &RenderedBottle) -> Vec<String> {
+    if bottle.deleted {
+        return vec![format!("\x02{}\x02", bottle.title)];
+    }
+
+    let quote = ">".repeat(bottle.level);
+    let mut lines = vec![match bottle.origin {
+        Some(ref origin) => format!("{}\x02{}\x02 From {} in {}", quote, bottle.title, bottle.author, origin),
+        None => format!("{}\x02{}\x02 From {}", quote, bottle.title, bottle.author)
+    }];
+
+    lines.extend(bottle.contents.lines().filter(|l| !l.trim().is_empty())
+        .map(|l| format!("{} {}", quote, truncate(l))));
+
+    if let Some(ref url) = bottle.url {
+        lines.push(format!("{} Link: {}", quote, url));
+    }
+
+    if let Some(ref image) = bottle.image {
+        lines.push(format!("{} Image: {}", quote, image));
+    }
+
+    lines.push(format!("{} Report: {}", quote, bottle.report_url));
+    lines
+}
+
+impl Bridge for IrcBridge {
+    fn platform(&self) -> Platform {
+        Platform::Irc
+    }
+
+    fn send(&self, channel: &PlatformId, bottle: &RenderedBottle) -> Res<()> {
+        let target = channel.external.splitn(2, '/').nth(1).ok_or("Malformed IRC target")?;
+
+        for line in render(bottle) {
+            self.privmsg(target, &line)?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Line<'a> {
+    tags: Option<&'a str>,
+    nick: Option<&'a str>,
+    command: &'a str,
+    params: Vec<&'a str>
+}
+
+impl<'a> Line<'a> {
+    /// The services account the sender is logged into, from the IRCv3 ``account-tag``
+    fn account(&self) -> Option<&'a str> {
+        self.tags?.split(';').filter_map(|t| t.strip_prefix("account=")).find(|a| !a.is_empty() && *a != "*")
+    }
+}
+
+fn parse(line: &str) -> Option<Line> {
+    let (tags, line) = match line.strip_prefix('@') {
+        Some(x) => {
+            let mut split = x.splitn(2, ' ');
+            (split.next(), split.next()?)
+        },
+        None => (None, line)
+    };
+
+    let (prefix, rest) = match line.strip_prefix(':') {
+        Some(x) => {
+            let mut split = x.splitn(2, ' ');
+            (split.next(), split.next()?)
+        },
+        None => (None, line)
+    };
+
+    let (middle, trailing) = match rest.find(" :") {
+        Some(i) => (&rest[..i], Some(&rest[i + 2..])),
+        None => (rest, None)
+    };
+
+    let mut words = middle.split(' ').filter(|x| !x.is_empty());
+    let command = words.next()?;
+    let mut params: Vec<&str> = words.collect();
+    params.extend(trailing);
+
+    Some(Line {tags, nick: prefix.map(|p| p.split('!').next().unwrap_or(p)), command, params})
+}
+
+fn is_bottle(text: &str) -> bool {
+    let text = text.trim();
+    let text = text.strip_prefix(ANONYMOUS_PREFIX).unwrap_or(text);
+    text.starts_with(SEND_PREFIX) || text.starts_with(REPLY_PREFIX)
+}
+
+/// Bridged channels act as their own guild, so guild settings and delivery work unchanged
+fn join(bridge: &IrcBridge, name: &str, conn: &mut Conn) -> Res<()> {
+    let channel = platform::map_id(Platform::Irc, &bridge.external(name), name, conn)?;
+
+    let mut guild = Guild::get(channel.id, conn);
+    guild.bottle_channel = Some(channel.id);
+    guild.update(conn)?;
+
+    bridge.send_raw(&format!("JOIN {}", name))
+}
+
+/// Nicks can be taken by anyone, so senders are identified by their services account and must be logged in
+fn handle_message(bridge: &IrcBridge, sender: &str, account: Option<&str>, target: &str, text: &str, service: &BottleService) -> Res<()> {
+    if !is_bottle(text) {
+        return Ok(());
+    }
+
+    let account = match account {
+        Some(x) => x,
+        None => return bridge.notice(sender, "Please identify with services before sending bottles!")
+    };
+
+    let conn = &mut service.pool.get_conn()?;
+    let user = platform::map_id(Platform::Irc, &bridge.external(&format!("~{}", account)), sender, conn)?; //~ can't start a nick or channel
+    User::get(user.id, conn).update(conn)?;
+
+    let (channel, guild) = if target.starts_with('#') || target.starts_with('&') {
+        let channel = platform::map_id(Platform::Irc, &bridge.external(target), target, conn)?;
+        (channel.id, Some(channel.id))
+    } else {
+        //direct messages are delivered back to the sender's current nick
+        (platform::map_id(Platform::Irc, &bridge.external(sender), sender, conn)?.id, None)
+    };
+
+    let url = text.split_whitespace().find(|w| w.starts_with("https://") || w.starts_with("http://")).map(str::to_owned);
+    let incoming = Incoming {
+        platform: Platform::Irc, user: user.id, message: PlatformId::next(conn)?,
+        channel, content: text.to_owned(), url, image: None
+    };
+
+    match service.new_bottle(&incoming, guild, conn) {
+        Ok(Some(x)) => bridge.notice(sender, &x),
+        Err(x) => {
+            if x.internal() {
+                error!("Error handling IRC bottle from {}: {}", sender, x);
+            }
+
+            bridge.notice(sender, &x.user_message())
+        },
+        _ => Ok(())
+    }
+}
+
+fn run(server: &str, service: &BottleService) -> Res<()> {
+    let cfg = &service.cfg;
+    let addr = if server.contains(':') { server.to_owned() } else { format!("{}:{}", server, DEFAULT_PORT) };
+    let nick = cfg.irc.nick.clone().unwrap_or_else(|| "bottle".to_owned());
+
+    let stream = TcpStream::connect(&addr)?;
+    let bridge = Arc::new(IrcBridge {server: server.to_owned(), writer: Mutex::new(stream.try_clone()?)});
+
+    bridge.send_raw("CAP REQ :account-tag")?;
+    bridge.send_raw(&format!("NICK {}", nick))?;
+    bridge.send_raw(&format!("USER {} 0 * :Bottle", nick))?;
+    platform::register(bridge.clone());
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        trace!("IRC << {}", line);
+
+        let msg = match parse(&line) {
+            Some(x) => x,
+            None => continue
+        };
+
+        let res = match (msg.command, msg.params.as_slice()) {
+            ("CAP", [_, "ACK", ..]) | ("CAP", [_, "NAK", ..]) => bridge.send_raw("CAP END"),
+            ("PING", params) => bridge.send_raw(&format!("PONG :{}", params.first().unwrap_or(&""))),
+            ("001", _) => {
+                info!("Connected to IRC server {}", server);
+                service.pool.get_conn().and_then(|mut conn| cfg.irc.channels.iter().map(|c| join(&bridge, c, &mut conn)).collect())
+            },
+            ("PRIVMSG", [target, text]) => match msg.nick {
+                Some(sender) if sender != nick => handle_message(&bridge, sender, msg.account(), target, text, service),
+                _ => Ok(())
+            },
+            _ => Ok(())
+        };
+
+        if let Err(err) = res {
+            error!("Error handling IRC message: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Bridges to the configured IRC server, reconnecting whenever the connection drops
+pub fn start(service: BottleService) {
+    let server = match service.cfg.irc.server.clone() {
+        Some(x) => x,
+        None => return
+    };
+
+    thread::spawn(move || loop {
+        if let Err(err) = run(&server, &service) {
+            error!("IRC connection to {} failed: {}", server, err);
+        } else {
+            info!("IRC connection to {} closed", server);
+        }
+
+        thread::sleep(RECONNECT);
+    });
+}
@@ -0,0 +1,37 @@
+use std::thread;
+use std::time::{Duration, Instant};
+use log::*;
+
+use model::*;
+use shutdown;
+
+const POLL: Duration = Duration::from_secs(30);
+/// Erasing or resetting a user changes XP without an event, so the tables are rebuilt at least this often
+const MAX_AGE: Duration = Duration::from_secs(600);
+
+/// Rebuilds the leaderboards whenever new XP has been handed out since the last rebuild
+pub fn start_worker(pool: ConnPool) {
+    thread::spawn(move || {
+        let mut latest = None;
+        let mut refreshed: Option<Instant> = None;
+
+        while !shutdown::stopping() {
+            let result = pool.get_conn().and_then(|mut conn| {
+                let event = XpEvent::get_latest(&mut conn)?;
+                if event != latest || refreshed.map_or(true, |t| t.elapsed() >= MAX_AGE) {
+                    LeaderboardEntry::refresh(&mut conn)?;
+                    latest = event;
+                    refreshed = Some(Instant::now());
+                }
+
+                Ok(())
+            });
+
+            if let Err(err) = result {
+                error!("Error refreshing leaderboards: {}", err);
+            }
+
+            thread::sleep(POLL);
+        }
+    });
+}
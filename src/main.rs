@@ -3,18 +3,18 @@ extern crate chrono;
 extern crate r2d2;
 extern crate uuid;
 extern crate diesel;
-#[cfg(not(debug_assertions))]
 extern crate diesel_migrations;
 extern crate serde;
 extern crate serde_json;
 extern crate serde_derive;
 extern crate kankyo;
-extern crate envy;
+extern crate config;
 extern crate typemap;
 extern crate discord_bots;
 extern crate serenity;
 
-extern crate simple_logging;
+extern crate tracing;
+extern crate tracing_subscriber;
 extern crate log;
 
 extern crate iron;
@@ -27,97 +27,224 @@ extern crate oauth2;
 extern crate reqwest;
 extern crate cookie;
 extern crate futures_lite;
+extern crate sha2;
+extern crate hex;
+extern crate hmac;
+extern crate tokio;
+extern crate prometheus;
+extern crate signal_hook;
+extern crate clap;
 
 pub mod schema;
+pub mod error;
+pub mod settings;
+pub mod cli;
 pub mod data;
 #[macro_use]
 pub mod model;
 pub mod web;
+pub mod api;
 pub mod bottle;
+pub mod webhook;
+pub mod leaderboard;
+pub mod platform;
+pub mod irc;
+pub mod gateway;
+pub mod profiles;
+pub mod metrics;
+pub mod health;
+pub mod shutdown;
+#[cfg(test)]
+mod testing;
 
 use std::thread;
 use std::fs::File;
 use std::sync::Arc;
+use std::borrow::Cow;
 use log::*;
-use diesel::pg::PgConnection;
-use diesel::r2d2::ConnectionManager;
+use clap::Parser;
+use futures_lite::future::Boxed;
 
 use serenity::prelude::*;
-use serenity::framework::standard::{CommandError, DispatchError, StandardFramework};
+use serenity::framework::standard::{Args, CommandError, DispatchError, StandardFramework};
 use serenity::model::channel::{Message, Channel, Reaction};
 use serenity::model::gateway;
 use serenity::model::permissions::Permissions;
 
 use model::*;
 use model::id::*;
+use platform::{Incoming, Reacted};
+use bottle::BottleService;
+use gateway::{DiscordGateway, HttpGateway};
+use profiles::CachedGateway;
 
 const ADMIN_PERM: Permissions = Permissions::ADMINISTRATOR;
 
-fn update_guilds(ctx: &Context) {
+async fn update_guilds(ctx: &Context) {
     let guild_count = {
         cache.read().all_guilds().len()
     };
 
+    let bots = ctx.data.read().await.get::<DBots>().unwrap().clone();
     let stats = discord_bots::PostBotStats::new(discord_bots::ServerCount::Single(guild_count));
-    let _ = ctx.get_bots().post_stats(stats);
+    let _ = tokio::task::spawn_blocking(move || bots.post_stats(stats)).await;
+}
+
+/// Runs command bodies like the event handlers, on a blocking thread with the service and a connection.
+/// Bodies can use ``Res``, only the user-facing half of an error is replied
+fn command<F>(f: F) -> impl Fn(&Context, &Message, Args) -> Boxed<Result<(), CommandError>> + Send + Sync + 'static
+    where F: Fn(&BottleService, &mut Conn, &Message, Args) -> Res<()> + Clone + Send + Sync + 'static {
+    move |ctx, msg, args| {
+        let (ctx, msg, f) = (ctx.clone(), msg.clone(), f.clone());
+
+        Box::pin(async move {
+            let content = msg.content.clone();
+            DService::get(&ctx).await.run(move |service, conn| f(service, conn, &msg, args)).await.map_err(|err| {
+                if err.internal() {
+                    error!("Error running command \"{}\": {}", content, err);
+                }
+
+                CommandError(err.user_message())
+            })
+        })
+    }
+}
+
+fn guild_of(msg: &Message) -> Res<model::GuildId> {
+    msg.guild_id.map(|gid| gid.as_i64()).ok_or_else(|| BottleError::Permission("This only works in guilds!".to_owned()))
+}
+
+fn guild_channel_of(msg: &Message) -> Res<Arc<RwLock<serenity::model::channel::GuildChannel>>> {
+    msg.channel().and_then(Channel::guild).ok_or(BottleError::NotFound("guild channel"))
+}
+
+/// Shared by ``-webhook`` and ``-globalwebhook``, global hooks have no guild
+fn webhook_command(guild: Option<model::GuildId>, msg: &Message, mut args: Args, conn: &mut Conn) -> Res<()> {
+    match args.single::<String>().ok().as_ref().map(String::as_str) {
+        Some("add") => {
+            let url = args.single::<String>().map_err(|_| BottleError::Invalid("Please specify a URL to deliver to!".to_owned()))?;
+            webhook::check_url(&url)?;
+
+            let mut events = 0;
+            for name in args.iter::<String>().filter_map(Result::ok) {
+                events |= match name.as_str() {
+                    "all" => webhook::ALL_EVENTS,
+                    _ => webhook::Event::parse(&name).map(webhook::Event::bit)
+                        .ok_or_else(|| BottleError::Invalid(format!("Unknown event {}, choose from {}", name, webhook::describe_events(webhook::ALL_EVENTS))))?
+                };
+            }
+
+            if events == 0 {
+                events = webhook::ALL_EVENTS;
+            }
+
+            if let Some(gid) = guild {
+                Guild::get(gid, conn).update(conn)?;
+            }
+
+            let secret = webhook::make_secret();
+            let hook = MakeWebhook {guild, url, secret: secret.clone(), events, created: now()}.make(conn)?;
+
+            msg.author.direct_message(|m| m.content(&format!("Webhook {} created. Payloads are signed with HMAC-SHA256 in the ``X-Bottle-Signature`` header using the secret ``{}``", hook.id, secret)))?;
+            msg.reply(&format!("Added webhook {} for {}, check your DMs for the signing secret!", hook.id, webhook::describe_events(events)))?;
+        },
+
+        Some("list") => {
+            let hooks = Webhook::get_from_guild(guild, conn)?;
+            if hooks.is_empty() {
+                msg.reply("No webhooks yet, add one with ``add <url> [events...]``")?;
+            } else {
+                let list = hooks.iter().map(|h| format!("{}: <{}> ({})", h.id, h.url, webhook::describe_events(h.events)))
+                    .collect::<Vec<_>>().join("\n");
+                msg.reply(&list)?;
+            }
+        },
+
+        Some("remove") => {
+            let id = args.single::<WebhookId>().map_err(|_| BottleError::Invalid("Please specify the id of the webhook to remove!".to_owned()))?;
+
+            if Webhook::del(id, guild, conn)? == 0 {
+                return Err(BottleError::NotFound("webhook with that id"));
+            }
+
+            msg.reply("Webhook removed!")?;
+        },
+
+        _ => return Err(BottleError::Invalid("Please use add <url> [events...|all], list or remove <id>!".to_owned()))
+    }
+
+    Ok(())
 }
 
 struct Handler;
 impl EventHandler for Handler {
     async fn message(&self, ctx: Context, new_message: Message) {
-        if !new_message.author.bot {
-            let conn = ctx.get_conn();
+        if new_message.author.bot {
+            return;
+        }
 
-            let res = match new_message.channel() {
-                Some(Channel::Guild(ref channel)) => {
-                    let channel = channel.read();
-                    let gid = channel.guild_id.as_i64();
-                    debug!("New message in guild {}, looking at db....", gid);
-                    let guilddata = Guild::get(gid, &conn);
+        let incoming = Incoming::from_discord(&new_message);
+        let guild = new_message.guild_id.map(|gid| gid.as_i64());
 
-                    if Some(channel.id.as_i64()) == guilddata.bottle_channel {
-                        bottle::new_bottle(&new_message, Some(gid), ctx.get_pool(), ctx.get_cfg())
+        let res = DService::get(&ctx).await.run(move |service, conn| {
+            match guild {
+                Some(gid) => {
+                    debug!("New message in guild {}, looking at db....", gid);
+                    if Some(incoming.channel) == Guild::get(gid, conn).bottle_channel {
+                        Ok(service.new_bottle(&incoming, Some(gid), conn)?.map(Cow::into_owned))
                     } else {
                         Ok(None)
                     }
                 },
 
-                Some(Channel::Private(_)) => bottle::new_bottle(&new_message, None, ctx.get_pool(), ctx.get_cfg()),
-                _ => Ok(None)
-            };
-
-            match res {
-                Ok(Some(x)) => new_message.reply(&*x).ok(),
-                Err(x) => new_message.reply(x.description()).ok(),
-                _ => None
-            };
-        }
+                None => Ok(service.new_bottle(&incoming, None, conn)?.map(Cow::into_owned))
+            }
+        }).await;
+
+        match res {
+            Ok(Some(x)) => new_message.reply(&x).ok(),
+            Err(x) => {
+                if x.internal() {
+                    error!("Error handling message {}: {}", new_message.id, x);
+                }
+
+                new_message.reply(&x.user_message()).ok()
+            },
+            _ => None
+        };
     }
 
     async fn message_delete(&self, ctx: Context, _channel: serenity::model::id::ChannelId, deleted_msg_id: serenity::model::id::MessageId) {
         debug!("Message {} deleted, checking db...", deleted_msg_id);
-        let conn = ctx.get_conn();
-        if let Ok(x) = Bottle::get_from_message(deleted_msg_id.as_i64(), &mut conn) {
-            bottle::del_bottle(x, conn, &ctx.get_cfg()).unwrap();
+
+        let res = DService::get(&ctx).await.run(move |service, conn| {
+            match Bottle::get_from_message(deleted_msg_id.as_i64(), conn) {
+                Ok(x) => service.del_bottle(x, conn),
+                Err(_) => Ok(())
+            }
+        }).await;
+
+        if let Err(err) = res {
+            error!("Error deleting bottle of message {}: {}", deleted_msg_id, err);
         }
     }
 
-    fn reaction_add(&self, ctx: Context, r: Reaction) {
-        let conn = ctx.get_conn();
-        bottle::react(&mut conn, r, true, &ctx.get_cfg()).unwrap();
+    async fn reaction_add(&self, ctx: Context, r: Reaction) {
+        react(ctx, r, true).await
     }
 
-    fn reaction_remove(&self, ctx: Context, r: Reaction) {
-        let conn = ctx.get_conn();
-        bottle::react(&mut conn, r, false, &ctx.get_cfg()).unwrap();
+    async fn reaction_remove(&self, ctx: Context, r: Reaction) {
+        react(ctx, r, false).await
     }
 
-    fn guild_create(&self, ctx: Context, guild: serenity::model::guild::Guild, is_new: bool) {
-        let conn = ctx.get_conn();
-        let guilddata = Guild::get(guild.id.as_i64(), &mut conn);
-        let user = cache.read().user.id.clone();
+    async fn guild_create(&self, ctx: Context, guild: serenity::model::guild::Guild, is_new: bool) {
+        let gid = guild.id.as_i64();
+        if let Err(err) = DService::get(&ctx).await.run(move |_, conn| Ok(Guild::get(gid, conn).update(conn)?)).await {
+            error!("Error saving guild {}: {}", gid, err);
+        }
 
         if is_new {
+            let user = cache.read().user.id.clone();
             let general = guild.channels.iter()
                 .find(|&(channelid, _)| guild.permissions_in(channelid, user).send_messages());
 
@@ -127,89 +254,187 @@ impl EventHandler for Handler {
             }
         }
 
-        guilddata.update(&mut conn).unwrap();
-        update_guilds(&ctx);
+        update_guilds(&ctx).await;
         info!("Gained guild {}.", &guild.name)
     }
 
-    fn guild_delete (&self, ctx: Context, incomplete: serenity::model::guild::PartialGuild, _full: Option<Arc<RwLock<serenity::model::guild::Guild>>>) {
-        Guild::del(incomplete.id.as_i64(), &ctx.get_conn()).unwrap();
+    async fn guild_delete (&self, ctx: Context, incomplete: serenity::model::guild::UnavailableGuild, _full: Option<Arc<RwLock<serenity::model::guild::Guild>>>) {
+        if incomplete.unavailable { //an outage, the bot is still in the guild
+            warn!("Guild {} became unavailable", incomplete.id);
+            return;
+        }
+
+        let gid = incomplete.id.as_i64();
+        if let Err(err) = DService::get(&ctx).await.run(move |_, conn| Ok(Guild::erase(gid, conn)?)).await {
+            error!("Error erasing guild {}: {}", gid, err);
+        }
 
-        update_guilds(&ctx);
+        update_guilds(&ctx).await;
         info!("Guild lost.")
     }
 
-    fn ready(&self, ctx:Context, _data_about_bot: serenity::model::gateway::Ready) {
+    async fn ready(&self, ctx:Context, _data_about_bot: serenity::model::gateway::Ready) {
         ctx.set_presence(Some(gateway::Game {kind: gateway::GameType::Listening, name: "you, try -help".to_owned(), url: None})
                          , serenity::model::user::OnlineStatus::Online);
 
-        let conn = &ctx.get_conn();
-        let mut u = User::get(ctx.get_cfg().auto_admin, conn);
-        u.admin = true;
-        let _ = u.update(conn);
+        let res = DService::get(&ctx).await.run(|service, conn| {
+            let mut u = User::get(service.cfg.discord.auto_admin, conn);
+            u.admin = true;
+            Ok(u.update(conn)?)
+        }).await;
+
+        if let Err(err) = res {
+            error!("Error promoting the auto admin: {}", err);
+        }
 
         info!("Client is ready");
     }
 }
 
-#[cfg(not(debug_assertions))]
-embed_migrations!("migrations/");
+/// Reactions from bots and custom emoji are dropped before touching the database
+async fn react(ctx: Context, r: Reaction, add: bool) {
+    let r = match Reacted::from_discord(&r) {
+        Ok(Some(r)) => r,
+        Ok(None) => return,
+        Err(err) => return error!("Error reading reaction: {}", err)
+    };
+
+    if let Err(err) = DService::get(&ctx).await.run(move |service, conn| service.react(&r, add, conn)).await {
+        error!("Error handling reaction: {}", err);
+    }
+}
 
 #[cfg(not(debug_assertions))]
 fn do_migrations(db: &ConnPool) {
-    embedded_migrations::run_with_output(&db.get_conn(), &mut std::io::stdout()).unwrap();
+    db.run_migrations().expect("Error running migrations.");
 }
 
 #[cfg(debug_assertions)]
 fn do_migrations(_: &ConnPool) { }
 
+/// JSON lines on stderr, ``log`` records from us and our dependencies are forwarded into the same output
+fn init_logging(cfg: &Config) {
+    let default = if cfg.logging.debug { "debug" } else { "error" };
+    let filter = tracing_subscriber::EnvFilter::try_new(cfg.logging.filter.as_deref().unwrap_or(default))
+        .expect("Error parsing logging.filter.");
+
+    tracing_subscriber::fmt().json().with_current_span(true).with_span_list(true)
+        .with_env_filter(filter).with_writer(std::io::stderr).init();
+}
+
 fn main() {
-    kankyo::load_from_reader(&mut File::open("./.env").unwrap()).unwrap();
-    let config:Config = envy::from_env::<Config>().unwrap();
-    let manager = ConnectionManager::<PgConnection>::new(config.clone().database_url);
-    let db = r2d2::Pool::builder().build(manager).expect("Error initializing connection pool.");
+    let cli = cli::Cli::parse();
+
+    //a .env is still picked up for the BOTTLE_ overrides, but no longer needed
+    if let Ok(mut env) = File::open("./.env") {
+        let _ = kankyo::load_from_reader(&mut env);
+    }
+
+    let config = match settings::load(&cli.config) {
+        Ok(config) => config,
+        Err(errs) => {
+            eprint!("{}", errs);
+            std::process::exit(1);
+        }
+    };
+
+    if cli.check_config {
+        println!("Configuration is valid.");
+        return;
+    }
+
+    init_logging(&config);
+
+    let (web, bot) = match cli.command.unwrap_or(cli::Command::Run) {
+        cli::Command::Run => (true, true),
+        cli::Command::WebOnly => (true, false),
+        cli::Command::BotOnly => (false, true),
+        command => {
+            if let Err(err) = cli::maintain(command, &config) {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+
+            return;
+        }
+    };
+
+    run(config, web, bot);
+}
+
+/// The web server and the bot can run in separate processes, background workers go with the bot
+/// so that only one process delivers webhooks and resumes pending bottles
+fn run(config: Config, web: bool, bot: bool) {
+    let db = ConnPool::connect(&config.database.url).expect("Error initializing connection pool.");
 
     do_migrations(&db);
 
-    let log_level = if config.debug_log { log::LevelFilter::Debug }
-        else { log::LevelFilter::Error };
+    platform::load_names(&mut db.get_conn().expect("Error connecting to the database.")).expect("Error loading bridged names.");
+    let shards = health::Shards::default();
+
+    //without the gateway cache the web pages use their own REST client, and readiness doesn't wait on shards
+    if !bot {
+        let gateway = Arc::new(CachedGateway::new(Arc::new(HttpGateway::new(&config.discord.token)), db.clone(), &config.cache));
+        gateway.clone().watch();
+        let webservice = BottleService::new(gateway, db.clone(), config.clone());
+        thread::spawn( move || web::start_serv(webservice, None));
+        platform::refresh_names(db.clone());
+
+        shutdown::handle_signals(|| ());
+        shutdown::wait();
+        shutdown::stop();
+        return;
+    }
 
-    simple_logging::log_to_stderr(log_level);
+    let gateway = Arc::new(CachedGateway::new(Arc::new(DiscordGateway), db.clone(), &config.cache));
+    gateway.clone().watch();
+    let service = BottleService::new(gateway, db.clone(), config.clone());
+    if web {
+        let webservice = service.clone();
+        let webshards = shards.clone();
+        thread::spawn( move || web::start_serv(webservice, Some(webshards)));
+    }
 
-    let webdb = db.clone(); let webcfg = config.clone();
-    thread::spawn( move || web::start_serv(webdb, webcfg));
+    webhook::start_worker(db.clone());
+    leaderboard::start_worker(db.clone());
+    irc::start(service.clone());
 
-    let dbots = Arc::new(discord_bots::Client::new(&config.discord_bots_token));
+    if let Err(err) = service.resume_deliveries() {
+        error!("Error resuming pending deliveries: {}", err);
+    }
+
+    let dbots = Arc::new(discord_bots::Client::new(&config.discord.discord_bots_token));
 
-    let mut client = Client::new(&config.token, Handler).expect("Error initializing client.");
+    let mut client = Client::new(&config.discord.token, Handler).expect("Error initializing client.");
     client.data.lock().insert::<DBots>(dbots);
-    client.data.lock().insert::<DConn>(db.clone());
-    client.data.lock().insert::<DConfig>(config.clone());
+    client.data.lock().insert::<DService>(service);
 
     client.with_framework(StandardFramework::new()
         .configure(|c| c.on_mention(true)
             .prefix("-").dynamic_prefix(|ctx, msg| {
-            let conn = &ctx.get_conn();
+            let (ctx, guild) = (ctx.clone(), msg.guild_id.map(|gid| gid.as_i64()));
 
-            msg.guild_id.and_then(|gid| Guild::get(gid.as_i64(), conn).prefix)
+            Box::pin(async move {
+                let gid = guild?;
+                DService::get(&ctx).await.run(move |_, conn| Ok(Guild::get(gid, conn).prefix)).await.ok().flatten()
+            })
         }))
         .help(|_f, msg, _opts, _cmds, _args | {
-              msg.reply ("Set a bottle channel with ``-configure <channel>``, then start sending out and replying (prefix your message with ``->`` to bottles there! Or dm me for anonymous bottles! :^) Also try ``-info``")?;
+              msg.reply ("Set a bottle channel with ``-configure <channel>``, then start sending out and replying (prefix your message with ``->`` to bottles there! Or dm me for anonymous bottles, or put a ``?`` before the prefix if your guild allows it! :^) Also try ``-info``")?;
 
               Ok(())
         })
         .command("configure", |c|
             c.required_permissions(ADMIN_PERM)
                 .guild_only(true)
-                .exec(| ctx, msg, mut args: serenity::framework::standard::Args | {
-                    let conn = &ctx.get_conn();
-                    let mut guild = Guild::get(msg.guild_id.unwrap().as_i64(), &conn);
+                .exec(command(|service, conn, msg, mut args| {
+                    let mut guild = Guild::get(guild_of(msg)?, conn);
 
                     if let Ok(chan) = args.find::<serenity::model::channel::Channel>() {
                         guild.bottle_channel = Some(chan.id().as_i64());
                         guild.update(conn)?;
 
-                        msg.reply("All set!")?;
+                        msg.reply(&format!("All set! More settings are at {}", guild_settings_url(guild.id, &service.cfg)))?;
                         Ok(())
                     } else if let Ok(x) = args.find::<char>() {
                         guild.prefix = Some(x.to_string());
@@ -219,23 +444,95 @@ fn main() {
 
                         Ok(())
                     } else {
-                        Err("Please specify a valid channel or a single character prefix!".into())
+                        Err(BottleError::Invalid("Please specify a valid channel or a single character prefix!".to_owned()))
                     }
-                })
+                }))
+        )
+        .command("anonymous", |c|
+            c.required_permissions(ADMIN_PERM)
+                .guild_only(true)
+                .exec(command(|_, conn, msg, _args| {
+                    let mut guild = Guild::get(guild_of(msg)?, conn);
+
+                    guild.allow_anonymous = !guild.allow_anonymous;
+                    guild.update(conn)?;
+
+                    if guild.allow_anonymous {
+                        msg.reply("Anonymous bottles are now allowed! Prefix a bottle with ``?`` to send it anonymously.")?;
+                    } else {
+                        msg.reply("Anonymous bottles are no longer allowed.")?;
+                    }
+
+                    Ok(())
+                }))
+        )
+        .command("privacy", |c|
+            c.exec(command(|service, conn, msg, mut args| {
+                let mut privacy = Privacy::get(msg.author.id.as_i64(), conn);
+
+                if let Ok(setting) = args.single::<String>() {
+                    let hidden = match setting.as_str() {
+                        "profile" => &mut privacy.hide_profile,
+                        "bottles" => &mut privacy.hide_bottles,
+                        "contributions" => &mut privacy.hide_contributions,
+                        "leaderboard" => &mut privacy.hide_leaderboard,
+                        _ => return Err(BottleError::Invalid("Please specify profile, bottles, contributions or leaderboard!".to_owned()))
+                    };
+
+                    *hidden = !*hidden;
+                    User::get(privacy.user, conn).update(conn)?;
+                    privacy.update(conn)?;
+                }
+
+                msg.reply(&format!("Toggle a setting with ``-privacy <setting>`` or at {}\n{}", settings_url(&service.cfg), privacy.describe()))?;
+                Ok(())
+            }))
+        )
+        .command("export", |c|
+            c.exec(command(|_, conn, msg, _args| {
+                let export = User::get(msg.author.id.as_i64(), conn).export(conn)?;
+                let json = serde_json::to_vec_pretty(&export)?;
+
+                msg.author.direct_message(|m| m.content("Here's everything Bottle stores about you!")
+                    .add_file((json.as_slice(), "bottle-export.json")))?;
+
+                if !msg.is_private() {
+                    msg.reply("Check your DMs!")?;
+                }
+
+                Ok(())
+            }))
+        )
+        .command("deleteaccount", |c|
+            c.dm_only(true).exec(command(|service, conn, msg, mut args| {
+                if args.single::<String>().ok().as_ref().map(String::as_str) != Some("confirm") {
+                    msg.reply("This permanently erases your bottles, XP, contributions and settings, and removes your bottles everywhere they were delivered. \
+                        Run ``-deleteaccount confirm`` if you're sure!")?;
+
+                    return Ok(());
+                }
+
+                service.erase_user(msg.author.id.as_i64(), conn)?;
+                msg.reply("Your account has been erased. Goodbye, sailor!")?;
+                Ok(())
+            }))
         )
         .group("Auto Admin Commands", |g|
             g.check(|ctx, msg, _args, _opts| {
-                if ctx.get_cfg().auto_admin != msg.author.id.as_i64() {
-                    let _ = msg.reply("You must be an auto admin to do this!");
-                    false
-                } else { true }
+                let (ctx, msg) = (ctx.clone(), msg.clone());
+
+                Box::pin(async move {
+                    if DService::get(&ctx).await.cfg.discord.auto_admin != msg.author.id.as_i64() {
+                        let _ = msg.reply("You must be an auto admin to do this!");
+                        false
+                    } else { true }
+                })
             })
             .command("mote", |c|
-                c.exec(|ctx, msg, mut args| {
+                c.exec(command(|_, conn, msg, mut args| {
                     let usr = args.single::<serenity::model::user::User>()
-                        .map_err(|_| "Please specify a user to promote.")?;
+                        .map_err(|_| BottleError::Invalid("Please specify a user to promote.".to_owned()))?;
 
-                    let conn = &ctx.get_conn();
                     let mut u = User::get(usr.id.as_i64(), conn);
                     if !u.admin {
                         u.admin = true;
@@ -247,13 +544,12 @@ fn main() {
 
                     u.update(conn)?;
                     Ok(())
-                })
+                }))
             )
             .command("announce", |c|
-                c.exec(|ctx, msg, args| {
+                c.exec(command(|_, conn, msg, args| {
                     let announcement = args.rest();
 
-                    let conn = &ctx.get_conn();
                     for x in cache.read().all_guilds() {
                         if let Some(c) = Guild::get(x.as_i64(), conn).bottle_channel {
                             let cid = serenity::model::id::ChannelId(c as u64);
@@ -264,17 +560,16 @@ fn main() {
                     info!("{} sent {} to all guilds!", msg.author.tag(), announcement);
                     msg.reply("Sent to all guilds!")?;
                     Ok(())
-                })
+                }))
             )
         )
         .command("info", |c|
-            c.guild_only(true).exec(|ctx, msg, _args| {
-                let conn = &ctx.get_conn();
-                let gdata = Guild::get(msg.guild_id.unwrap().as_i64(), conn);
+            c.guild_only(true).exec(command(|service, conn, msg, _args| {
+                let gdata = Guild::get(guild_of(msg)?, conn);
                 let gdata_xp = gdata.get_xp(conn)?;
 
-                let guild_channel = msg.channel().unwrap().guild().unwrap();
-                let guild = guild_channel.read().guild().unwrap();
+                let guild_channel = guild_channel_of(msg)?;
+                let guild = guild_channel.read().guild().ok_or(BottleError::NotFound("guild"))?;
 
                 guild_channel.read().send_message(|msg| msg.embed(|embed| {
                     let public = match gdata.invite.as_ref() {
@@ -292,27 +587,42 @@ fn main() {
                         .field("XP", gdata_xp, true)
                         .field("Bottle channel", bottle_channel, true)
                         .field("Public", public, true)
+                        .field("Anonymous bottles", if gdata.allow_anonymous { "Allowed" } else { "Use -anonymous to allow" }, true)
 
-                        .url(guild_url(gdata.id, &ctx.get_cfg()))
+                        .url(guild_url(gdata.id, &service.cfg))
                 }))?;
 
                 Ok(())
-            })
+            }))
+        )
+        .command("webhook", |c|
+            c.guild_only(true).required_permissions(ADMIN_PERM)
+                .exec(command(|_, conn, msg, args| {
+                webhook_command(Some(guild_of(msg)?), msg, args, conn)
+            }))
+        )
+        .command("globalwebhook", |c|
+            c.exec(command(|_, conn, msg, args| {
+                if !User::get(msg.author.id.as_i64(), conn).admin {
+                    return Err(BottleError::Permission("You must be an admin to do this!".to_owned()));
+                }
+
+                webhook_command(None, msg, args, conn)
+            }))
         )
         .command("publicize", |c|
             c.guild_only(true).required_permissions(ADMIN_PERM)
-                .exec(|ctx, msg, _args| {
-                let conn = &ctx.get_conn();
-                let mut gdata = Guild::get(msg.guild_id.unwrap().as_i64(), conn);
+                .exec(command(|_, conn, msg, _args| {
+                let mut gdata = Guild::get(guild_of(msg)?, conn);
 
-                let guildc = msg.channel().unwrap().guild().unwrap();
+                let guildc = guild_channel_of(msg)?;
                 let inv = guildc.read().create_invite(|x| x.max_age(0).temporary(true))?;
                 gdata.invite = Some(inv.url());
                 gdata.update(conn)?;
 
                 msg.reply("Guild publicized!")?;
                 Ok(())
-            })
+            }))
         )
 
         .on_dispatch_error(| _ctx, msg, err | {
@@ -330,5 +640,12 @@ fn main() {
         })
     );
 
-    client.start_autosharded().unwrap();
+    health::watch_shards(client.shard_manager.clone(), shards);
+    shutdown::stop_shards_on_signal(client.shard_manager.clone());
+
+    if let Err(err) = client.start_autosharded() {
+        error!("Client error: {}", err);
+    }
+
+    shutdown::stop();
 }
\ No newline at end of file
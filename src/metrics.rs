@@ -0,0 +1,57 @@
+use std::sync::LazyLock;
+
+use iron::prelude::*;
+use iron::status;
+use prometheus::{self, Encoder, TextEncoder, IntCounter, IntCounterVec, IntGaugeVec, Histogram, GaugeVec};
+use prometheus::{register_int_counter, register_int_counter_vec, register_int_gauge_vec, register_histogram, register_gauge_vec};
+
+use model::*;
+
+pub static BOTTLES_SENT: LazyLock<IntCounterVec> = LazyLock::new(||
+    register_int_counter_vec!("bottle_bottles_sent_total", "Bottles cast away, by prefix", &["prefix"]).unwrap());
+
+pub static REJECTIONS: LazyLock<IntCounterVec> = LazyLock::new(||
+    register_int_counter_vec!("bottle_rejections_total", "Bottles turned away before sending, by reason", &["reason"]).unwrap());
+
+pub static DELIVERIES: LazyLock<IntCounter> = LazyLock::new(||
+    register_int_counter!("bottle_deliveries_total", "Channels a bottle was delivered to or attempted").unwrap());
+
+pub static DELIVERY_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(||
+    register_int_counter_vec!("bottle_delivery_failures_total", "Failed deliveries, by error class", &["class"]).unwrap());
+
+pub static RENDER_SECONDS: LazyLock<Histogram> = LazyLock::new(||
+    register_histogram!("bottle_render_seconds", "Time to render a bottle and post or edit it").unwrap());
+
+pub static FANOUT: LazyLock<Histogram> = LazyLock::new(||
+    register_histogram!("bottle_distribution_fanout", "Channels each bottle is distributed to",
+        vec![0.0, 1.0, 2.0, 3.0, 5.0, 8.0, 13.0, 21.0]).unwrap());
+
+pub static REPORTS: LazyLock<IntCounter> = LazyLock::new(||
+    register_int_counter!("bottle_reports_total", "Bottles reported to the admins").unwrap());
+
+pub static BANS: LazyLock<IntCounter> = LazyLock::new(||
+    register_int_counter!("bottle_bans_total", "Users banned").unwrap());
+
+pub static PROFILE_LOOKUPS: LazyLock<IntCounterVec> = LazyLock::new(||
+    register_int_counter_vec!("bottle_profile_lookups_total", "Discord user and guild lookups, by cache result", &["result"]).unwrap());
+
+static POOL: LazyLock<IntGaugeVec> = LazyLock::new(||
+    register_int_gauge_vec!("bottle_db_pool_connections", "Database pool connections, by state", &["state"]).unwrap());
+
+pub static SHARD_LATENCY: LazyLock<GaugeVec> = LazyLock::new(||
+    register_gauge_vec!("bottle_shard_latency_seconds", "Gateway heartbeat latency, by shard", &["shard"]).unwrap());
+
+/// Pool usage is read when scraped rather than tracked on every checkout
+pub fn serve(pool: &ConnPool) -> IronResult<Response> {
+    let state = pool.state();
+    POOL.with_label_values(&["idle"]).set(state.idle_connections as i64);
+    POOL.with_label_values(&["in_use"]).set((state.connections - state.idle_connections) as i64);
+    POOL.with_label_values(&["max"]).set(pool.max_size() as i64);
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    encoder.encode(&prometheus::gather(), &mut body)
+        .map_err(|err| IronError::new(BottleError::Other(err.to_string()), status::InternalServerError))?;
+
+    Ok(Response::with((status::Ok, encoder.format_type().parse::<iron::mime::Mime>().unwrap(), body)))
+}
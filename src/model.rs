@@ -6,30 +6,59 @@ pub use std::error::Error;
 use uuid::Uuid;
 use diesel::prelude::*;
 use diesel::r2d2::ConnectionManager;
-use r2d2::{Pool, PooledConnection};
+use r2d2::Pool;
 use serde_derive::{Serialize, Deserialize};
 use std::sync::Arc;
 
 use super::schema::*;
+use super::bottle::BottleService;
+use super::data::Repo;
+pub use super::error::BottleError;
+pub use super::settings::Config;
 
 pub const SEND_PREFIX: &str = ">";
 pub const REPLY_PREFIX: &str = "->";
 pub const BRANCH_REPLY_PREFIX: &str = "->>";
+pub const ANONYMOUS_PREFIX: &str = "?";
 
 pub enum Prefix {
     SendPrefix, ReplyPrefix, BranchReplyPrefix
 }
 
+impl Prefix {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Prefix::SendPrefix => "send",
+            Prefix::ReplyPrefix => "reply",
+            Prefix::BranchReplyPrefix => "branch_reply"
+        }
+    }
+}
+
 pub const PUSHXP: i32 = 15;
 pub const REPLYXP: i32 = 65;
 pub const URLXP: i32 = 2;
 pub const IMAGEXP: i32 = 6;
 pub const REPORTXP: i32 = 20;
 pub const COOLDOWN: i64 = 1;
+pub const DELIVERNUM: i64 = 4;
+
+pub const BOTTLE_REASON: &str = "bottle";
+pub const REPLY_REASON: &str = "reply";
+pub const REPORT_REASON: &str = "report";
 pub const MAX_TICKETS: i32 = 5;
+pub const SESSION_DAYS: i64 = 30;
+pub const API_RATE_LIMIT: i32 = 60;
+pub const POOL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
 
-pub type ConnPool = Pool<ConnectionManager<PgConnection>>;
-pub type Conn = PooledConnection<ConnectionManager<PgConnection>>;
+/// Chosen from the database url, see ``ConnPool::connect``
+#[derive(Clone)]
+pub enum ConnPool {
+    Postgres(Pool<ConnectionManager<PgConnection>>),
+    Sqlite(Pool<ConnectionManager<SqliteConnection>>)
+}
+
+pub type Conn = Box<dyn Repo>;
 pub type DTime = chrono::NaiveDateTime;
 
 pub type BottleId = i64;
@@ -38,6 +67,7 @@ pub type UserId = i64;
 pub type ReceivedBottleId = i64;
 pub type GuildContributionId = (GuildId, UserId);
 pub type ReportId = i64;
+pub type WebhookId = i64;
 
 #[derive(Insertable, AsChangeset, Clone)]
 #[table_name="bottle"]
@@ -53,20 +83,21 @@ pub struct MakeBottle {
     pub url: Option<String>,
     pub image: Option<String>,
 
-    pub channel: i64
+    pub channel: i64,
+    pub anonymous: bool,
+    pub platform: i16
 }
 
-#[derive(Queryable, Insertable, AsChangeset, Identifiable, Clone)]
+#[derive(Queryable, Insertable, AsChangeset, Identifiable, Serialize, Clone)]
 #[table_name="user"]
 pub struct User {
     pub id: UserId,
-    pub session: Option<Uuid>,
     pub xp: i32,
     pub admin: bool,
     pub tickets: i32
 }
 
-#[derive(Queryable, Associations, Identifiable, Clone, Debug)]
+#[derive(Queryable, Associations, Identifiable, Serialize, Clone, Debug)]
 #[table_name="bottle"]
 #[belongs_to(User, foreign_key="user")]
 #[belongs_to(Bottle, foreign_key="reply_to")]
@@ -84,7 +115,11 @@ pub struct Bottle {
     pub image: Option<String>,
 
     pub channel: i64,
-    pub deleted: bool
+    pub deleted: bool,
+
+    pub anonymous: bool,
+    pub pseudonym: Option<i32>,
+    pub platform: i16
 }
 
 #[derive(Queryable, Insertable, AsChangeset, Identifiable, Debug)]
@@ -94,12 +129,36 @@ pub struct Guild {
     pub invite: Option<String>,
     pub bottle_channel: Option<i64>,
     pub admin_channel: Option<i64>,
-    pub prefix: Option<String>
+    pub prefix: Option<String>,
+    pub allow_anonymous: bool,
+
+    pub allow_images: bool,
+    pub allow_links: bool,
+    pub blocked_words: Option<String>,
+    pub receive_bottles: bool,
+    pub receive_replies: bool
 }
 
 impl Guild {
     pub fn new (gid: GuildId) -> Guild {
-        Guild {id: gid, bottle_channel: None, invite: None, admin_channel: None, prefix: None}
+        Guild {id: gid, bottle_channel: None, invite: None, admin_channel: None, prefix: None, allow_anonymous: false,
+            allow_images: true, allow_links: true, blocked_words: None, receive_bottles: true, receive_replies: true}
+    }
+
+    pub fn blocked_words(&self) -> Vec<String> {
+        self.blocked_words.as_ref().map(|words| {
+            words.split(',').map(|w| w.trim().to_lowercase()).filter(|w| !w.is_empty()).collect()
+        }).unwrap_or_default()
+    }
+
+    pub fn accepts(&self, bottle: &Bottle) -> bool {
+        let opted_in = if bottle.reply_to.is_some() { self.receive_replies } else { self.receive_bottles };
+        if !opted_in || (bottle.image.is_some() && !self.allow_images) || (bottle.url.is_some() && !self.allow_links) {
+            return false;
+        }
+
+        let contents = bottle.contents.to_lowercase();
+        !self.blocked_words().iter().any(|w| contents.contains(w.as_str()))
     }
 }
 
@@ -109,10 +168,12 @@ pub struct MakeReceivedBottle {
     pub bottle: BottleId,
     pub channel: i64,
     pub message: i64,
-    pub time_recieved: DTime
+    pub time_recieved: DTime,
+    pub platform: i16,
+    pub guild: Option<GuildId>
 }
 
-#[derive(Queryable, Associations, Identifiable)]
+#[derive(Queryable, Associations, Identifiable, Serialize)]
 #[table_name="received_bottle"]
 #[belongs_to(Bottle, foreign_key="bottle")]
 pub struct ReceivedBottle {
@@ -120,10 +181,54 @@ pub struct ReceivedBottle {
     pub bottle: BottleId,
     pub message: i64,
     pub time_recieved: DTime,
-    pub channel: i64
+    pub channel: i64,
+    pub platform: i16,
+    /// The guild the channel belonged to, so the copy can be erased with it
+    pub guild: Option<GuildId>
+}
+
+/// A bottle whose fan-out hasn't finished, left behind if the process stops mid-distribution
+#[derive(Queryable, Insertable)]
+#[table_name="pending_delivery"]
+pub struct PendingDelivery {
+    pub bottle: BottleId,
+    pub queued: DTime
 }
 
-#[derive(Queryable, Insertable, AsChangeset)]
+/// Filters for the public bottle archive, ``before`` is exclusive
+#[derive(Clone, Default, Debug)]
+pub struct BottleSearch {
+    pub text: Option<String>,
+    pub guild: Option<GuildId>,
+    pub user: Option<UserId>,
+    pub after: Option<DTime>,
+    pub before: Option<DTime>,
+    pub has_image: bool,
+    pub has_replies: bool
+}
+
+/// A row of the user or guild leaderboard, rebuilt from the user and contribution tables
+#[derive(Queryable, Serialize, Clone, Debug)]
+pub struct LeaderboardEntry {
+    pub id: i64,
+    pub rank: i64,
+    pub xp: i64
+}
+
+/// Last known name and picture of a Discord user or guild, served when Discord can't be reached
+#[derive(Queryable, Insertable, AsChangeset, Clone, Debug)]
+#[table_name="discord_profile"]
+#[changeset_options(treat_none_as_null="true")]
+pub struct DiscordProfile {
+    pub id: i64,
+    pub guild: bool,
+    pub name: String,
+    pub avatar: Option<String>,
+    pub bot: bool,
+    pub refreshed: DTime
+}
+
+#[derive(Queryable, Insertable, AsChangeset, Serialize)]
 #[table_name="guild_contribution"]
 pub struct GuildContribution {
     pub guild: GuildId,
@@ -133,11 +238,172 @@ pub struct GuildContribution {
 
 impl User {
     pub fn new (uid: UserId) -> User {
-        User {id: uid, session: None, xp: 0, admin: false, tickets: 0}
+        User {id: uid, xp: 0, admin: false, tickets: 0}
     }
 }
 
-#[derive(Queryable, Insertable, AsChangeset)]
+#[derive(Queryable, Insertable, AsChangeset, Serialize, Clone)]
+#[table_name="privacy"]
+pub struct Privacy {
+    pub user: UserId,
+    pub hide_profile: bool,
+    pub hide_bottles: bool,
+    pub hide_contributions: bool,
+    pub hide_leaderboard: bool
+}
+
+impl Privacy {
+    pub fn new (uid: UserId) -> Privacy {
+        Privacy {user: uid, hide_profile: false, hide_bottles: false, hide_contributions: false, hide_leaderboard: false}
+    }
+
+    /// Same as the archive's filter, hiding the profile hides the bottles too
+    pub fn bottles_hidden(&self) -> bool {
+        self.hide_bottles || self.hide_profile
+    }
+
+    pub fn describe(&self) -> String {
+        let state = |hidden: bool| if hidden { "hidden" } else { "shown" };
+
+        format!("Profile: {}\nBottles: {}\nContributions: {}\nLeaderboard: {}",
+            state(self.hide_profile), state(self.hide_bottles), state(self.hide_contributions), state(self.hide_leaderboard))
+    }
+}
+
+#[derive(Queryable, Insertable, AsChangeset, Identifiable, Clone, PartialEq, Debug)]
+#[table_name="session"]
+#[changeset_options(treat_none_as_null="true")]
+pub struct Session {
+    pub id: String,
+    pub user: Option<UserId>,
+    pub csrf: Option<String>,
+    pub redirect: Option<String>,
+    pub form_token: Option<String>,
+    pub created: DTime,
+    pub expires: DTime,
+    pub revoked: bool
+}
+
+impl Session {
+    pub fn new() -> Session {
+        let created = now();
+
+        Session {id: Uuid::new_v4().to_string(), user: None, csrf: None, redirect: None, form_token: None,
+            created, expires: created + chrono::Duration::days(SESSION_DAYS), revoked: false}
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.user.is_none() && self.csrf.is_none() && self.redirect.is_none() && self.form_token.is_none()
+    }
+}
+
+/// Only a hash of the key is stored, the key itself is shown once when it's made
+#[derive(Queryable, Insertable, Clone, Debug)]
+#[table_name="api_key"]
+pub struct ApiKey {
+    pub id: String,
+    pub user: UserId,
+    pub name: String,
+    pub rate_limit: i32,
+    pub created: DTime,
+    pub revoked: bool
+}
+
+impl ApiKey {
+    pub fn hash(key: &str) -> String {
+        use sha2::{Sha256, Digest};
+        hex::encode(Sha256::digest(key.as_bytes()))
+    }
+
+    /// Returns the key to hand to the user along with the row to store
+    pub fn new(uid: UserId, name: String) -> (String, ApiKey) {
+        let key = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        let api_key = ApiKey {id: ApiKey::hash(&key), user: uid, name, rate_limit: API_RATE_LIMIT, created: now(), revoked: false};
+
+        (key, api_key)
+    }
+}
+
+#[derive(Queryable, Identifiable, Clone, Debug)]
+#[table_name="webhook"]
+pub struct Webhook {
+    pub id: WebhookId,
+    pub guild: Option<GuildId>,
+    pub url: String,
+    pub secret: String,
+    pub events: i32,
+    pub created: DTime
+}
+
+#[derive(Insertable)]
+#[table_name="webhook"]
+pub struct MakeWebhook {
+    pub guild: Option<GuildId>,
+    pub url: String,
+    pub secret: String,
+    pub events: i32,
+    pub created: DTime
+}
+
+#[derive(Queryable, Identifiable, Debug)]
+#[table_name="webhook_delivery"]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook: WebhookId,
+    pub event: String,
+    pub payload: String,
+    pub attempts: i32,
+    pub next_attempt: Option<DTime>,
+    pub status: Option<i32>,
+    pub error: Option<String>,
+    pub created: DTime
+}
+
+#[derive(Insertable)]
+#[table_name="webhook_delivery"]
+pub struct MakeWebhookDelivery<'a> {
+    pub webhook: WebhookId,
+    pub event: &'a str,
+    pub payload: &'a str,
+    pub attempts: i32,
+    pub next_attempt: Option<DTime>,
+    pub created: DTime
+}
+
+#[derive(Queryable, Insertable)]
+#[table_name="managed_guild"]
+pub struct ManagedGuild {
+    pub user: UserId,
+    pub guild: GuildId
+}
+
+/// Users and channels from bridged platforms get negative ids, so they never collide with discord snowflakes
+#[derive(Queryable, Identifiable, Clone, Debug)]
+#[table_name="platform_id"]
+pub struct PlatformId {
+    pub id: i64,
+    pub platform: i16,
+    pub external: String,
+    pub name: String
+}
+
+#[derive(Insertable)]
+#[table_name="platform_id"]
+pub struct MakePlatformId<'a> {
+    pub platform: i16,
+    pub external: &'a str,
+    pub name: &'a str
+}
+
+#[derive(Queryable, Insertable, Serialize)]
+#[table_name="pseudonym"]
+pub struct Pseudonym {
+    pub thread: BottleId,
+    pub user: UserId,
+    pub number: i32
+}
+
+#[derive(Queryable, Insertable, AsChangeset, Serialize)]
 #[table_name="report"]
 pub struct Report {
     pub bottle: BottleId,
@@ -145,72 +411,95 @@ pub struct Report {
     pub received_bottle: Option<ReceivedBottleId>
 }
 
-#[derive(Queryable, Insertable)]
+#[derive(Queryable, Insertable, Serialize)]
 #[table_name="ban"]
 pub struct Ban {
     pub report: Option<ReportId>,
     pub user: UserId
 }
 
-#[derive(Clone, Deserialize, Debug)]
-pub struct Config {
-    pub token: String,
-    pub discord_bots_token: String,
-    pub debug_log: bool,
-    pub client_id: String,
-    pub client_secret: String,
-    pub database_url: String,
-    pub host_url: String,
-    pub host_domain: String,
-    pub host_path: String,
-    pub admin_channel: i64,
-    pub ban_emoji: String,
-    pub delete_emoji: String,
-    pub auto_admin: UserId,
-    pub cookie_sig: String
+#[derive(Insertable)]
+#[table_name="xp_event"]
+pub struct MakeXpEvent<'a> {
+    pub user: UserId,
+    pub guild: Option<GuildId>,
+    pub bottle: Option<BottleId>,
+    pub xp: i32,
+    pub reason: &'a str,
+    pub time: DTime
+}
+
+#[derive(Queryable, Serialize)]
+pub struct XpEvent {
+    pub id: i64,
+    pub user: UserId,
+    pub guild: Option<GuildId>,
+    pub bottle: Option<BottleId>,
+    pub xp: i32,
+    pub reason: String,
+    pub time: DTime
+}
+
+#[derive(Serialize)]
+pub struct UserExport {
+    pub user: User,
+    pub privacy: Privacy,
+    pub bottles: Vec<Bottle>,
+    pub received_copies: Vec<ReceivedBottle>,
+    pub pseudonyms: Vec<Pseudonym>,
+    pub reports: Vec<Report>,
+    pub ban: Option<Ban>,
+    pub xp_history: Vec<XpEvent>,
+    pub contributions: Vec<GuildContribution>,
+    pub exported: DTime
 }
 
-pub type Res<A> = Result<A, Box<Error>>;
+pub type Res<A> = Result<A, BottleError>;
 
 pub fn now() -> chrono::NaiveDateTime {
     chrono::offset::Utc::now().naive_utc()
 }
 
 pub fn user_url(uid: UserId, cfg: &Config) -> String {
-    format!("{}/u/{}", cfg.host_url, uid)
+    format!("{}/u/{}", cfg.web.host_url, uid)
 }
 
 pub fn guild_url(gid: GuildId, cfg: &Config) -> String {
-    format!("{}/g/{}", cfg.host_url, gid)
+    format!("{}/g/{}", cfg.web.host_url, gid)
 }
 
 pub fn anonymous_url(cfg: &Config) -> String {
-    format!("{}/img/anonymous.png", cfg.host_url)
+    format!("{}/img/anonymous.png", cfg.web.host_url)
 }
 
 pub fn error_url(cfg: &Config) -> String {
-    format!("{}/img/fetcherror.png", cfg.host_url)
+    format!("{}/img/fetcherror.png", cfg.web.host_url)
 }
 
-pub fn report_url(bid: BottleId, cfg: &Config) -> String { format!("{}/report/{}", cfg.host_url, bid) }
+pub fn report_url(bid: BottleId, cfg: &Config) -> String { format!("{}/report/{}", cfg.web.host_url, bid) }
 
-pub fn get_guild_name(id: GuildId) -> String {
-    use serenity::model::id::GuildId;
-    GuildId(id as u64).to_guild_cached().map(|x| x.read().name.to_owned())
-        .unwrap_or_else(|| "Guild not found".to_owned())
+pub fn settings_url(cfg: &Config) -> String {
+    format!("{}/settings", cfg.web.host_url)
 }
 
-pub fn get_user_name(id: UserId) -> String {
-    use serenity::model::id::UserId;
-    UserId(id as u64).to_user().ok().map(|x| x.name).unwrap_or_else(|| "User not found".to_owned())
+pub fn guild_settings_url(gid: GuildId, cfg: &Config) -> String {
+    format!("{}/g/{}/settings", cfg.web.host_url, gid)
 }
 
-pub struct DConfig;
-impl Key for DConfig {
-    type Value = Config;
+pub fn me_url(cfg: &Config) -> String {
+    format!("{}/me", cfg.web.host_url)
 }
 
-impl TypeMapKey for DConfig {
+pub fn export_url(cfg: &Config) -> String {
+    format!("{}/export", cfg.web.host_url)
+}
+
+pub fn pseudonym_name(number: i32) -> String {
+    format!("Sailor #{}", number)
+}
+
+pub struct DConfig;
+impl Key for DConfig {
     type Value = Config;
 }
 
@@ -232,28 +521,23 @@ pub trait GetConfig {
     fn get_cfg(&self) -> &Config;
 }
 
-impl GetConfig for serenity::prelude::Context {
-    fn get_cfg(&self) -> &Config {
-        self.data.blocking_read().get::<DConfig>().unwrap()
-    }
-}
-
 pub struct DConn;
 impl Key for DConn {
     type Value = ConnPool;
 }
 
 pub trait GetConnection {
-    fn get_conn(&self) -> Conn {
+    /// Fails instead of waiting on an exhausted pool for longer than ``POOL_TIMEOUT``
+    fn get_conn(&self) -> Res<Conn> {
         self.get_pool().get_conn()
     }
 
     fn get_pool(&self) -> ConnPool;
 }
 
-impl GetConnection for Pool<ConnectionManager<PgConnection>> {
-    fn get_conn(&self) -> Conn {
-        self.get().unwrap()
+impl GetConnection for ConnPool {
+    fn get_conn(&self) -> Res<Conn> {
+        Ok(self.get()?)
     }
 
     fn get_pool(&self) -> ConnPool {
@@ -261,22 +545,36 @@ impl GetConnection for Pool<ConnectionManager<PgConnection>> {
     }
 }
 
-impl GetConnection for serenity::prelude::Context {
-    fn get_pool(&self) -> ConnPool {
-        self.data.blocking_read().get::<DConn>().unwrap().get_pool()
+impl ConnPool {
+    /// Runs blocking queries on tokio's blocking threads so async handlers keep the gateway responsive
+    pub async fn run<T, F>(&self, f: F) -> Res<T>
+        where T: Send + 'static, F: FnOnce(&mut Conn) -> Res<T> + Send + 'static {
+        let pool = self.clone();
+
+        tokio::task::spawn_blocking(move || pool.get_conn().and_then(|mut conn| f(&mut conn))).await?
     }
 }
 
-pub trait GetBots {
-    fn get_bots(&self) -> Arc<discord_bots::Client>;
+pub struct DService;
+impl Key for DService {
+    type Value = BottleService;
+}
+
+impl TypeMapKey for DService {
+    type Value = BottleService;
 }
 
-impl GetBots for serenity::prelude::Context {
-    fn get_bots(&self) -> Arc<discord_bots::Client> {
-        self.data.blocking_read().get::<DBots>().unwrap().clone()
+impl DService {
+    /// ``blocking_read`` panics on the runtime, so async handlers get the service through this
+    pub async fn get(ctx: &serenity::prelude::Context) -> BottleService {
+        ctx.data.read().await.get::<DService>().unwrap().clone()
     }
 }
 
+pub trait GetService {
+    fn get_service(&self) -> BottleService;
+}
+
 pub mod id {
     use serenity::model::id::*;
 
@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+use serenity::model::channel::{Message, Embed, Attachment, Reaction, ReactionType};
+
+use model;
+use model::*;
+use model::id::*;
+use bottle::author_name;
+use gateway::ChatGateway;
+use log::*;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Platform {
+    Discord, Irc
+}
+
+impl Platform {
+    pub fn id(self) -> i16 {
+        self as i16
+    }
+
+    pub fn from_id(id: i16) -> Option<Platform> {
+        match id {
+            0 => Some(Platform::Discord),
+            1 => Some(Platform::Irc),
+            _ => None
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Platform::Discord => "Discord",
+            Platform::Irc => "IRC"
+        }
+    }
+
+    /// Discord channels are snowflakes, everything else is mapped to a negative id
+    pub fn of_channel(channel: i64, conn: &mut Conn) -> Res<(Platform, Option<PlatformId>)> {
+        if channel >= 0 {
+            return Ok((Platform::Discord, None));
+        }
+
+        let mapped = PlatformId::get(channel, conn)?;
+        let platform = Platform::from_id(mapped.platform).ok_or("Unknown platform")?;
+        Ok((platform, Some(mapped)))
+    }
+}
+
+/// A message that may become a bottle, whichever platform it came from
+pub struct Incoming {
+    pub platform: Platform,
+    pub user: model::UserId,
+    pub message: i64,
+    pub channel: i64,
+    pub content: String,
+    pub url: Option<String>,
+    pub image: Option<String>
+}
+
+impl Incoming {
+    pub fn from_discord(msg: &Message) -> Incoming {
+        Incoming {
+            platform: Platform::Discord,
+            user: msg.author.id.as_i64(),
+            message: msg.id.as_i64(),
+            channel: msg.channel_id.as_i64(),
+            content: msg.content.clone(),
+            url: msg.embeds.get(0).and_then(|emb: &Embed| emb.url.clone()),
+            image: msg.attachments.get(0).map(|a: &Attachment| a.url.clone())
+        }
+    }
+}
+
+/// A reaction from a person, bots and custom emoji never matter to the core
+pub struct Reacted {
+    pub user: model::UserId,
+    pub message: i64,
+    pub emoji: String
+}
+
+impl Reacted {
+    pub fn from_discord(r: &Reaction) -> Res<Option<Reacted>> {
+        let user = r.user()?;
+        if user.bot {
+            return Ok(None);
+        }
+
+        Ok(match r.emoji {
+            ReactionType::Unicode(ref x) => Some(Reacted {user: user.id.as_i64(), message: r.message_id.as_i64(), emoji: x.clone()}),
+            _ => None
+        })
+    }
+}
+
+/// Everything a platform needs to draw a bottle, renderers only decide how it looks
+#[derive(Clone, Debug)]
+pub struct RenderedBottle {
+    pub title: String,
+    pub author: String,
+    pub author_url: Option<String>,
+    pub avatar: String,
+    pub contents: String,
+    pub url: Option<String>,
+    pub image: Option<String>,
+    pub guild_url: Option<String>,
+    pub origin: Option<String>,
+    pub origin_icon: Option<String>,
+    pub report_url: String,
+    pub time: DTime,
+    pub level: usize,
+    pub deleted: bool
+}
+
+impl RenderedBottle {
+    pub fn new(bottle: &Bottle, mut level: usize, in_reply: bool, gateway: &dyn ChatGateway, cfg: &Config) -> RenderedBottle {
+        if in_reply {
+            level += 1;
+        }
+
+        let (author, author_url, avatar) = if bottle.anonymous {
+            (author_name(bottle, gateway), None, anonymous_url(cfg))
+        } else if let Some(name) = bridged_name(bottle.user) {
+            (name, Some(user_url(bottle.user, cfg)), anonymous_url(cfg))
+        } else {
+            let user = gateway.get_user(bottle.user);
+            let username = user.as_ref().map(|u| u.tag.clone())
+                .unwrap_or_else(|| "Error fetching username".to_owned());
+            let avatar = user.and_then(|u| u.avatar).unwrap_or_else(|| anonymous_url(cfg));
+
+            (username, Some(user_url(bottle.user, cfg)), avatar)
+        };
+
+        let title = if bottle.deleted {
+            format!("BOTTLE FROM {} IS DELETED", author)
+        } else if level > 0 {
+            "You have found a message glued to the bottle!".to_owned()
+        } else {
+            "You have recovered a bottle!".to_owned()
+        };
+
+        let (origin, origin_icon) = match bottle.guild {
+            Some(gid) if gid < 0 => (bridged_name(gid), None),
+            Some(gid) => match gateway.get_guild(gid) {
+                Some(guild) => (Some(guild.name), guild.icon),
+                None => (None, None)
+            },
+            None => (None, None)
+        };
+
+        let platform = Platform::from_id(bottle.platform).unwrap_or(Platform::Discord);
+        let origin = match (origin, platform) {
+            (Some(name), Platform::Discord) => Some(name),
+            (Some(name), platform) => Some(format!("{} ({})", name, platform.name())),
+            (None, Platform::Discord) => None,
+            (None, platform) => Some(platform.name().to_owned())
+        };
+
+        RenderedBottle {
+            title, author, author_url, avatar,
+            contents: if bottle.deleted { "This bottle has been deleted.".to_owned() } else { bottle.contents.clone() },
+            url: if bottle.deleted { None } else { bottle.url.clone() },
+            image: if bottle.deleted { None } else { bottle.image.clone() },
+            guild_url: bottle.guild.map(|gid| guild_url(gid, cfg)),
+            origin, origin_icon,
+            report_url: report_url(bottle.id, cfg),
+            time: bottle.time_pushed,
+            level,
+            deleted: bottle.deleted
+        }
+    }
+}
+
+/// A chat platform other than discord that channels can join the ocean from
+pub trait Bridge: Send + Sync {
+    fn platform(&self) -> Platform;
+
+    /// Sends the bottle to the mapped channel, bridges without message editing just drop edits
+    fn send(&self, channel: &PlatformId, bottle: &RenderedBottle) -> Res<()>;
+}
+
+static BRIDGES: RwLock<Vec<Arc<dyn Bridge>>> = RwLock::new(Vec::new());
+const NAMES_REFRESH: Duration = Duration::from_secs(300);
+static NAMES: RwLock<Option<HashMap<i64, String>>> = RwLock::new(None);
+
+pub fn register(bridge: Arc<dyn Bridge>) {
+    info!("Bridging to {}", bridge.platform().name());
+
+    let mut bridges = BRIDGES.write().unwrap();
+    bridges.retain(|b| b.platform() != bridge.platform()); //reconnects replace the old bridge
+    bridges.push(bridge);
+}
+
+pub fn get_bridge(platform: Platform) -> Res<Arc<dyn Bridge>> {
+    BRIDGES.read().unwrap().iter().find(|b| b.platform() == platform).cloned()
+        .ok_or_else(|| format!("{} isn't bridged", platform.name()).into())
+}
+
+/// Names of bridged users and channels, since they can't be fetched from discord
+pub fn bridged_name(id: i64) -> Option<String> {
+    if id >= 0 {
+        return None;
+    }
+
+    NAMES.read().unwrap().as_ref().and_then(|names| names.get(&id).cloned())
+}
+
+pub fn remember(mapped: &PlatformId) {
+    NAMES.write().unwrap().get_or_insert_with(HashMap::new).insert(mapped.id, mapped.name.clone());
+}
+
+pub fn load_names(conn: &mut Conn) -> Res<()> {
+    for mapped in PlatformId::get_all(conn)? {
+        remember(&mapped);
+    }
+
+    Ok(())
+}
+
+/// For processes that don't bridge anything themselves, so they still see names the bot maps later
+pub fn refresh_names(pool: ConnPool) {
+    thread::spawn(move || loop {
+        thread::sleep(NAMES_REFRESH);
+
+        if let Err(err) = pool.get_conn().and_then(|mut conn| load_names(&mut conn)) {
+            error!("Error refreshing bridged names: {}", err);
+        }
+    });
+}
+
+/// Maps an external user or channel to its id, remembering the name for rendering
+pub fn map_id(platform: Platform, external: &str, name: &str, conn: &mut Conn) -> Res<PlatformId> {
+    let mapped = MakePlatformId {platform: platform.id(), external, name}.get_or_make(conn)?;
+    remember(&mapped);
+
+    Ok(mapped)
+}
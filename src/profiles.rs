@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::*;
+
+use model::*;
+use gateway::{ChatGateway, ChatUser, ChatGuild};
+use platform::RenderedBottle;
+use settings::CacheConfig;
+use metrics;
+
+/// How often stored profiles are checked, and how old they get before being fetched again
+const REFRESH_POLL: Duration = Duration::from_secs(300);
+const REFRESH_AFTER: i64 = 6;
+const REFRESH_BATCH: i64 = 50;
+/// Past this many entries, expired ones are dropped from memory on the next insert
+const MAX_ENTRIES: usize = 10000;
+
+struct Cached<T> {
+    value: Option<T>,
+    fetched: Instant
+}
+
+type Entries<T> = Mutex<HashMap<i64, Cached<T>>>;
+
+trait Profile: Clone {
+    fn to_profile(&self) -> DiscordProfile;
+    fn from_profile(profile: DiscordProfile) -> Self;
+}
+
+impl Profile for ChatUser {
+    fn to_profile(&self) -> DiscordProfile {
+        DiscordProfile {id: self.id, guild: false, name: self.tag.clone(), avatar: self.avatar.clone(), bot: self.bot, refreshed: now()}
+    }
+
+    fn from_profile(profile: DiscordProfile) -> Self {
+        ChatUser {id: profile.id, tag: profile.name, avatar: profile.avatar, bot: profile.bot}
+    }
+}
+
+impl Profile for ChatGuild {
+    fn to_profile(&self) -> DiscordProfile {
+        DiscordProfile {id: self.id, guild: true, name: self.name.clone(), avatar: self.icon.clone(), bot: false, refreshed: now()}
+    }
+
+    fn from_profile(profile: DiscordProfile) -> Self {
+        ChatGuild {id: profile.id, name: profile.name, icon: profile.avatar}
+    }
+}
+
+/// Remembers users and guilds for ``cache.ttl`` in front of another gateway. With ``cache.persist`` they're also
+/// kept in ``discord_profile``, which lookups fall back on when Discord doesn't answer
+pub struct CachedGateway {
+    inner: Arc<dyn ChatGateway>,
+    pool: Option<ConnPool>,
+    ttl: Duration,
+    users: Entries<ChatUser>,
+    guilds: Entries<ChatGuild>
+}
+
+impl CachedGateway {
+    pub fn new(inner: Arc<dyn ChatGateway>, pool: ConnPool, cfg: &CacheConfig) -> CachedGateway {
+        CachedGateway {
+            inner, pool: if cfg.persist { Some(pool) } else { None }, ttl: Duration::from_secs(cfg.ttl),
+            users: Mutex::new(HashMap::new()), guilds: Mutex::new(HashMap::new())
+        }
+    }
+
+    fn store<T: Profile>(&self, entries: &Entries<T>, id: i64, value: Option<T>) {
+        let mut entries = entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, c| c.fetched.elapsed() < self.ttl);
+        }
+
+        entries.insert(id, Cached {value, fetched: Instant::now()});
+    }
+
+    /// Erased users are still looked up to render what's left of their bottles, but aren't stored again
+    fn persist<T: Profile>(&self, value: &T) {
+        if let Some(pool) = &self.pool {
+            let res = pool.get_conn().and_then(|mut conn| {
+                let profile = value.to_profile();
+                if !profile.guild && User::is_erased(profile.id, &mut conn)? {
+                    return Ok(());
+                }
+
+                profile.update(&mut conn)?;
+                Ok(())
+            });
+
+            if let Err(err) = res {
+                warn!("Error storing Discord profile: {}", err);
+            }
+        }
+    }
+
+    fn stored<T: Profile>(&self, id: i64) -> Option<T> {
+        let mut conn = self.pool.as_ref()?.get_conn().ok()?;
+        DiscordProfile::get(id, &mut conn).ok().map(T::from_profile)
+    }
+
+    /// Misses are remembered as well, so an outage or a deleted account isn't asked about on every render
+    fn lookup<T: Profile, F: FnOnce() -> Option<T>>(&self, entries: &Entries<T>, id: i64, fetch: F) -> Option<T> {
+        if let Some(cached) = entries.lock().unwrap().get(&id).filter(|c| c.fetched.elapsed() < self.ttl) {
+            metrics::PROFILE_LOOKUPS.with_label_values(&["hit"]).inc();
+            return cached.value.clone();
+        }
+
+        let value = match fetch() {
+            Some(fresh) => {
+                metrics::PROFILE_LOOKUPS.with_label_values(&["fetched"]).inc();
+                self.persist(&fresh);
+                Some(fresh)
+            },
+            None => {
+                let stale = entries.lock().unwrap().get(&id).and_then(|c| c.value.clone()).or_else(|| self.stored(id));
+                metrics::PROFILE_LOOKUPS.with_label_values(&[if stale.is_some() { "fallback" } else { "missing" }]).inc();
+                stale
+            }
+        };
+
+        self.store(entries, id, value.clone());
+        value
+    }
+
+    /// Fetches the least recently refreshed stored profiles again, returning how many were updated
+    pub fn refresh_stale(&self) -> Res<usize> {
+        let pool = match &self.pool {
+            Some(pool) => pool,
+            None => return Ok(0)
+        };
+
+        let before = now() - chrono::Duration::hours(REFRESH_AFTER);
+        let stale = DiscordProfile::get_stale(before, REFRESH_BATCH, &mut pool.get_conn()?)?;
+        let mut refreshed = 0;
+
+        for profile in stale {
+            if profile.guild {
+                if let Some(guild) = self.inner.get_guild(profile.id) {
+                    self.persist(&guild);
+                    self.store(&self.guilds, guild.id, Some(guild));
+                    refreshed += 1;
+                }
+            } else if let Some(user) = self.inner.get_user(profile.id) {
+                self.persist(&user);
+                self.store(&self.users, user.id, Some(user));
+                refreshed += 1;
+            }
+        }
+
+        Ok(refreshed)
+    }
+
+    /// Keeps stored profiles from going stale in the background, a no-op without ``cache.persist``
+    pub fn watch(self: Arc<Self>) {
+        if self.pool.is_none() {
+            return;
+        }
+
+        thread::spawn(move || loop {
+            match self.refresh_stale() {
+                Ok(0) => (),
+                Ok(n) => debug!("Refreshed {} Discord profiles", n),
+                Err(err) => error!("Error refreshing Discord profiles: {}", err)
+            }
+
+            thread::sleep(REFRESH_POLL);
+        });
+    }
+}
+
+impl ChatGateway for CachedGateway {
+    fn send_bottle(&self, channel: i64, bottle: &RenderedBottle) -> Res<i64> {
+        self.inner.send_bottle(channel, bottle)
+    }
+
+    fn edit_bottle(&self, channel: i64, message: i64, bottle: &RenderedBottle) -> Res<()> {
+        self.inner.edit_bottle(channel, message, bottle)
+    }
+
+    fn say(&self, channel: i64, text: &str) -> Res<i64> {
+        self.inner.say(channel, text)
+    }
+
+    fn react(&self, channel: i64, message: i64, emoji: &str) -> Res<()> {
+        self.inner.react(channel, message, emoji)
+    }
+
+    fn get_user(&self, user: UserId) -> Option<ChatUser> {
+        self.lookup(&self.users, user, || self.inner.get_user(user))
+    }
+
+    fn forget_user(&self, user: UserId) {
+        self.users.lock().unwrap().remove(&user);
+        self.inner.forget_user(user);
+    }
+
+    fn get_guild(&self, guild: GuildId) -> Option<ChatGuild> {
+        self.lookup(&self.guilds, guild, || self.inner.get_guild(guild))
+    }
+
+    fn text_channels(&self, guild: GuildId) -> Res<Vec<(i64, String)>> {
+        self.inner.text_channels(guild)
+    }
+
+    fn create_invite(&self, channel: i64) -> Res<String> {
+        self.inner.create_invite(channel)
+    }
+
+    fn can_manage(&self, guild: GuildId, user: UserId) -> Res<bool> {
+        self.inner.can_manage(guild, user)
+    }
+}
@@ -1,5 +1,16 @@
 use diesel::prelude::*;
 
+table! {
+    api_key (id) {
+        id -> Text,
+        user -> Int8,
+        name -> Text,
+        rate_limit -> Int4,
+        created -> Timestamp,
+        revoked -> Bool,
+    }
+}
+
 table! {
     ban (user) {
         report -> Nullable<Int8>,
@@ -20,6 +31,27 @@ table! {
         image -> Nullable<Text>,
         channel -> Int8,
         deleted -> Bool,
+        anonymous -> Bool,
+        pseudonym -> Nullable<Int4>,
+        platform -> Int2,
+    }
+}
+
+table! {
+    discord_profile (id) {
+        id -> Int8,
+        guild -> Bool,
+        name -> Text,
+        avatar -> Nullable<Text>,
+        bot -> Bool,
+        refreshed -> Timestamp,
+    }
+}
+
+table! {
+    erased_user (id) {
+        id -> Int8,
+        erased -> Timestamp,
     }
 }
 
@@ -29,7 +61,13 @@ table! {
         invite -> Nullable<Text>,
         bottle_channel -> Nullable<Int8>,
         admin_channel -> Nullable<Int8>,
-        prefix -> Nullable<Bpchar>,
+        prefix -> Nullable<Text>,
+        allow_anonymous -> Bool,
+        allow_images -> Bool,
+        allow_links -> Bool,
+        blocked_words -> Nullable<Text>,
+        receive_bottles -> Bool,
+        receive_replies -> Bool,
     }
 }
 
@@ -41,6 +79,55 @@ table! {
     }
 }
 
+table! {
+    guild_leaderboard (id) {
+        id -> Int8,
+        rank -> Int8,
+        xp -> Int8,
+    }
+}
+
+table! {
+    managed_guild (user, guild) {
+        user -> Int8,
+        guild -> Int8,
+    }
+}
+
+table! {
+    pending_delivery (bottle) {
+        bottle -> Int8,
+        queued -> Timestamp,
+    }
+}
+
+table! {
+    platform_id (id) {
+        id -> Int8,
+        platform -> Int2,
+        external -> Text,
+        name -> Text,
+    }
+}
+
+table! {
+    privacy (user) {
+        user -> Int8,
+        hide_profile -> Bool,
+        hide_bottles -> Bool,
+        hide_contributions -> Bool,
+        hide_leaderboard -> Bool,
+    }
+}
+
+table! {
+    pseudonym (thread, user) {
+        thread -> Int8,
+        user -> Int8,
+        number -> Int4,
+    }
+}
+
 table! {
     received_bottle (id) {
         id -> Int8,
@@ -48,6 +135,8 @@ table! {
         message -> Int8,
         time_recieved -> Timestamp,
         channel -> Int8,
+        platform -> Int2,
+        guild -> Nullable<Int8>,
     }
 }
 
@@ -59,33 +148,119 @@ table! {
     }
 }
 
+table! {
+    session (id) {
+        id -> Text,
+        user -> Nullable<Int8>,
+        csrf -> Nullable<Text>,
+        redirect -> Nullable<Text>,
+        form_token -> Nullable<Text>,
+        created -> Timestamp,
+        expires -> Timestamp,
+        revoked -> Bool,
+    }
+}
+
 table! {
     user (id) {
         id -> Int8,
-        session -> Nullable<Uuid>,
         xp -> Int4,
         admin -> Bool,
         tickets -> Int4,
     }
 }
 
+table! {
+    user_leaderboard (id) {
+        id -> Int8,
+        rank -> Int8,
+        xp -> Int8,
+    }
+}
+
+table! {
+    webhook (id) {
+        id -> Int8,
+        guild -> Nullable<Int8>,
+        url -> Text,
+        secret -> Text,
+        events -> Int4,
+        created -> Timestamp,
+    }
+}
+
+table! {
+    webhook_delivery (id) {
+        id -> Int8,
+        webhook -> Int8,
+        event -> Text,
+        payload -> Text,
+        attempts -> Int4,
+        next_attempt -> Nullable<Timestamp>,
+        status -> Nullable<Int4>,
+        error -> Nullable<Text>,
+        created -> Timestamp,
+    }
+}
+
+table! {
+    xp_event (id) {
+        id -> Int8,
+        user -> Int8,
+        guild -> Nullable<Int8>,
+        bottle -> Nullable<Int8>,
+        xp -> Int4,
+        reason -> Text,
+        time -> Timestamp,
+    }
+}
+
+joinable!(api_key -> user (user));
 joinable!(ban -> report (report));
 joinable!(ban -> user (user));
 joinable!(bottle -> guild (guild));
 joinable!(bottle -> user (user));
+joinable!(erased_user -> user (id));
 joinable!(guild_contribution -> guild (guild));
 joinable!(guild_contribution -> user (user));
+joinable!(guild_leaderboard -> guild (id));
+joinable!(managed_guild -> guild (guild));
+joinable!(managed_guild -> user (user));
+joinable!(pending_delivery -> bottle (bottle));
+joinable!(privacy -> user (user));
+joinable!(pseudonym -> bottle (thread));
+joinable!(pseudonym -> user (user));
 joinable!(received_bottle -> bottle (bottle));
 joinable!(report -> bottle (bottle));
 joinable!(report -> received_bottle (received_bottle));
 joinable!(report -> user (user));
+joinable!(session -> user (user));
+joinable!(user_leaderboard -> user (id));
+joinable!(webhook -> guild (guild));
+joinable!(webhook_delivery -> webhook (webhook));
+joinable!(xp_event -> bottle (bottle));
+joinable!(xp_event -> user (user));
 
 allow_tables_to_appear_in_same_query!(
+    api_key,
     ban,
     bottle,
+    discord_profile,
+    erased_user,
     guild,
     guild_contribution,
+    guild_leaderboard,
+    managed_guild,
+    pending_delivery,
+    platform_id,
+    privacy,
+    pseudonym,
     received_bottle,
     report,
+    session,
     user,
+    user_leaderboard,
+    webhook,
+    webhook_delivery,
+    xp_event,
 );
@@ -0,0 +1,380 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use config;
+use serde_derive::Deserialize;
+
+use model::*;
+
+pub const DEFAULT_PATH: &str = "./bottle.toml";
+/// ``BOTTLE_WEB__HOST_URL`` overrides ``host_url`` in ``[web]``
+const ENV_PREFIX: &str = "BOTTLE";
+
+#[derive(Clone, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Config {
+    pub discord: DiscordConfig,
+    pub database: DatabaseConfig,
+    pub web: WebConfig,
+    pub moderation: ModerationConfig,
+    pub economy: EconomyConfig,
+    pub cache: CacheConfig,
+    pub logging: LoggingConfig,
+    pub irc: IrcConfig
+}
+
+#[derive(Clone, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct DiscordConfig {
+    pub token: String,
+    pub discord_bots_token: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub admin_channel: i64,
+    pub auto_admin: UserId
+}
+
+#[derive(Clone, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    /// Postgres, or SQLite for ``sqlite:`` urls and paths ending in ``.db``/``.sqlite``
+    pub url: String
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(default)]
+pub struct WebConfig {
+    pub bind: String,
+    /// Where /metrics and /readyz are served, apart from the public site
+    pub internal_bind: String,
+    pub host_url: String,
+    /// Cookie domain and path, taken from ``host_url`` when left out
+    pub host_domain: String,
+    pub host_path: String,
+    pub cookie_sig: String,
+    /// Only send the session cookie over https, follows the scheme of ``host_url`` when left out
+    pub secure_cookies: Option<bool>
+}
+
+impl Default for WebConfig {
+    fn default() -> Self {
+        WebConfig {
+            bind: "0.0.0.0:8080".to_owned(), internal_bind: "127.0.0.1:9090".to_owned(), host_url: String::new(),
+            host_domain: String::new(), host_path: String::new(), cookie_sig: String::new(), secure_cookies: None
+        }
+    }
+}
+
+impl WebConfig {
+    pub fn secure_cookies(&self) -> bool {
+        self.secure_cookies.unwrap_or_else(|| self.host_url.starts_with("https://"))
+    }
+}
+
+#[derive(Clone, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ModerationConfig {
+    pub ban_emoji: String,
+    pub delete_emoji: String
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(default)]
+pub struct EconomyConfig {
+    pub push_xp: i32,
+    pub reply_xp: i32,
+    pub url_xp: i32,
+    pub image_xp: i32,
+    pub report_xp: i32,
+    /// Minutes between bottles from the same user
+    pub cooldown: i64,
+    /// Rejected bottles before the bot stops answering
+    pub max_tickets: i32,
+    /// Guilds each bottle is sent to
+    pub deliver_to: i64
+}
+
+impl Default for EconomyConfig {
+    fn default() -> Self {
+        EconomyConfig {
+            push_xp: PUSHXP, reply_xp: REPLYXP, url_xp: URLXP, image_xp: IMAGEXP, report_xp: REPORTXP,
+            cooldown: COOLDOWN, max_tickets: MAX_TICKETS, deliver_to: DELIVERNUM
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Seconds a Discord user or guild is reused before it's fetched again
+    pub ttl: u64,
+    /// Keep profiles in ``discord_profile`` to fall back on when Discord is unavailable, refreshed in the background
+    pub persist: bool
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {ttl: 600, persist: true}
+    }
+}
+
+#[derive(Clone, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub debug: bool,
+    /// ``EnvFilter`` directives like ``info,bottle::web=debug,serenity=warn``, overrides ``debug``
+    pub filter: Option<String>
+}
+
+#[derive(Clone, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct IrcConfig {
+    pub server: Option<String>,
+    pub nick: Option<String>,
+    pub channels: Vec<String>
+}
+
+/// Every problem found in the configuration, so they can all be fixed in one go
+#[derive(Debug)]
+pub struct ConfigErrors(pub Vec<String>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Invalid configuration:")?;
+        for problem in &self.0 {
+            writeln!(f, "  - {}", problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+/// Defaults, then the file at ``path`` if there is one, then ``BOTTLE_SECTION__KEY`` environment variables
+pub fn load(path: &str) -> Result<Config, ConfigErrors> {
+    let mut cfg: Config = config::Config::builder()
+        .add_source(config::File::from(Path::new(path)).required(false))
+        .add_source(config::Environment::with_prefix(ENV_PREFIX).prefix_separator("_").separator("__").try_parsing(true)
+            .list_separator(",").with_list_parse_key("irc.channels"))
+        .build().and_then(|c| c.try_deserialize())
+        .map_err(|err| ConfigErrors(vec![err.to_string()]))?;
+
+    cfg.fill_derived();
+    cfg.validate()?;
+    Ok(cfg)
+}
+
+fn is_url(url: &str) -> bool {
+    url.starts_with("https://") || url.starts_with("http://")
+}
+
+impl Config {
+    fn fill_derived(&mut self) {
+        let web = &mut self.web;
+        let rest = web.host_url.splitn(2, "://").nth(1).unwrap_or("");
+        let (domain, path) = rest.split_once('/').map_or((rest, ""), |(d, p)| (d, p));
+
+        if web.host_domain.is_empty() {
+            web.host_domain = domain.split(':').next().unwrap_or("").to_owned();
+        }
+
+        if web.host_path.is_empty() {
+            web.host_path = format!("/{}", path.trim_end_matches('/'));
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigErrors> {
+        let mut problems = Vec::new();
+        let mut require = |value: &str, name: &str| if value.trim().is_empty() {
+            problems.push(format!("{} is required", name));
+        };
+
+        require(&self.discord.token, "discord.token");
+        require(&self.discord.client_id, "discord.client_id");
+        require(&self.discord.client_secret, "discord.client_secret");
+        require(&self.database.url, "database.url");
+        require(&self.web.host_url, "web.host_url");
+        require(&self.moderation.ban_emoji, "moderation.ban_emoji");
+        require(&self.moderation.delete_emoji, "moderation.delete_emoji");
+
+        if self.discord.admin_channel == 0 {
+            problems.push("discord.admin_channel is required".to_owned());
+        }
+
+        if self.discord.auto_admin == 0 {
+            problems.push("discord.auto_admin is required".to_owned());
+        }
+
+        if self.web.bind.parse::<SocketAddr>().is_err() {
+            problems.push(format!("web.bind must be an address like 0.0.0.0:8080, not {:?}", self.web.bind));
+        }
+
+        if self.web.internal_bind.parse::<SocketAddr>().is_err() {
+            problems.push(format!("web.internal_bind must be an address like 127.0.0.1:9090, not {:?}", self.web.internal_bind));
+        } else if self.web.internal_bind == self.web.bind {
+            problems.push("web.internal_bind must differ from web.bind".to_owned());
+        }
+
+        if !self.web.host_url.is_empty() && !is_url(&self.web.host_url) {
+            problems.push("web.host_url must start with http:// or https://".to_owned());
+        }
+
+        if self.web.cookie_sig.len() < 32 {
+            problems.push("web.cookie_sig must be at least 32 bytes long".to_owned());
+        }
+
+        if self.web.secure_cookies == Some(true) && self.web.host_url.starts_with("http://") {
+            problems.push("web.secure_cookies needs an https:// web.host_url, browsers won't send the session back otherwise".to_owned());
+        }
+
+        let economy = &self.economy;
+        for (value, name) in [(economy.push_xp, "push_xp"), (economy.reply_xp, "reply_xp"), (economy.url_xp, "url_xp"),
+            (economy.image_xp, "image_xp"), (economy.report_xp, "report_xp"), (economy.max_tickets, "max_tickets")] {
+            if value < 0 {
+                problems.push(format!("economy.{} can't be negative", name));
+            }
+        }
+
+        if economy.cooldown < 0 {
+            problems.push("economy.cooldown can't be negative".to_owned());
+        }
+
+        if economy.deliver_to < 1 {
+            problems.push("economy.deliver_to must be at least 1".to_owned());
+        }
+
+        if self.cache.ttl == 0 {
+            problems.push("cache.ttl must be at least 1 second".to_owned());
+        }
+
+        if let Some(filter) = &self.logging.filter {
+            if let Err(err) = tracing_subscriber::EnvFilter::try_new(filter) {
+                problems.push(format!("logging.filter is invalid: {}", err));
+            }
+        }
+
+        if self.irc.server.is_none() && !self.irc.channels.is_empty() {
+            problems.push("irc.channels are set without an irc.server".to_owned());
+        }
+
+        if problems.is_empty() { Ok(()) } else { Err(ConfigErrors(problems)) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+    use testing::test_config;
+
+    fn complete() -> Config {
+        let mut cfg = test_config("sqlite:bottle.db".to_owned());
+        cfg.discord.token = "token".to_owned();
+        cfg.discord.client_id = "1".to_owned();
+        cfg.discord.client_secret = "secret".to_owned();
+        cfg
+    }
+
+    #[test]
+    fn complete_config_is_valid() {
+        complete().validate().unwrap();
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let mut cfg = complete();
+        cfg.discord.token = " ".to_owned();
+        cfg.web.bind = "8080".to_owned();
+        cfg.web.cookie_sig = "short".to_owned();
+        cfg.economy.deliver_to = 0;
+        cfg.economy.push_xp = -1;
+        cfg.logging.filter = Some("bottle=loud".to_owned());
+        cfg.irc.channels = vec!["#bottles".to_owned()];
+
+        let problems = cfg.validate().unwrap_err().0;
+        assert_eq!(problems.len(), 7);
+        assert_eq!(&problems[..5], &[
+            "discord.token is required".to_owned(),
+            "web.bind must be an address like 0.0.0.0:8080, not \"8080\"".to_owned(),
+            "web.cookie_sig must be at least 32 bytes long".to_owned(),
+            "economy.push_xp can't be negative".to_owned(),
+            "economy.deliver_to must be at least 1".to_owned()
+        ]);
+        assert!(problems[5].starts_with("logging.filter is invalid"));
+        assert_eq!(problems[6], "irc.channels are set without an irc.server");
+    }
+
+    #[test]
+    fn internal_endpoints_need_their_own_address() {
+        let mut cfg = complete();
+        cfg.web.internal_bind = cfg.web.bind.clone();
+        assert_eq!(cfg.validate().unwrap_err().0, vec!["web.internal_bind must differ from web.bind".to_owned()]);
+
+        cfg.web.internal_bind = "metrics".to_owned();
+        assert_eq!(cfg.validate().unwrap_err().0, vec!["web.internal_bind must be an address like 127.0.0.1:9090, not \"metrics\"".to_owned()]);
+    }
+
+    #[test]
+    fn cookies_are_secure_behind_https() {
+        let mut cfg = complete();
+        assert!(!cfg.web.secure_cookies());
+
+        cfg.web.secure_cookies = Some(true);
+        assert_eq!(cfg.validate().unwrap_err().0,
+            vec!["web.secure_cookies needs an https:// web.host_url, browsers won't send the session back otherwise".to_owned()]);
+
+        cfg.web.host_url = "https://example.com/bottle".to_owned();
+        cfg.validate().unwrap();
+        assert!(cfg.web.secure_cookies());
+
+        cfg.web.secure_cookies = None;
+        assert!(cfg.web.secure_cookies());
+    }
+
+    #[test]
+    fn cookie_scope_comes_from_the_host_url() {
+        let mut cfg = complete();
+        cfg.web.host_url = "https://example.com:8443/bottle/".to_owned();
+        cfg.web.host_domain = String::new();
+        cfg.web.host_path = String::new();
+        cfg.fill_derived();
+        assert_eq!((cfg.web.host_domain.as_str(), cfg.web.host_path.as_str()), ("example.com", "/bottle"));
+
+        cfg.web.host_url = "https://example.com".to_owned();
+        cfg.web.host_path = String::new();
+        cfg.fill_derived();
+        assert_eq!(cfg.web.host_path, "/");
+
+        cfg.web.host_domain = "bottle.example.com".to_owned();
+        cfg.fill_derived();
+        assert_eq!(cfg.web.host_domain, "bottle.example.com");
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let path = env::temp_dir().join(format!("bottle-settings-{}.toml", std::process::id()));
+        fs::write(&path, "[discord]\ntoken = \"token\"\nclient_id = \"1\"\nclient_secret = \"secret\"\nadmin_channel = 5\nauto_admin = 1\n\
+            [database]\nurl = \"sqlite:bottle.db\"\n\
+            [web]\nhost_url = \"http://localhost/bottle\"\ncookie_sig = \"00000000000000000000000000000000\"\n\
+            [moderation]\nban_emoji = \"b\"\ndelete_emoji = \"d\"\n").unwrap();
+
+        env::set_var("BOTTLE_ECONOMY__DELIVER_TO", "3");
+        env::set_var("BOTTLE_IRC__SERVER", "irc.example.com:6697");
+        env::set_var("BOTTLE_IRC__CHANNELS", "#a,#b");
+        let cfg = load(path.to_str().unwrap());
+        for var in ["BOTTLE_ECONOMY__DELIVER_TO", "BOTTLE_IRC__SERVER", "BOTTLE_IRC__CHANNELS"] {
+            env::remove_var(var);
+        }
+        let _ = fs::remove_file(&path);
+
+        let cfg = cfg.unwrap();
+        assert_eq!(cfg.discord.admin_channel, 5);
+        assert_eq!(cfg.economy.deliver_to, 3);
+        assert_eq!(cfg.economy.push_xp, PUSHXP);
+        assert_eq!(cfg.irc.channels, vec!["#a".to_owned(), "#b".to_owned()]);
+        assert_eq!(cfg.web.host_domain, "localhost");
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::*;
+use serenity::client::bridge::gateway::ShardManager;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+/// How long in-flight distributions get before they're left to the ``pending_delivery`` table
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(20);
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+static IN_FLIGHT: Mutex<usize> = Mutex::new(0);
+static DRAINED: Condvar = Condvar::new();
+static HOOKS: Mutex<Vec<Box<dyn FnOnce() + Send>>> = Mutex::new(Vec::new());
+static SIGNALLED: Mutex<bool> = Mutex::new(false);
+static DONE: Condvar = Condvar::new();
+
+/// New bottles are turned away once this is set
+pub fn stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+/// Held by background work that should get the chance to finish before exiting
+pub struct Work(());
+
+impl Drop for Work {
+    fn drop(&mut self) {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        *in_flight -= 1;
+
+        if *in_flight == 0 {
+            DRAINED.notify_all();
+        }
+    }
+}
+
+pub fn track() -> Work {
+    *IN_FLIGHT.lock().unwrap() += 1;
+    Work(())
+}
+
+/// Waits for tracked work, returning how much was still running at the deadline
+fn drain(timeout: Duration) -> usize {
+    let deadline = Instant::now() + timeout;
+    let mut in_flight = IN_FLIGHT.lock().unwrap();
+
+    while *in_flight > 0 {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+
+        in_flight = DRAINED.wait_timeout(in_flight, left).unwrap().0;
+    }
+
+    *in_flight
+}
+
+/// Registers something to stop once the shards are down, like the web listener
+pub fn on_stop<F: FnOnce() + Send + 'static>(f: F) {
+    HOOKS.lock().unwrap().push(Box::new(f));
+}
+
+pub fn stop() {
+    for hook in HOOKS.lock().unwrap().drain(..) {
+        hook();
+    }
+
+    info!("Shut down");
+}
+
+/// On SIGTERM or SIGINT: stop taking bottles, let deliveries drain, then run ``then``
+pub fn handle_signals<F: FnOnce() + Send + 'static>(then: F) {
+    let mut signals = Signals::new([SIGTERM, SIGINT]).expect("Error registering signal handlers.");
+
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            info!("Received signal {}, shutting down", signal);
+            STOPPING.store(true, Ordering::SeqCst);
+
+            match drain(DRAIN_TIMEOUT) {
+                0 => info!("Deliveries drained"),
+                left => warn!("{} deliveries still running, they'll resume from pending_delivery on the next start", left)
+            }
+
+            then();
+            *SIGNALLED.lock().unwrap() = true;
+            DONE.notify_all();
+        }
+    });
+}
+
+/// Taking the shards down returns ``main`` from ``start_autosharded`` to call ``stop``
+pub fn stop_shards_on_signal(manager: Arc<serenity::prelude::Mutex<ShardManager>>) {
+    handle_signals(move || manager.lock().shutdown_all());
+}
+
+/// Blocks until a signal has been handled, for when there's no gateway to wait on
+pub fn wait() {
+    let mut signalled = SIGNALLED.lock().unwrap();
+    while !*signalled {
+        signalled = DONE.wait(signalled).unwrap();
+    }
+}
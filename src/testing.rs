@@ -0,0 +1,243 @@
+//! An in-memory chat service and database harness, so scenarios run without discord
+
+use std::collections::HashMap;
+use std::{env, fs};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicI64, Ordering};
+use diesel::prelude::*;
+
+use model::*;
+use settings::{DiscordConfig, DatabaseConfig, WebConfig, ModerationConfig, LoggingConfig};
+use bottle::{BottleService, Delivery};
+use gateway::{ChatGateway, ChatUser, ChatGuild};
+use platform::{Platform, Incoming, Reacted, RenderedBottle};
+
+pub const ADMIN_CHANNEL: i64 = 900;
+pub const BAN_EMOJI: &str = "🔨";
+pub const DELETE_EMOJI: &str = "🗑";
+
+#[derive(Clone, Debug)]
+pub struct FakeMessage {
+    pub id: i64,
+    pub channel: i64,
+    pub text: Option<String>,
+    pub embed: Option<RenderedBottle>,
+    pub reactions: Vec<String>,
+    pub edits: usize
+}
+
+#[derive(Default)]
+struct FakeState {
+    messages: Vec<FakeMessage>,
+    users: HashMap<i64, ChatUser>,
+    guilds: HashMap<i64, ChatGuild>,
+    managers: Vec<(GuildId, UserId)>
+}
+
+/// Keeps every channel, message, reaction, user and guild the bot touches in memory
+pub struct FakeGateway {
+    state: Mutex<FakeState>,
+    ids: AtomicI64
+}
+
+impl FakeGateway {
+    pub fn new() -> FakeGateway {
+        FakeGateway {state: Mutex::new(FakeState::default()), ids: AtomicI64::new(1_000_000)}
+    }
+
+    pub fn next_id(&self) -> i64 {
+        self.ids.fetch_add(1, Ordering::SeqCst)
+    }
+
+    pub fn add_user(&self, id: UserId, tag: &str) {
+        self.state.lock().unwrap().users.insert(id, ChatUser {id, tag: tag.to_owned(), avatar: None, bot: false});
+    }
+
+    pub fn add_guild(&self, id: GuildId, name: &str) {
+        self.state.lock().unwrap().guilds.insert(id, ChatGuild {id, name: name.to_owned(), icon: None});
+    }
+
+    pub fn add_manager(&self, guild: GuildId, user: UserId) {
+        self.state.lock().unwrap().managers.push((guild, user));
+    }
+
+    pub fn messages_in(&self, channel: i64) -> Vec<FakeMessage> {
+        self.state.lock().unwrap().messages.iter().filter(|m| m.channel == channel).cloned().collect()
+    }
+
+    pub fn message(&self, id: i64) -> Option<FakeMessage> {
+        self.state.lock().unwrap().messages.iter().find(|m| m.id == id).cloned()
+    }
+
+    fn push(&self, channel: i64, text: Option<String>, embed: Option<RenderedBottle>) -> i64 {
+        let id = self.next_id();
+        self.state.lock().unwrap().messages.push(FakeMessage {id, channel, text, embed, reactions: Vec::new(), edits: 0});
+        id
+    }
+
+    fn find<T, F: FnOnce(&mut FakeMessage) -> T>(&self, channel: i64, message: i64, f: F) -> Res<T> {
+        let mut state = self.state.lock().unwrap();
+        let msg = state.messages.iter_mut().find(|m| m.id == message && m.channel == channel)
+            .ok_or("Unknown message")?;
+
+        Ok(f(msg))
+    }
+}
+
+impl ChatGateway for FakeGateway {
+    fn send_bottle(&self, channel: i64, bottle: &RenderedBottle) -> Res<i64> {
+        Ok(self.push(channel, None, Some(bottle.clone())))
+    }
+
+    fn edit_bottle(&self, channel: i64, message: i64, bottle: &RenderedBottle) -> Res<()> {
+        self.find(channel, message, |m| {
+            m.embed = Some(bottle.clone());
+            m.edits += 1;
+        })
+    }
+
+    fn say(&self, channel: i64, text: &str) -> Res<i64> {
+        Ok(self.push(channel, Some(text.to_owned()), None))
+    }
+
+    fn react(&self, channel: i64, message: i64, emoji: &str) -> Res<()> {
+        self.find(channel, message, |m| m.reactions.push(emoji.to_owned()))
+    }
+
+    fn get_user(&self, user: UserId) -> Option<ChatUser> {
+        self.state.lock().unwrap().users.get(&user).cloned()
+    }
+
+    fn get_guild(&self, guild: GuildId) -> Option<ChatGuild> {
+        self.state.lock().unwrap().guilds.get(&guild).cloned()
+    }
+
+    fn text_channels(&self, _: GuildId) -> Res<Vec<(i64, String)>> {
+        Ok(Vec::new())
+    }
+
+    fn create_invite(&self, channel: i64) -> Res<String> {
+        Ok(format!("https://discord.gg/{}", channel))
+    }
+
+    fn can_manage(&self, guild: GuildId, user: UserId) -> Res<bool> {
+        Ok(self.state.lock().unwrap().managers.contains(&(guild, user)))
+    }
+}
+
+pub fn test_config(database_url: String) -> Config {
+    Config {
+        discord: DiscordConfig {admin_channel: ADMIN_CHANNEL, auto_admin: 1, ..Default::default()},
+        database: DatabaseConfig {url: database_url},
+        web: WebConfig {
+            host_url: "http://localhost/bottle".to_owned(), host_domain: "localhost".to_owned(), host_path: "/bottle".to_owned(),
+            cookie_sig: "0".repeat(32), ..Default::default()
+        },
+        moderation: ModerationConfig {ban_emoji: BAN_EMOJI.to_owned(), delete_emoji: DELETE_EMOJI.to_owned()},
+        logging: LoggingConfig {debug: true, filter: None},
+        ..Default::default()
+    }
+}
+
+/// Scenarios share one database, so they run one at a time on a freshly emptied one
+static DATABASE: Mutex<()> = Mutex::new(());
+
+pub struct Harness {
+    pub gateway: Arc<FakeGateway>,
+    pub service: BottleService,
+    _lock: MutexGuard<'static, ()>
+}
+
+impl Harness {
+    /// Uses ``TEST_DATABASE_URL`` if it's set, otherwise a fresh SQLite database in the temp directory
+    pub fn new() -> Harness {
+        let lock = DATABASE.lock().unwrap_or_else(|err| err.into_inner());
+
+        let url = env::var("TEST_DATABASE_URL").unwrap_or_else(|_| {
+            let path = env::temp_dir().join(format!("bottle-test-{}.db", std::process::id())).display().to_string();
+            for file in [path.clone(), format!("{}-wal", path), format!("{}-shm", path)] {
+                let _ = fs::remove_file(file);
+            }
+
+            format!("sqlite:{}", path)
+        });
+
+        let pool = ConnPool::connect(&url).expect("Error connecting to the test database.");
+        pool.run_migrations().expect("Error migrating the test database.");
+
+        if let ConnPool::Postgres(ref pg) = pool {
+            diesel::sql_query("TRUNCATE \"user\", guild, platform_id, webhook RESTART IDENTITY CASCADE")
+                .execute(&mut pg.get().unwrap()).expect("Error emptying the test database.");
+        }
+
+        let gateway = Arc::new(FakeGateway::new());
+        let service = BottleService::new(gateway.clone(), pool, test_config(url)).with_delivery(Delivery::Inline); //scenarios assert on deliveries right after sending
+
+        Harness {gateway, service, _lock: lock}
+    }
+
+    pub fn conn(&self) -> Conn {
+        self.service.pool.get_conn().unwrap()
+    }
+
+    pub fn user(&self, id: UserId, tag: &str) -> UserId {
+        self.gateway.add_user(id, tag);
+
+        let conn = &mut self.conn();
+        User::get(id, conn).update(conn).unwrap();
+        id
+    }
+
+    pub fn admin(&self, id: UserId, tag: &str) -> UserId {
+        self.user(id, tag);
+
+        let conn = &mut self.conn();
+        let mut u = User::get(id, conn);
+        u.admin = true;
+        u.update(conn).unwrap();
+        id
+    }
+
+    /// Registers a guild with its bottle channel configured
+    pub fn guild(&self, id: GuildId, name: &str, channel: i64) -> GuildId {
+        self.gateway.add_guild(id, name);
+
+        let conn = &mut self.conn();
+        let mut g = Guild::get(id, conn);
+        g.bottle_channel = Some(channel);
+        g.update(conn).unwrap();
+        id
+    }
+
+    /// Posts a message as the user, returning its id and whatever the bot replied
+    pub fn send(&self, user: UserId, channel: i64, guild: Option<GuildId>, content: &str) -> (i64, Res<Option<String>>) {
+        let message = self.gateway.next_id();
+        let incoming = Incoming {platform: Platform::Discord, user, message, channel, content: content.to_owned(), url: None, image: None};
+
+        (message, self.service.new_bottle(&incoming, guild, &mut self.conn()).map(|x| x.map(|x| x.into_owned())))
+    }
+
+    pub fn react(&self, user: UserId, message: i64, emoji: &str, add: bool) -> Res<()> {
+        self.service.react(&Reacted {user, message, emoji: emoji.to_owned()}, add, &mut self.conn())
+    }
+
+    /// Stores a bottle straight in the database, skipping the service's checks and delivery
+    pub fn bottle(&self, user: UserId, guild: Option<GuildId>, channel: i64, contents: &str) -> Bottle {
+        self.draft(user, guild, channel, contents).make(&mut self.conn()).unwrap()
+    }
+
+    /// A plain bottle to adjust before making it, for replies, anonymous bottles and the like
+    pub fn draft(&self, user: UserId, guild: Option<GuildId>, channel: i64, contents: &str) -> MakeBottle {
+        MakeBottle {
+            user, message: self.gateway.next_id(), guild, reply_to: None, time_pushed: now(),
+            contents: contents.to_owned(), url: None, image: None,
+            channel, anonymous: false, platform: Platform::Discord.id()
+        }
+    }
+
+    /// Records a delivered copy of the bottle, as if it had reached the guild's channel at ``time``
+    pub fn received(&self, bottle: &Bottle, guild: GuildId, channel: i64, time: DTime) -> ReceivedBottle {
+        MakeReceivedBottle {bottle: bottle.id, channel, message: self.gateway.next_id(), time_recieved: time, platform: Platform::Discord.id(), guild: Some(guild)}
+            .make(&mut self.conn()).unwrap()
+    }
+}
@@ -1,12 +1,11 @@
-use std::{collections::HashMap, fmt, sync::Arc};
-use uuid::Uuid;
+use std::{collections::HashMap, fmt, sync::Arc, time::Instant};
 
 use oauth2::{self, TokenResponse, CsrfToken};
 use oauth2::basic::BasicClient;
 use iron::{self, AroundMiddleware};
 use iron::prelude::*;
 use iron::{Handler, BeforeMiddleware, AfterMiddleware, status, modifiers::RedirectRaw, headers};
-use cookie::{Cookie, CookieBuilder};
+use cookie::{Cookie, CookieJar, SameSite};
 use typemap::Key;
 use handlebars_iron::{Template, HandlebarsEngine, DirectorySource};
 #[cfg(feature = "watch")]
@@ -15,16 +14,23 @@ use router::{Router, NoRoute};
 use staticfile::Static;
 use mount::Mount;
 use params::{Params, Value};
-use serenity::model::id;
-use serenity::model::guild;
 use serde_derive::{Deserialize, Serialize};
 use serde_json;
-use log::debug;
+use log::{debug, error};
 use futures_lite::future;
 
 use model::*;
 use data::*;
-use bottle;
+use bottle::{self, BottleService};
+use gateway::ChatGateway;
+use api;
+use metrics;
+use health;
+use shutdown;
+
+/// Rows per page of ``/leaderboard/users`` and ``/leaderboard/guilds``
+const LEADERBOARD_PAGE: i64 = 25;
+const ARCHIVE_PAGE: i64 = 20;
 
 #[derive(Debug)]
 struct InternalError(String);
@@ -33,64 +39,93 @@ struct ParamError;
 #[derive(Debug)]
 struct AuthError;
 
-#[derive(Clone, Deserialize, Serialize)]
-struct SessionData {
-    id: Uuid,
-    redirect: Option<String>,
-    csrf: Option<String>
+struct DSession;
+impl Key for DSession {
+    type Value = Session;
 }
 
-struct DSessionData;
-impl Key for DSessionData {
-    type Value = SessionData;
+fn session_cookie(ses: &Session, cfg: &Config) -> Cookie<'static> {
+    Cookie::build("session", ses.id.clone())
+        .domain(cfg.web.host_domain.clone()).path(cfg.web.host_path.clone())
+        .secure(cfg.web.secure_cookies()).http_only(true).same_site(SameSite::Lax)
+        .max_age(cookie::time::Duration::days(SESSION_DAYS)).finish()
 }
 
-impl SessionData {
-    fn new() -> Self {
-        SessionData { id: Uuid::new_v4(), redirect: None, csrf: None}
-    }
+/// Keeps only the session id client side, in a private (encrypted and authenticated) cookie
+struct SessionStorage {key: cookie::Key}
 
-    fn to_cookie(&self, cfg: &Config) -> Cookie {
-        Cookie::build("session", serde_json::to_string(self).unwrap())
-            .domain(cfg.host_domain).path(cfg.host_path).secure(true).finish()
+impl SessionStorage {
+    fn new(cfg: &Config) -> Self {
+        assert!(cfg.web.cookie_sig.len() >= 32, "cookie_sig must be at least 32 bytes long");
+        SessionStorage {key: cookie::Key::derive_from(cfg.web.cookie_sig.as_bytes())}
     }
-
-    
 }
 
-struct SessionStorage {}
-
 impl AroundMiddleware for SessionStorage {
     fn around(self, handler: Box<dyn Handler>) -> Box<dyn Handler> {
         Box::new(move |req: &mut Request| -> IronResult<Response> {
-            let ses = match req.headers.iter()
-                .filter(|x| x.is::<headers::Cookie>())
-                .filter_map(|x| x.value::<headers::Cookie>())
-                .next().and_then(|c| c.0.iter().filter_map(|s| Cookie::parse(s).ok())
-                    .find(|c| c.name()=="session")) {
-                Some(cookie) =>
-                    serde_json::from_str(cookie.value())
-                        .map_err(|e| IronError::new(ParamError, status::BadRequest))?,
-                _ => SessionData::new()
-            };
+            let mut jar = CookieJar::new();
+            for c in req.headers.get::<headers::Cookie>().into_iter().flat_map(|c| c.0.iter()) {
+                if let Ok(c) = Cookie::parse(c.clone()) {
+                    jar.add_original(c);
+                }
+            }
 
-            req.extensions.insert::<DSessionData>(ses);
+            let original = jar.private(&self.key).get("session")
+                .and_then(|c| Session::get(c.value(), &mut req.get_conn().ok()?).ok());
+
+            req.extensions.insert::<DSession>(original.clone().unwrap_or_else(Session::new));
             let mut resp = handler.handle(req)?;
 
-            let data = req.extensions.get::<DSessionData>().unwrap();
-            resp.headers.set(headers::SetCookie(vec![data.to_cookie(req.get_cfg()).to_string()]));
+            let ses = req.extensions.get::<DSession>().unwrap().clone();
+            let changed = match original {
+                Some(ref original) => original != &ses,
+                None => !ses.is_empty()
+            };
+
+            if changed {
+                InternalError::with(|| Ok(ses.update(&mut req.get_conn()?)?))?;
+
+                jar.private_mut(&self.key).add(session_cookie(&ses, req.get_cfg()));
+                resp.headers.set(headers::SetCookie(jar.delta().map(|c| c.to_string()).collect()));
+            }
+
             Ok(resp)
         })
     }
 }
 
+/// One structured line per request, anything logged while handling it carries the request span
+struct RequestLog;
+
+impl AroundMiddleware for RequestLog {
+    fn around(self, handler: Box<dyn Handler>) -> Box<dyn Handler> {
+        Box::new(move |req: &mut Request| -> IronResult<Response> {
+            let path = format!("/{}", req.url.path().join("/"));
+            let span = tracing::info_span!("request", method = %req.method, path = %path);
+            let _span = span.enter();
+
+            let start = Instant::now();
+            let res = handler.handle(req);
+
+            let status = match &res {
+                Ok(resp) => resp.status,
+                Err(err) => err.response.status
+            }.map_or(0, |s| s.to_u16());
+
+            tracing::info!(status, elapsed_ms = start.elapsed().as_millis() as u64, "handled request");
+            res
+        })
+    }
+}
+
 trait GetSession {
-    fn session(&mut self) -> &mut SessionData;
+    fn session(&mut self) -> &mut Session;
 }
 
 impl<'a,'b> GetSession for Request<'a,'b> {
-    fn session(&mut self) -> &mut SessionData {
-        self.extensions.get_mut::<DSessionData>().unwrap()
+    fn session(&mut self) -> &mut Session {
+        self.extensions.get_mut::<DSession>().unwrap()
     }
 }
 
@@ -105,10 +140,41 @@ impl iron::Error for InternalError {}
 
 impl InternalError {
     fn with<T, F: FnMut() -> Res<T>>(mut f: F) -> IronResult<T> {
-        f().map_err(|err| {
-            IronError::new(InternalError(err.to_string()), status::InternalServerError)
-        })
+        f().map_err(InternalError::from_err)
     }
+
+    /// Only the user-facing message goes in the response, the detail goes to the log
+    fn from_err(err: BottleError) -> IronError {
+        if err.internal() {
+            error!("Error handling request: {}", err);
+        }
+
+        IronError::new(InternalError(err.user_message()), error_status(&err))
+    }
+}
+
+pub(crate) fn error_status(err: &BottleError) -> status::Status {
+    match err {
+        BottleError::NotFound(_) => status::NotFound,
+        BottleError::Permission(_) | BottleError::Banned => status::Forbidden,
+        BottleError::Invalid(_) | BottleError::Empty => status::BadRequest,
+        BottleError::Cooldown {..} => status::TooManyRequests,
+        //an exhausted pool clears up by itself, so it's a 503 rather than a 500
+        BottleError::Unavailable(_) | BottleError::ShuttingDown => status::ServiceUnavailable,
+        _ => status::InternalServerError
+    }
+}
+
+pub(crate) fn db_conn(req: &Request) -> IronResult<Conn> {
+    req.get_conn().map_err(InternalError::from_err)
+}
+
+fn params(req: &mut Request) -> IronResult<params::Map> {
+    req.get_ref::<Params>().map(Clone::clone).map_err(|_| IronError::new(ParamError, status::BadRequest))
+}
+
+fn oauth(req: &Request) -> IronResult<BasicClient> {
+    req.extensions.get::<DOauth2>().cloned().ok_or_else(|| IronError::new(AuthError, status::InternalServerError))
 }
 
 impl fmt::Display for ParamError {
@@ -127,13 +193,15 @@ impl fmt::Display for AuthError {
 
 impl iron::Error for AuthError {}
 
-struct PrerequisiteMiddleware {pool: ConnPool, oauth: BasicClient, cfg: Config}
+#[derive(Clone)]
+pub(crate) struct PrerequisiteMiddleware {service: BottleService, oauth: BasicClient}
 
 impl BeforeMiddleware for PrerequisiteMiddleware {
     fn before(&self, req: &mut Request) -> IronResult<()> {
-        req.extensions.insert::<DConn>(self.pool.clone());
+        req.extensions.insert::<DConn>(self.service.pool.clone());
         req.extensions.insert::<DOauth2>(self.oauth.clone());
-        req.extensions.insert::<DConfig>(self.cfg.clone());
+        req.extensions.insert::<DConfig>(self.service.cfg.clone());
+        req.extensions.insert::<DService>(self.service.clone());
 
         Ok(())
     }
@@ -145,6 +213,12 @@ impl<'a, 'b> GetConnection for Request<'a, 'b> {
     }
 }
 
+impl<'a, 'b> GetService for Request<'a, 'b> {
+    fn get_service(&self) -> BottleService {
+        self.extensions.get::<DService>().unwrap().clone()
+    }
+}
+
 impl<'a, 'b> GetConfig for Request<'a, 'b> {
     fn get_cfg(&self) -> &Config {
         self.extensions.get::<DConfig>().unwrap()
@@ -163,47 +237,61 @@ impl AfterMiddleware for StatusMiddleware {
 }
 
 #[derive(Deserialize, Serialize)]
-struct BottlePage {
+pub(crate) struct BottlePage {
     contents: String, time_pushed: String, image: Option<String>, guild: Option<String>
 }
 
+impl BottlePage {
+    fn new(bottle: Bottle, gateway: &dyn ChatGateway) -> Self {
+        BottlePage {
+            contents: bottle.contents,
+            time_pushed: bottle.time_pushed.format(&"%m/%d/%y - %H:%M").to_string(),
+            image: bottle.image,
+            guild: bottle.guild.map(|gid| gateway.guild_name(gid))
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize)]
-struct GuildContribution {guild: String, gid: i64, xp: i64}
+pub(crate) struct GuildContribution {pub(crate) guild: String, pub(crate) gid: i64, pub(crate) xp: i64}
 #[derive(Deserialize, Serialize)]
-struct UserPage {
-    tag: String, admin: bool, pfp: String, xp: i32, ranked: i64, num_bottles: i64, contributions: Vec<GuildContribution>, recent_bottles: Vec<BottlePage>
+pub(crate) struct UserPage {
+    uid: i64, tag: String, admin: bool, pfp: String, xp: i32, ranked: Option<i64>, num_bottles: i64, contributions: Vec<GuildContribution>, recent_bottles: Vec<BottlePage>,
+    hide_contributions: bool, hide_bottles: bool
 }
 
 #[derive(Deserialize, Serialize)]
-struct UserContribution {user: String, uid: i64, xp: i64}
+pub(crate) struct UserContribution {pub(crate) user: String, pub(crate) uid: i64, pub(crate) xp: i64}
 #[derive(Deserialize, Serialize)]
-struct GuildPage {
+pub(crate) struct GuildPage {
     name: String, pfp: String, invite: Option<String>, xp: i64, ranked: Option<i64>, num_bottles: i64, contributions: Vec<UserContribution>
 }
 
-fn get_user_data(uid: UserId, conn: &Conn, cfg: &Config) -> Res<UserPage> {
+pub(crate) fn get_user_data(uid: UserId, gateway: &dyn ChatGateway, conn: &mut Conn, cfg: &Config) -> Res<UserPage> {
     debug!("Getting user page data for {}", uid);
 
     let udata = User::get(uid, conn);
-    let user = id::UserId(udata.id as u64).to_user()?;
+    let privacy = Privacy::get(uid, conn);
+    if privacy.hide_profile {
+        return Err(BottleError::Permission("This profile is private".to_owned()));
+    }
+
+    let user = gateway.get_user(udata.id).ok_or(BottleError::NotFound("user"))?;
+
+    let contributions = if privacy.hide_contributions { Vec::new() } else { udata.get_contributions(5, conn)? };
+    let recent_bottles = if privacy.hide_bottles { Vec::new() } else { udata.get_last_bottles(10, conn)? };
 
     let data = UserPage {
-        tag: user.tag(), admin: udata.admin,
-        pfp: user.avatar_url().unwrap_or_else(|| anonymous_url(cfg)),
+        uid: udata.id, tag: user.tag, admin: udata.admin,
+        pfp: user.avatar.unwrap_or_else(|| anonymous_url(cfg)),
         xp: udata.xp,
-        ranked: udata.get_ranking(conn)?,
+        ranked: udata.get_ranking(conn).ok(),
         num_bottles: udata.get_num_bottles(conn)?,
-        contributions: udata.get_contributions(5, conn)?.into_iter().map(|c| {
-            GuildContribution {guild: get_guild_name(c.guild), gid: c.guild, xp: c.xp as i64}
+        contributions: contributions.into_iter().map(|c| {
+            GuildContribution {guild: gateway.guild_name(c.guild), gid: c.guild, xp: c.xp as i64}
         }).collect(),
-        recent_bottles: udata.get_last_bottles(10, conn)?.into_iter().map(|bottle| {
-            BottlePage {
-                contents: bottle.contents,
-                time_pushed: bottle.time_pushed.format(&"%m/%d/%y - %H:%M").to_string(),
-                image: bottle.image,
-                guild: bottle.guild.map(get_guild_name)
-            }
-        }).collect()
+        recent_bottles: recent_bottles.into_iter().map(|b| BottlePage::new(b, gateway)).collect(),
+        hide_contributions: privacy.hide_contributions, hide_bottles: privacy.hide_bottles
     };
 
     Ok(data)
@@ -212,7 +300,7 @@ fn get_user_data(uid: UserId, conn: &Conn, cfg: &Config) -> Res<UserPage> {
 fn user(req: &mut Request) -> IronResult<Response> {
     let udata = req.extensions.get::<Router>().unwrap()
         .find("user").and_then(|x| x.parse().ok()).and_then(|uid| {
-        get_user_data(uid, &req.get_conn(), &req.get_cfg()).ok()
+        get_user_data(uid, req.get_service().gateway.as_ref(), &mut req.get_conn().ok()?, &req.get_cfg()).ok()
     });
 
     match udata {
@@ -221,20 +309,20 @@ fn user(req: &mut Request) -> IronResult<Response> {
     }
 }
 
-fn get_guild_data(gid: GuildId, conn: &Conn, cfg: &Config) -> Res<GuildPage> {
+pub(crate) fn get_guild_data(gid: GuildId, gateway: &dyn ChatGateway, conn: &mut Conn, cfg: &Config) -> Res<GuildPage> {
     debug!("Getting guild page data for {}", gid);
 
     let gdata = Guild::get(gid, conn);
-    let guild: guild::PartialGuild = id::GuildId(gid as u64).to_partial_guild()?;
+    let guild = gateway.get_guild(gid).ok_or(BottleError::NotFound("guild"))?;
 
     let data = GuildPage {
-        name: guild.name.clone(), invite: gdata.invite.clone(),
-        pfp: guild.icon_url().unwrap_or_else(|| anonymous_url(cfg)).clone(),
+        name: guild.name, invite: gdata.invite.clone(),
+        pfp: guild.icon.unwrap_or_else(|| anonymous_url(cfg)),
         xp: gdata.get_xp(conn)?,
         ranked: gdata.get_ranking(conn).ok(),
         num_bottles: gdata.get_num_bottles(conn)?,
         contributions: gdata.get_contributions(15, conn)?.into_iter().map(|c| {
-            UserContribution {user: get_user_name(c.user), uid: c.user, xp: c.xp as i64}
+            UserContribution {user: gateway.user_name(c.user), uid: c.user, xp: c.xp as i64}
         }).collect()
     };
 
@@ -244,7 +332,7 @@ fn get_guild_data(gid: GuildId, conn: &Conn, cfg: &Config) -> Res<GuildPage> {
 fn guild(req: &mut Request) -> IronResult<Response> {
     let gdata = req.extensions.get::<Router>().unwrap()
         .find("guild").and_then(|x| x.parse().ok()).and_then(|gid| {
-        get_guild_data(gid, &req.get_conn(), &req.get_cfg()).ok()
+        get_guild_data(gid, req.get_service().gateway.as_ref(), &mut req.get_conn().ok()?, &req.get_cfg()).ok()
     });
 
     match gdata {
@@ -269,18 +357,61 @@ impl DUserData {
     }
 }
 
-fn get_user(ses: &SessionData, conn: &Conn) -> Option<User> {
-    User::from_session(ses.id, conn).ok()
+#[derive(Clone, Deserialize, Serialize, Debug)]
+struct DGuildData {
+    id: String,
+    owner: bool,
+    permissions: serde_json::Value
 }
 
-fn set_tok(ses: &SessionData, tok: oauth2::basic::BasicTokenResponse, conn: &Conn) -> Res<()> {
-    let uid = DUserData::get(tok.access_token().secret())?.id.parse()?;
-    let mut u = User::get(uid, conn);
+const GETGUILDS: &str = "https://discordapp.com/api/users/@me/guilds";
+const MANAGE_GUILD: u64 = 0x20;
+impl DGuildData {
+    fn get(access_token: &str) -> Res<Vec<Self>> {
+        let fut = reqwest::Client::new().get(GETGUILDS)
+            .header("Authorization", format!("Bearer {}", access_token)).send();
+        Ok(future::block_on(future::block_on(fut)?.json::<Vec<DGuildData>>())?)
+    }
+
+    fn can_manage(&self) -> bool {
+        let perms = match &self.permissions {
+            serde_json::Value::Number(x) => x.as_u64(),
+            serde_json::Value::String(x) => x.parse().ok(),
+            _ => None
+        };
+
+        self.owner || perms.map_or(false, |x| x & MANAGE_GUILD != 0)
+    }
+}
+
+fn get_user(ses: &Session, conn: &mut Conn) -> Option<User> {
+    ses.user.map(|uid| User::get(uid, conn))
+}
 
-    u.session = Some(ses.id);
+fn set_tok(tok: &oauth2::basic::BasicTokenResponse, conn: &mut Conn) -> Res<UserId> {
+    let uid = DUserData::get(tok.access_token().secret())?.id.parse()?;
+    let u = User::get(uid, conn);
     u.update(conn)?;
 
-    Ok(())
+    let managed: Vec<GuildId> = DGuildData::get(tok.access_token().secret())?.into_iter()
+        .filter(DGuildData::can_manage).filter_map(|g| g.id.parse().ok()).collect();
+    u.set_managed_guilds(&managed, conn)?;
+
+    Ok(uid)
+}
+
+fn login(req: &mut Request, redirect: String) -> IronResult<Response> {
+    let (url,tok) = oauth(req)?
+        .authorize_url(CsrfToken::new_random)
+        .add_scope(oauth2::Scope::new("identify".to_string()))
+        .add_scope(oauth2::Scope::new("guilds".to_string()))
+        .url();
+
+    let ses = req.session();
+    ses.csrf = Some(tok.secret().to_string());
+    ses.redirect = Some(redirect);
+
+    Ok(Response::with((status::TemporaryRedirect, RedirectRaw(url.to_string()))))
 }
 
 fn report(req: &mut Request) -> IronResult<Response> {
@@ -288,7 +419,7 @@ fn report(req: &mut Request) -> IronResult<Response> {
         .find("bottle").and_then(|x| x.parse().ok())
         .ok_or_else(|| IronError::new(ParamError, status::BadRequest))?;
 
-    let conn = &req.get_conn();
+    let conn = &mut db_conn(req)?;
     let ses = req.session();
 
     if let Ok (bottle) = Bottle::get(bid, conn) {
@@ -299,11 +430,13 @@ fn report(req: &mut Request) -> IronResult<Response> {
                     let alreadyexists = Report::exists(bid, conn)?;
 
                     if (x.admin || !banned) && !alreadyexists {
-                        let received_bottle = bottle::report_bottle(&bottle, x.id, conn, &req.get_cfg())?;
+                        let received_bottle = req.get_service().report_bottle(&bottle, x.id, conn)?;
                         Report { user: x.id, bottle: bid, received_bottle: Some(received_bottle) }.make(conn)?;
 
-                        x.xp += REPORTXP;
+                        let xp = req.get_cfg().economy.report_xp;
+                        x.xp += xp;
                         x.update(conn)?;
+                        MakeXpEvent {user: x.id, guild: None, bottle: Some(bid), xp, reason: REPORT_REASON, time: now()}.make(conn)?;
                     }
 
                     let mut data = HashMap::new();
@@ -316,15 +449,8 @@ fn report(req: &mut Request) -> IronResult<Response> {
                 Ok(Response::with((status::Ok, Template::new("reportmade", data))))
             },
             None => {
-                let (url,tok) = req.extensions.get::<DOauth2>().unwrap().clone()
-                    .authorize_url(CsrfToken::new_random)
-                    .add_scope(oauth2::Scope::new("identify".to_string()))
-                    .url();
-
-                ses.csrf = Some(tok.secret().to_string());
-                ses.redirect = Some(report_url(bid, &req.get_cfg()));
-
-                Ok(Response::with((status::TemporaryRedirect, RedirectRaw(url.to_string()))))
+                let redirect = report_url(bid, &req.get_cfg());
+                login(req, redirect)
             }
         }
     } else {
@@ -332,26 +458,403 @@ fn report(req: &mut Request) -> IronResult<Response> {
     }
 }
 
+fn login_page(req: &mut Request) -> IronResult<Response> {
+    let conn = &mut db_conn(req)?;
+    let redirect = me_url(&req.get_cfg());
+
+    match get_user(req.session(), conn) {
+        Some(_) => Ok(Response::with((status::TemporaryRedirect, RedirectRaw(redirect)))),
+        None => login(req, redirect)
+    }
+}
+
+fn logout(req: &mut Request) -> IronResult<Response> {
+    let conn = &mut db_conn(req)?;
+
+    InternalError::with(|| Ok(Session::revoke(&req.session().id, conn)?))?;
+    *req.session() = Session::new();
+    Ok(Response::with((status::TemporaryRedirect, RedirectRaw(req.get_cfg().web.host_url.clone()))))
+}
+
+#[derive(Serialize)]
+struct DeliveryPage {
+    guild: Option<String>, gid: Option<i64>, time_recieved: String
+}
+
+#[derive(Serialize)]
+struct MyBottlePage {
+    bottle: BottlePage, anonymous: bool, deleted: bool, deliveries: Vec<DeliveryPage>
+}
+
+#[derive(Serialize)]
+struct ReplyPage {
+    author: String, bottle: BottlePage
+}
+
+#[derive(Serialize)]
+struct ManagedGuildPage {
+    name: String, gid: i64, configured: bool
+}
+
+#[derive(Serialize)]
+struct ApiKeyPage {
+    id: String, short: String, name: String, rate_limit: i32, created: String
+}
+
+#[derive(Serialize)]
+struct MePage {
+    uid: i64, tag: String, pfp: String, xp: i32,
+    bottles: Vec<MyBottlePage>, replies: Vec<ReplyPage>, guilds: Vec<ManagedGuildPage>,
+    keys: Vec<ApiKeyPage>, new_key: Option<String>, token: String
+}
+
+fn get_me_data(udata: &User, new_key: Option<String>, token: String, gateway: &dyn ChatGateway, conn: &mut Conn, cfg: &Config) -> Res<MePage> {
+    debug!("Getting dashboard data for {}", udata.id);
+
+    let user = gateway.get_user(udata.id).ok_or(BottleError::NotFound("user"))?;
+    let mut bottles = udata.get_all_bottles(conn)?;
+    bottles.sort_by(|a, b| b.time_pushed.cmp(&a.time_pushed));
+
+    let bottles = bottles.into_iter().take(20).map(|bottle| {
+        let deliveries = ReceivedBottle::get_trail(bottle.id, conn)?.into_iter().map(|(recv, gid)| {
+            DeliveryPage {
+                guild: gid.map(|gid| gateway.guild_name(gid)), gid,
+                time_recieved: recv.time_recieved.format(&"%m/%d/%y - %H:%M").to_string()
+            }
+        }).collect();
+
+        Ok(MyBottlePage {anonymous: bottle.anonymous, deleted: bottle.deleted, deliveries, bottle: BottlePage::new(bottle, gateway)})
+    }).collect::<Res<Vec<_>>>()?;
+
+    Ok(MePage {
+        uid: udata.id, tag: user.tag, xp: udata.xp,
+        pfp: user.avatar.unwrap_or_else(|| anonymous_url(cfg)),
+        bottles,
+        replies: udata.get_replies(20, conn)?.into_iter()
+            .map(|bottle| ReplyPage {author: bottle::author_name(&bottle, gateway), bottle: BottlePage::new(bottle, gateway)}).collect(),
+        guilds: udata.get_managed_guilds(conn)?.into_iter()
+            .map(|g| ManagedGuildPage {name: gateway.guild_name(g.id), gid: g.id, configured: g.bottle_channel.is_some()}).collect(),
+        keys: ApiKey::get_from_user(udata.id, conn)?.into_iter().map(|k| ApiKeyPage {
+            short: k.id.chars().take(8).collect(), id: k.id, name: k.name, rate_limit: k.rate_limit,
+            created: k.created.format(&"%m/%d/%y - %H:%M").to_string()
+        }).collect(),
+        new_key, token
+    })
+}
+
+fn render_me(req: &mut Request, user: &User, new_key: Option<String>, conn: &mut Conn) -> IronResult<Response> {
+    let token = CsrfToken::new_random().secret().to_string();
+    req.session().form_token = Some(token.clone());
+
+    let service = req.get_service();
+    let data = InternalError::with(|| get_me_data(user, new_key.clone(), token.clone(), service.gateway.as_ref(), conn, &service.cfg))?;
+    Ok(Response::with((status::Ok, Template::new("me", &data))))
+}
+
+fn me(req: &mut Request) -> IronResult<Response> {
+    let conn = &mut db_conn(req)?;
+
+    match get_user(req.session(), conn) {
+        Some(user) => render_me(req, &user, None, conn),
+        None => {
+            let redirect = me_url(&req.get_cfg());
+            login(req, redirect)
+        }
+    }
+}
+
+fn check_form(req: &mut Request, params: &params::Map) -> IronResult<User> {
+    let conn = &mut db_conn(req)?;
+    let user = get_user(req.session(), conn)
+        .ok_or_else(|| IronError::new(AuthError, status::Unauthorized))?;
+
+    match params.find(&["token"]) {
+        Some(Value::String(token)) if Some(token) == req.session().form_token.as_ref() => Ok(user),
+        _ => Err(IronError::new(AuthError, status::BadRequest))
+    }
+}
+
+fn make_key(req: &mut Request) -> IronResult<Response> {
+    let params = params(req)?;
+    let user = check_form(req, &params)?;
+    let conn = &mut db_conn(req)?;
+
+    let name = match params.find(&["name"]) {
+        Some(Value::String(x)) if !x.trim().is_empty() => x.trim().to_owned(),
+        _ => "API key".to_owned()
+    };
+
+    let (key, api_key) = ApiKey::new(user.id, name);
+    InternalError::with(|| Ok(api_key.make(conn)?))?;
+
+    render_me(req, &user, Some(key), conn)
+}
+
+fn revoke_key(req: &mut Request) -> IronResult<Response> {
+    let params = params(req)?;
+    let user = check_form(req, &params)?;
+    let conn = &mut db_conn(req)?;
+
+    if let Some(Value::String(id)) = params.find(&["key"]) {
+        InternalError::with(|| Ok(ApiKey::revoke(id, user.id, conn)?))?;
+    }
+
+    render_me(req, &user, None, conn)
+}
+
+#[derive(Serialize)]
+struct ChannelOption {
+    id: String, name: String, bottle: bool, admin: bool
+}
+
+#[derive(Serialize)]
+struct GuildSettingsPage {
+    gid: i64, name: String, token: String, saved: bool, error: Option<String>,
+    channels: Vec<ChannelOption>, prefix: String, public: bool, blocked_words: String,
+    allow_anonymous: bool, allow_images: bool, allow_links: bool, receive_bottles: bool, receive_replies: bool
+}
+
+fn get_text_channels(gid: GuildId, gateway: &dyn ChatGateway) -> Res<Vec<(i64, String)>> {
+    let mut channels = gateway.text_channels(gid)?;
+
+    channels.sort_by(|a, b| a.1.cmp(&b.1));
+    Ok(channels)
+}
+
+/// The guilds from login only narrow things down, permissions are asked for again in case they were revoked since
+fn get_manager(req: &mut Request, gid: GuildId, conn: &mut Conn) -> IronResult<Option<User>> {
+    let gateway = req.get_service().gateway;
+
+    match get_user(req.session(), conn) {
+        Some(user) => {
+            if InternalError::with(|| Ok(user.admin || (user.can_manage(gid, conn)? && gateway.can_manage(gid, user.id)?)))? {
+                Ok(Some(user))
+            } else {
+                Err(IronError::new(AuthError, status::Forbidden))
+            }
+        },
+        None => Ok(None)
+    }
+}
+
+fn render_guild_settings(req: &mut Request, gdata: &Guild, saved: bool, error: Option<String>) -> IronResult<Response> {
+    let token = CsrfToken::new_random().secret().to_string();
+    req.session().form_token = Some(token.clone());
+
+    let gateway = req.get_service().gateway;
+    let channels = InternalError::with(|| get_text_channels(gdata.id, gateway.as_ref()))?;
+    let data = GuildSettingsPage {
+        gid: gdata.id, name: gateway.guild_name(gdata.id), token, saved, error,
+        channels: channels.into_iter().map(|(cid, name)| ChannelOption {
+            id: cid.to_string(), name, bottle: gdata.bottle_channel == Some(cid), admin: gdata.admin_channel == Some(cid)
+        }).collect(),
+        prefix: gdata.prefix.clone().unwrap_or_default(), public: gdata.invite.is_some(),
+        blocked_words: gdata.blocked_words.clone().unwrap_or_default(),
+        allow_anonymous: gdata.allow_anonymous, allow_images: gdata.allow_images, allow_links: gdata.allow_links,
+        receive_bottles: gdata.receive_bottles, receive_replies: gdata.receive_replies
+    };
+
+    Ok(Response::with((status::Ok, Template::new("guildsettings", &data))))
+}
+
+fn guild_settings(req: &mut Request) -> IronResult<Response> {
+    let gid: GuildId = req.extensions.get::<Router>().unwrap()
+        .find("guild").and_then(|x| x.parse().ok())
+        .ok_or_else(|| IronError::new(ParamError, status::BadRequest))?;
+
+    let conn = &mut db_conn(req)?;
+    if get_manager(req, gid, conn)?.is_none() {
+        let redirect = guild_settings_url(gid, &req.get_cfg());
+        return login(req, redirect);
+    }
+
+    render_guild_settings(req, &Guild::get(gid, conn), false, None)
+}
+
+fn save_guild_settings(req: &mut Request) -> IronResult<Response> {
+    let params = params(req)?;
+    let gid: GuildId = req.extensions.get::<Router>().unwrap()
+        .find("guild").and_then(|x| x.parse().ok())
+        .ok_or_else(|| IronError::new(ParamError, status::BadRequest))?;
+
+    let conn = &mut db_conn(req)?;
+    get_manager(req, gid, conn)?.ok_or_else(|| IronError::new(AuthError, status::Unauthorized))?;
+
+    match params.find(&["token"]) {
+        Some(Value::String(token)) if Some(token) == req.session().form_token.as_ref() => (),
+        _ => return Err(IronError::new(AuthError, status::BadRequest))
+    }
+
+    let text = |name: &str| match params.find(&[name]) {
+        Some(Value::String(x)) if !x.trim().is_empty() => Some(x.trim().to_owned()),
+        _ => None
+    };
+
+    let checked = |name: &str| params.find(&[name]).is_some();
+
+    let mut gdata = Guild::get(gid, conn);
+    let gateway = req.get_service().gateway;
+    let channels: Vec<i64> = InternalError::with(|| get_text_channels(gid, gateway.as_ref()))?.into_iter().map(|(cid, _)| cid).collect();
+    let channel = |name: &str| -> Result<Option<i64>, String> {
+        match text(name).map(|x| x.parse::<i64>()) {
+            None => Ok(None),
+            Some(Ok(cid)) if channels.contains(&cid) => Ok(Some(cid)),
+            _ => Err("That channel isn't in this guild!".to_owned())
+        }
+    };
+
+    let changes = (|| -> Result<(), String> {
+        gdata.bottle_channel = channel("bottle_channel")?;
+        gdata.admin_channel = channel("admin_channel")?;
+
+        gdata.prefix = match text("prefix") {
+            Some(ref x) if x.chars().count() != 1 => return Err("The prefix must be a single character!".to_owned()),
+            x => x
+        };
+
+        Ok(())
+    })();
+
+    if let Err(err) = changes {
+        return render_guild_settings(req, &Guild::get(gid, conn), false, Some(err));
+    }
+
+    gdata.blocked_words = text("blocked_words");
+    gdata.allow_anonymous = checked("allow_anonymous");
+    gdata.allow_images = checked("allow_images");
+    gdata.allow_links = checked("allow_links");
+    gdata.receive_bottles = checked("receive_bottles");
+    gdata.receive_replies = checked("receive_replies");
+
+    InternalError::with(|| {
+        match (checked("public"), gdata.invite.is_some(), gdata.bottle_channel) {
+            (true, false, Some(cid)) => gdata.invite = Some(gateway.create_invite(cid)?),
+            (false, true, _) => gdata.invite = None,
+            _ => ()
+        }
+
+        gdata.update(conn)?;
+        Ok(())
+    })?;
+
+    render_guild_settings(req, &gdata, true, None)
+}
+
+#[derive(Serialize)]
+struct SettingsPage {
+    privacy: Privacy,
+    token: String,
+    saved: bool
+}
+
+fn render_settings(req: &mut Request, user: &User, saved: bool, conn: &mut Conn) -> IronResult<Response> {
+    let token = CsrfToken::new_random().secret().to_string();
+    req.session().form_token = Some(token.clone());
+
+    let data = SettingsPage { privacy: Privacy::get(user.id, conn), token, saved };
+    Ok(Response::with((status::Ok, Template::new("settings", &data))))
+}
+
+fn settings(req: &mut Request) -> IronResult<Response> {
+    let conn = &mut db_conn(req)?;
+
+    match get_user(req.session(), conn) {
+        Some(user) => render_settings(req, &user, false, conn),
+        None => {
+            let redirect = settings_url(&req.get_cfg());
+            login(req, redirect)
+        }
+    }
+}
+
+fn save_settings(req: &mut Request) -> IronResult<Response> {
+    let params = params(req)?;
+    let conn = &mut db_conn(req)?;
+
+    let user = get_user(req.session(), conn)
+        .ok_or_else(|| IronError::new(AuthError, status::Unauthorized))?;
+
+    match params.find(&["token"]) {
+        Some(Value::String(token)) if Some(token) == req.session().form_token.as_ref() => (),
+        _ => return Err(IronError::new(AuthError, status::BadRequest))
+    }
+
+    let checked = |name: &str| params.find(&[name]).is_some();
+    let privacy = Privacy {
+        user: user.id,
+        hide_profile: checked("hide_profile"),
+        hide_bottles: checked("hide_bottles"),
+        hide_contributions: checked("hide_contributions"),
+        hide_leaderboard: checked("hide_leaderboard")
+    };
+
+    InternalError::with(|| Ok(privacy.update(conn)?))?;
+    render_settings(req, &user, true, conn)
+}
+
+fn delete_account(req: &mut Request) -> IronResult<Response> {
+    let params = params(req)?;
+    let conn = &mut db_conn(req)?;
+
+    let user = get_user(req.session(), conn)
+        .ok_or_else(|| IronError::new(AuthError, status::Unauthorized))?;
+
+    match (params.find(&["token"]), params.find(&["confirm"])) {
+        (Some(Value::String(token)), Some(Value::String(confirm)))
+            if Some(token) == req.session().form_token.as_ref() && confirm == "delete" => (),
+        _ => return Err(IronError::new(ParamError, status::BadRequest))
+    }
+
+    InternalError::with(|| req.get_service().erase_user(user.id, conn))?;
+    *req.session() = Session::new();
+
+    Ok(Response::with((status::Ok, Template::new("accountdeleted", &false))))
+}
+
+fn export(req: &mut Request) -> IronResult<Response> {
+    let conn = &mut db_conn(req)?;
+
+    let user = match get_user(req.session(), conn) {
+        Some(user) => user,
+        None => {
+            let redirect = export_url(&req.get_cfg());
+            return login(req, redirect);
+        }
+    };
+
+    let data = InternalError::with(|| Ok(serde_json::to_string_pretty(&user.export(conn)?)?))?;
+
+    let mut resp = Response::with((status::Ok, "application/json".parse::<iron::mime::Mime>().unwrap(), data));
+    resp.headers.set_raw("Content-Disposition", vec![format!("attachment; filename=\"bottle-{}.json\"", user.id).into_bytes()]);
+    Ok(resp)
+}
+
 fn redirect(req: &mut Request) -> IronResult<Response> {
-    let params = req.get_ref::<Params>().unwrap().clone();
+    let params = params(req)?;
+
+    let (csrf, target) = {
+        let session = req.session();
+        (session.csrf.clone(), session.redirect.clone())
+    };
 
-    let session = req.session();
     match params.find(&["state"]) {
-        Some(Value::String(state)) if Some(state) == session.csrf.as_ref() => {
+        Some(Value::String(state)) if Some(state) == csrf.as_ref() => {
             if let Some(Value::String(code)) = params.find(&["code"]) {
-                let oauth = req.extensions.get::<DOauth2>().unwrap().clone();
+                let oauth = oauth(req)?;
 
                 if let Ok(tok) = oauth
                     .exchange_code(oauth2::AuthorizationCode::new(code.to_string()))
                     .request(oauth2::reqwest::http_client) {
 
-                    let conn = &req.get_conn();
-                    set_tok(req.session(), tok, conn).unwrap();
+                    let conn = &mut db_conn(req)?;
+                    let uid = InternalError::with(|| set_tok(&tok, conn))?;
+
+                    //rotate the session so a pre-login id can never be used to ride the login
+                    InternalError::with(|| Ok(Session::revoke(&req.session().id, conn)?))?;
+                    *req.session() = Session { user: Some(uid), ..Session::new() };
 
-                    return match session.redirect {
-                        Some(ref redirect) => Ok(Response::with((status::TemporaryRedirect, RedirectRaw(redirect.clone())))),
-                        _ => Ok(Response::with(status::Ok))
-                    };
+                    let target = target.unwrap_or_else(|| me_url(&req.get_cfg()));
+                    return Ok(Response::with((status::TemporaryRedirect, RedirectRaw(target))));
                 }
             }
         },
@@ -372,18 +875,19 @@ struct HomePage {
 }
 
 fn home(req: &mut Request) -> IronResult<Response> {
-    let conn: &Conn = &req.get_conn();
+    let conn = &mut db_conn(req)?;
+    let gateway = req.get_service().gateway;
 
     let data = InternalError::with(|| {
         Ok(HomePage {
-            bottle_count: get_bottle_count(conn).map_err(Box::new)?,
+            bottle_count: get_bottle_count(conn)?,
             user_count: get_user_count(conn)?,
             guild_count: get_guild_count(conn)?,
 
             guild_leaderboard: Guild::get_top(10, conn)?
-                .into_iter().map(|x| GuildContribution {gid: x.id, guild: get_guild_name(x.id), xp: x.get_xp(conn).unwrap_or(0)}).collect(),
+                .into_iter().map(|x| GuildContribution {gid: x.id, guild: gateway.guild_name(x.id), xp: x.xp}).collect(),
             user_leaderboard: User::get_top(10, conn)?
-                .into_iter().map(|x| UserContribution {uid: x.id, user: get_user_name(x.id), xp: x.xp as i64}).collect(),
+                .into_iter().map(|x| UserContribution {uid: x.id, user: gateway.user_name(x.id), xp: x.xp}).collect(),
         })
     })?;
 
@@ -391,6 +895,171 @@ fn home(req: &mut Request) -> IronResult<Response> {
     Ok(resp)
 }
 
+/// The archive form as submitted, echoed back into the page and its links
+#[derive(Serialize, Default)]
+struct ArchiveFilters {
+    q: String, guild: String, user: String, from: String, to: String, image: bool, replies: bool
+}
+
+impl ArchiveFilters {
+    fn new(params: &params::Map) -> Self {
+        let text = |name: &str| match params.find(&[name]) {
+            Some(Value::String(x)) => x.trim().to_owned(),
+            _ => String::new()
+        };
+
+        let flag = |name: &str| matches!(text(name).as_str(), "1" | "true" | "on");
+
+        ArchiveFilters {
+            q: text("q"), guild: text("guild"), user: text("user"), from: text("from"), to: text("to"),
+            image: flag("image"), replies: flag("replies")
+        }
+    }
+
+    fn search(&self) -> Result<BottleSearch, String> {
+        let some = |x: &str| if x.is_empty() { None } else { Some(x.to_owned()) };
+        let id = |x: &str, name: &str| some(x).map(|x| x.parse::<i64>().map_err(|_| format!("The {} must be an id", name))).transpose();
+        let date = |x: &str, name: &str| some(x).map(|x| chrono::NaiveDate::parse_from_str(&x, "%Y-%m-%d")
+            .map_err(|_| format!("The {} date must look like 2019-05-08", name))).transpose();
+
+        Ok(BottleSearch {
+            text: some(&self.q), guild: id(&self.guild, "guild")?, user: id(&self.user, "user")?,
+            after: date(&self.from, "from")?.and_then(|d| d.and_hms_opt(0, 0, 0)),
+            before: date(&self.to, "to")?.and_then(|d| d.succ_opt()).and_then(|d| d.and_hms_opt(0, 0, 0)),
+            has_image: self.image, has_replies: self.replies
+        })
+    }
+
+    /// Relative to the archive, keeping only the filters that were filled in
+    fn link(&self, page: i64) -> String {
+        let mut url = reqwest::Url::parse("http://localhost/").unwrap();
+        {
+            let mut query = url.query_pairs_mut();
+            for (name, value) in [("q", &self.q), ("guild", &self.guild), ("user", &self.user), ("from", &self.from), ("to", &self.to)] {
+                if !value.is_empty() {
+                    query.append_pair(name, value);
+                }
+            }
+
+            if self.image {
+                query.append_pair("image", "1");
+            }
+
+            if self.replies {
+                query.append_pair("replies", "1");
+            }
+
+            query.append_pair("page", &page.to_string());
+        }
+
+        format!("?{}", url.query().unwrap_or_default())
+    }
+}
+
+/// Shared with ``/api/v1/bottles``, empty fields are ignored so a submitted form can be passed through as is
+pub(crate) fn bottle_search(params: &params::Map) -> Result<BottleSearch, String> {
+    ArchiveFilters::new(params).search()
+}
+
+#[derive(Serialize)]
+struct ArchiveBottle {
+    author: String, uid: i64, gid: Option<i64>, bottle: BottlePage
+}
+
+#[derive(Serialize)]
+struct ArchivePage {
+    filters: ArchiveFilters, bottles: Vec<ArchiveBottle>, error: Option<String>,
+    page: i64, prev: Option<String>, next: Option<String>
+}
+
+fn archive(req: &mut Request) -> IronResult<Response> {
+    let params = params(req)?;
+    let filters = ArchiveFilters::new(&params);
+    let page = match params.find(&["page"]) {
+        Some(Value::String(x)) => x.parse::<i64>().ok().filter(|&p| p >= 1).unwrap_or(1),
+        _ => 1
+    };
+
+    let offset = (page - 1).checked_mul(ARCHIVE_PAGE).ok_or_else(|| IronError::new(ParamError, status::BadRequest))?;
+
+    let conn = &mut db_conn(req)?;
+    let gateway = req.get_service().gateway;
+
+    let (bottles, next, error) = match filters.search() {
+        Ok(search) => {
+            //one past the page to know if there's another
+            let mut bottles = InternalError::with(|| Bottle::search(&search, offset, ARCHIVE_PAGE + 1, conn))?;
+            let next = bottles.len() as i64 > ARCHIVE_PAGE;
+            bottles.truncate(ARCHIVE_PAGE as usize);
+
+            (bottles, next, None)
+        },
+        Err(err) => (Vec::new(), false, Some(err))
+    };
+
+    let bottles = bottles.into_iter().map(|b| ArchiveBottle {
+        author: gateway.user_name(b.user), uid: b.user, gid: b.guild, bottle: BottlePage::new(b, gateway.as_ref())
+    }).collect();
+
+    let data = ArchivePage {
+        bottles, error, page,
+        prev: if page > 1 { Some(filters.link(page - 1)) } else { None },
+        next: if next { Some(filters.link(page + 1)) } else { None },
+        filters
+    };
+
+    Ok(Response::with((status::Ok, Template::new("bottles", &data))))
+}
+
+#[derive(Serialize)]
+struct LeaderboardRow {
+    rank: i64, xp: i64, user: Option<String>, uid: i64, guild: Option<String>, gid: i64
+}
+
+#[derive(Serialize)]
+struct LeaderboardPage {
+    guilds: bool, page: i64, prev: Option<i64>, next: Option<i64>, rows: Vec<LeaderboardRow>
+}
+
+fn leaderboard(req: &mut Request) -> IronResult<Response> {
+    let guilds = match req.extensions.get::<Router>().unwrap().find("kind") {
+        Some("users") => false,
+        Some("guilds") => true,
+        _ => return Err(IronError::new(ParamError, status::NotFound))
+    };
+
+    let page = match params(req)?.find(&["page"]) {
+        None => 1,
+        Some(Value::String(x)) => x.parse::<i64>().ok().filter(|&p| p >= 1)
+            .ok_or_else(|| IronError::new(ParamError, status::BadRequest))?,
+        _ => return Err(IronError::new(ParamError, status::BadRequest))
+    };
+
+    let offset = (page - 1).checked_mul(LEADERBOARD_PAGE).ok_or_else(|| IronError::new(ParamError, status::BadRequest))?;
+
+    let conn = &mut db_conn(req)?;
+    let gateway = req.get_service().gateway;
+
+    //one past the page to know if there's another
+    let mut entries = InternalError::with(|| if guilds {
+        Guild::get_top_page(offset, LEADERBOARD_PAGE + 1, conn)
+    } else {
+        User::get_top_page(offset, LEADERBOARD_PAGE + 1, conn)
+    })?;
+
+    let next = if entries.len() as i64 > LEADERBOARD_PAGE { Some(page + 1) } else { None };
+    entries.truncate(LEADERBOARD_PAGE as usize);
+
+    let rows = entries.into_iter().map(|x| LeaderboardRow {
+        rank: x.rank, xp: x.xp,
+        user: if guilds { None } else { Some(gateway.user_name(x.id)) }, uid: x.id,
+        guild: if guilds { Some(gateway.guild_name(x.id)) } else { None }, gid: x.id
+    }).collect();
+
+    let data = LeaderboardPage {guilds, page, prev: if page > 1 { Some(page - 1) } else { None }, next, rows};
+    Ok(Response::with((status::Ok, Template::new("leaderboard", &data))))
+}
+
 #[cfg(feature = "watch")]
 fn watch_serv(hbse: &Arc<HandlebarsEngine>) {
     hbse.watch("./res/");
@@ -401,21 +1070,38 @@ fn watch_serv(_: &Arc<HandlebarsEngine>) {
     ()
 }
 
-pub fn start_serv (db: ConnPool, cfg: Config) {
-    let reqcfg = cfg.clone();
+pub fn start_serv (service: BottleService, shards: Option<health::Shards>) {
+    let db = service.pool.clone();
+    let cfg = service.cfg.clone();
+    let sessions = SessionStorage::new(&cfg);
+    let _ = db.get_conn().and_then(|mut conn| Ok(Session::clean(&mut conn)?));
+
     let oauthcfg = BasicClient::new(
-        oauth2::ClientId::new(cfg.client_id),
-        Some(oauth2::ClientSecret::new(cfg.client_secret)),
+        oauth2::ClientId::new(cfg.discord.client_id),
+        Some(oauth2::ClientSecret::new(cfg.discord.client_secret)),
         oauth2::AuthUrl::new("https://discordapp.com/api/oauth2/authorize".to_string()).unwrap(),
         Some(oauth2::TokenUrl::new("https://discordapp.com/api/oauth2/token".to_string()).unwrap())
     )
-        .set_redirect_url(format!("{}/oauth", cfg.host_url));
+        .set_redirect_url(format!("{}/oauth", cfg.web.host_url));
 
     let mut router = Router::new();
     router.get("/", home, "home");
     router.get("/u/:user", user, "user");
     router.get("/g/:guild", guild, "guild");
+    router.get("/leaderboard/:kind", leaderboard, "leaderboard");
+    router.get("/bottles", archive, "archive");
+    router.get("/g/:guild/settings", guild_settings, "guild_settings");
+    router.post("/g/:guild/settings", save_guild_settings, "save_guild_settings");
     router.get("/report/:bottle", report, "report");
+    router.get("/login", login_page, "login");
+    router.get("/logout", logout, "logout");
+    router.get("/me", me, "me");
+    router.post("/me/keys", make_key, "make_key");
+    router.post("/me/keys/revoke", revoke_key, "revoke_key");
+    router.get("/settings", settings, "settings");
+    router.post("/settings", save_settings, "save_settings");
+    router.post("/settings/delete", delete_account, "delete_account");
+    router.get("/export", export, "export");
     router.get("/oauth", redirect, "redirect");
 
     let mut chain = Chain::new(router);
@@ -427,17 +1113,34 @@ pub fn start_serv (db: ConnPool, cfg: Config) {
     let hbse_r = Arc::new(hbse);
     watch_serv(&hbse_r);
 
-    chain.link_around(SessionStorage {});
-    chain.link_before(PrerequisiteMiddleware {pool: db, oauth: oauthcfg, cfg: reqcfg});
+    let prerequisites = PrerequisiteMiddleware {service, oauth: oauthcfg};
+
+    chain.link_around(sessions);
+    chain.link_before(prerequisites.clone());
     chain.link_after(StatusMiddleware);
     chain.link_after(hbse_r);
 
     let mut mount = Mount::new();
 
     mount.mount("/", chain);
+    mount.mount("/api/v1", api::make_chain(prerequisites));
+    mount.mount("/healthz", |_: &mut Request| health::healthz());
     mount.mount("/style", Static::new("./res/style"));
     mount.mount("/img", Static::new("./res/img"));
 
-    let iron = Iron::new(mount);
-    let _ = iron.http("0.0.0.0:8080", ).unwrap();
+    //metrics and readiness say more about the deployment than the public should see
+    let mut internal = Mount::new();
+    let readydb = db.clone();
+    internal.mount("/metrics", move |_: &mut Request| metrics::serve(&db));
+    internal.mount("/healthz", |_: &mut Request| health::healthz());
+    internal.mount("/readyz", move |_: &mut Request| health::readyz(&readydb, shards.as_ref()));
+
+    let iron = Iron::new(RequestLog.around(Box::new(mount)));
+    let mut listening = iron.http(cfg.web.bind.as_str()).expect("Error binding the web server.");
+    let mut internal = Iron::new(internal).http(cfg.web.internal_bind.as_str()).expect("Error binding the internal web server.");
+
+    shutdown::on_stop(move || {
+        let _ = listening.close();
+        let _ = internal.close();
+    });
 }
\ No newline at end of file
@@ -0,0 +1,225 @@
+use std::thread;
+use std::time::Duration;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use serde_json::{self, json};
+use uuid::Uuid;
+use log::*;
+
+use model::*;
+use platform::Platform;
+use shutdown;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Event {
+    BottleSent, BottleReceived, ReplyReceived, ReportFiled, BanIssued, BottleDeleted
+}
+
+pub const EVENTS: [Event; 6] = [Event::BottleSent, Event::BottleReceived, Event::ReplyReceived, Event::ReportFiled, Event::BanIssued, Event::BottleDeleted];
+pub const ALL_EVENTS: i32 = (1 << EVENTS.len()) - 1;
+
+const MAX_ATTEMPTS: i32 = 5;
+const POLL: Duration = Duration::from_secs(5);
+const TIMEOUT: Duration = Duration::from_secs(10);
+const BATCH: i64 = 50;
+
+impl Event {
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::BottleSent => "bottle.sent",
+            Event::BottleReceived => "bottle.received",
+            Event::ReplyReceived => "reply.received",
+            Event::ReportFiled => "report.filed",
+            Event::BanIssued => "ban.issued",
+            Event::BottleDeleted => "bottle.deleted"
+        }
+    }
+
+    pub fn bit(self) -> i32 {
+        1 << (self as i32)
+    }
+
+    pub fn parse(name: &str) -> Option<Event> {
+        EVENTS.iter().cloned().find(|e| e.name() == name)
+    }
+}
+
+pub fn describe_events(events: i32) -> String {
+    EVENTS.iter().filter(|e| events & e.bit() != 0).map(|e| e.name()).collect::<Vec<_>>().join(", ")
+}
+
+pub fn make_secret() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+pub fn bottle_payload(bottle: &Bottle) -> serde_json::Value {
+    json!({
+        "id": bottle.id,
+        "reply_to": bottle.reply_to,
+        "guild": bottle.guild,
+        "channel": bottle.channel,
+        "author": if bottle.anonymous { None } else { Some(bottle.user) },
+        "anonymous": bottle.anonymous,
+        "pseudonym": bottle.pseudonym,
+        "time_pushed": bottle.time_pushed,
+        "contents": bottle.contents,
+        "url": bottle.url,
+        "image": bottle.image,
+        "deleted": bottle.deleted,
+        "platform": Platform::from_id(bottle.platform).map(Platform::name)
+    })
+}
+
+/// Queues the event for the guild's webhooks and every global webhook, the worker sends them.
+/// Webhooks are a side channel, so failing to queue one is logged rather than failing whatever fired it
+pub fn fire(event: Event, guild: Option<GuildId>, data: serde_json::Value, conn: &mut Conn) {
+    if let Err(err) = queue(event, guild, data, conn) {
+        error!("Error queueing {} webhooks: {}", event.name(), err);
+    }
+}
+
+fn queue(event: Event, guild: Option<GuildId>, data: serde_json::Value, conn: &mut Conn) -> Res<()> {
+    let hooks: Vec<Webhook> = Webhook::get_for(guild, conn)?.into_iter()
+        .filter(|h| h.events & event.bit() != 0).collect();
+
+    if hooks.is_empty() {
+        return Ok(());
+    }
+
+    let time = now();
+    let payload = json!({"event": event.name(), "guild": guild, "time": time, "data": data}).to_string();
+
+    for hook in hooks {
+        trace!("Queueing {} for webhook {}", event.name(), hook.id);
+        MakeWebhookDelivery {webhook: hook.id, event: event.name(), payload: &payload, attempts: 0, next_attempt: Some(time), created: time}.make(conn)?;
+    }
+
+    Ok(())
+}
+
+/// Loopback, private, link-local and other non-routable addresses, which a webhook could use to reach the bot's own network
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast()
+                || ip.is_multicast() || ip.is_documentation() || a == 0 || (a == 100 && (64..128).contains(&b))
+        },
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            match ip.to_ipv4_mapped() {
+                Some(v4) => is_internal(IpAddr::V4(v4)),
+                None => ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+                    || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+            }
+        }
+    }
+}
+
+/// Only https URLs whose host resolves entirely to public addresses are delivered to.
+/// Checked when a webhook is added and again before every delivery, since DNS can change in between
+pub fn check_url(url: &str) -> Res<()> {
+    resolve(url).map(|_| ())
+}
+
+/// The URL's host and one of its vetted addresses, which the delivery then connects to without resolving again
+fn resolve(url: &str) -> Res<(String, SocketAddr)> {
+    let parsed = reqwest::Url::parse(url).map_err(|_| BottleError::Invalid("That isn't a valid URL!".to_owned()))?;
+    if parsed.scheme() != "https" {
+        return Err(BottleError::Invalid("Webhook URLs must start with https://".to_owned()));
+    }
+
+    let host = parsed.host_str().ok_or_else(|| BottleError::Invalid("Webhook URLs need a host!".to_owned()))?;
+    let addrs: Vec<_> = (host.trim_start_matches('[').trim_end_matches(']'), parsed.port_or_known_default().unwrap_or(443))
+        .to_socket_addrs().map_err(|_| BottleError::Invalid(format!("Couldn't resolve {}", host)))?.collect();
+
+    match addrs.first() {
+        Some(&addr) if !addrs.iter().any(|addr| is_internal(addr.ip())) => Ok((host.to_owned(), addr)),
+        _ => Err(BottleError::Invalid("Webhooks can't be delivered to private or local addresses!".to_owned()))
+    }
+}
+
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body.as_bytes());
+
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Resolves the host once and pins the client to that address, so a rebinding DNS server can't swap in
+/// an internal one between the check and the connection
+fn deliver(hook: &Webhook, delivery: &WebhookDelivery) -> Result<u16, String> {
+    let (host, addr) = resolve(&hook.url).map_err(|err| err.to_string())?;
+    let client = reqwest::blocking::Client::builder().timeout(TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve(&host, addr).build()
+        .map_err(|err| err.to_string())?;
+
+    let resp = client.post(&hook.url)
+        .header("Content-Type", "application/json")
+        .header("X-Bottle-Event", delivery.event.as_str())
+        .header("X-Bottle-Delivery", delivery.id.to_string())
+        .header("X-Bottle-Signature", sign(&hook.secret, &delivery.payload))
+        .body(delivery.payload.clone())
+        .send().map_err(|err| err.to_string())?;
+
+    Ok(resp.status().as_u16())
+}
+
+fn backoff(attempts: i32) -> chrono::Duration {
+    chrono::Duration::seconds(5 * 4i64.pow(attempts as u32))
+}
+
+fn run_deliveries(pool: &ConnPool) -> Res<()> {
+    let conn = &mut pool.get_conn()?;
+
+    for (delivery, hook) in WebhookDelivery::get_due(BATCH, conn)? {
+        let attempts = delivery.attempts + 1;
+        let retry = if attempts >= MAX_ATTEMPTS { None } else { Some(now() + backoff(attempts)) };
+
+        let (next_attempt, status, error) = match deliver(&hook, &delivery) {
+            Ok(status) if (200..300).contains(&status) => (None, Some(status as i32), None),
+            Ok(status) => (retry, Some(status as i32), Some(format!("Webhook responded with {}", status))),
+            Err(err) => (retry, None, Some(err))
+        };
+
+        if let Some(ref err) = error {
+            debug!("Webhook {} delivery {} failed (attempt {}): {}", hook.id, delivery.id, attempts, err);
+        }
+
+        delivery.record(next_attempt, status, error, conn)?;
+    }
+
+    Ok(())
+}
+
+pub fn start_worker(pool: ConnPool) {
+    thread::spawn(move || {
+        while !shutdown::stopping() {
+            let work = shutdown::track();
+            if let Err(err) = run_deliveries(&pool) {
+                error!("Error delivering webhooks: {}", err);
+            }
+
+            drop(work);
+            thread::sleep(POLL);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deliveries_pin_a_public_address() {
+        let (host, addr) = resolve("https://8.8.8.8:8443/hook").unwrap();
+        assert_eq!((host.as_str(), addr), ("8.8.8.8", "8.8.8.8:8443".parse().unwrap()));
+
+        for url in ["http://8.8.8.8/hook", "https://127.0.0.1/hook", "https://10.0.0.1/hook", "https://[::1]/hook",
+            "https://[::ffff:192.168.0.1]/hook", "https://100.64.0.1/hook", "https://169.254.169.254/latest"] {
+            assert!(resolve(url).is_err(), "{} should be rejected", url);
+        }
+    }
+}